@@ -1,14 +1,22 @@
 use std::ffi::CStr;
 use std::fmt;
 use std::net::SocketAddr;
+use std::ptr;
 
-use varnish::ffi::{backend, BACKEND_MAGIC, DIRECTOR_MAGIC, VCL_BACKEND};
+use varnish::ffi::{backend, VRT_Healthy, BACKEND_MAGIC, DIRECTOR_MAGIC, VCL_BACKEND};
+use varnish::vcl::Ctx;
 
 #[derive(Debug, Clone)]
 pub struct Backend {
     pub(crate) name: String,
     pub(crate) address: SocketAddr,
     pub(crate) vcl_backend: VCL_BACKEND,
+    /// Relative capacity of this backend, 1.0 by default
+    pub(crate) weight: f64,
+    /// The Varnish backend behind `vcl_backend`, for its connection counters
+    pub(crate) vbe: *const backend,
+    /// Availability zone, for locality-aware selection
+    pub(crate) zone: Option<String>,
 }
 
 impl PartialEq for Backend {
@@ -68,10 +76,60 @@ impl Backend {
                 name: Self::name_from_backend(backend),
                 address: Self::address_from_backend(backend)?,
                 vcl_backend: backend_director,
+                weight: 1.0,
+                vbe: backend,
+                zone: None,
             })
         }
     }
 
+    /// Sets the relative capacity of this backend.
+    pub fn with_weight(mut self, weight: f64) -> Self {
+        self.weight = weight;
+        self
+    }
+
+    /// Sets the availability zone of this backend.
+    pub fn with_zone(mut self, zone: Option<String>) -> Self {
+        self.zone = zone;
+        self
+    }
+
+    /// Tells whether `other` is the same server, possibly through the
+    /// backend of another VCL: same name, same address.
+    pub fn is_same_server(&self, other: &Backend) -> bool {
+        self.name == other.name && self.address == other.address
+    }
+
+    /// Tells whether this backend is in `zone`.
+    pub fn in_zone(&self, zone: &str) -> bool {
+        self.zone.as_deref() == Some(zone)
+    }
+
+    /// Returns the number of connections Varnish currently uses to this
+    /// backend, for all directors of this instance.
+    pub fn local_connections(&self) -> usize {
+        unsafe { self.vbe.as_ref().map_or(0, |vbe| vbe.n_conn as usize) }
+    }
+
+    /// Returns the number of fetches of this Varnish waiting for a
+    /// connection to this backend, once it reached `max_connections`.
+    pub fn local_waiting(&self) -> usize {
+        unsafe { self.vbe.as_ref().map_or(0, |vbe| vbe.cw_count as usize) }
+    }
+
+    /// Returns the requests this Varnish has in flight to this backend:
+    /// connections in use plus fetches waiting for one.
+    pub fn local_load(&self) -> usize {
+        self.local_connections() + self.local_waiting()
+    }
+
+    /// Checks Varnish's view of this backend's health: the result of its
+    /// VCL `probe`, overridden by any `varnishadm backend.set_health`.
+    pub fn is_healthy(&self, ctx: &Ctx) -> bool {
+        unsafe { VRT_Healthy(&*ctx.raw, self.vcl_backend, ptr::null_mut()).into() }
+    }
+
     fn name_from_backend(backend: &backend) -> String {
         unsafe {
             if !backend.vcl_name.is_null() {
@@ -143,6 +201,20 @@ impl fmt::Display for Backend {
 unsafe impl Send for Backend {}
 unsafe impl Sync for Backend {}
 
+/// A backend for unit tests, standing in for the VCL backend number `id`
+/// without a Varnish backend behind it.
+#[cfg(test)]
+pub(crate) fn test_backend(name: &str, address: SocketAddr, id: usize) -> Backend {
+    Backend {
+        name: name.to_string(),
+        address,
+        vcl_backend: VCL_BACKEND(id as *const varnish::ffi::director),
+        weight: 1.0,
+        vbe: ptr::null(),
+        zone: None,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::ffi::{c_void, CString};
@@ -265,6 +337,22 @@ mod tests {
         assert_eq!(parsed.address, addr);
     }
 
+    #[test]
+    fn test_backend_local_load() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 8080));
+        let parsed = Backend::new(create_test_backend("test1", addr)).unwrap();
+        assert_eq!(parsed.local_load(), 0);
+
+        unsafe {
+            let vbe = parsed.vbe as *mut backend;
+            (*vbe).n_conn = 4;
+            (*vbe).cw_count = 2;
+        }
+        assert_eq!(parsed.local_connections(), 4);
+        assert_eq!(parsed.local_waiting(), 2);
+        assert_eq!(parsed.local_load(), 6);
+    }
+
     #[test]
     fn test_backend_parsing_invalid_backend() {
         let name_cstr = CString::new("test1").unwrap();
@@ -0,0 +1,384 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use varnish::ffi::VCL_BACKEND;
+
+use crate::backend::Backend;
+
+/// When real responses trip a backend's circuit breaker.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BreakerConfig {
+    /// Share of errors among the responses of `window` that opens the
+    /// circuit; 0 disables the breakers
+    pub error_rate: f64,
+    /// How far back responses count towards the error rate
+    pub window: Duration,
+    /// Responses needed within `window` before the error rate is trusted
+    pub min_requests: usize,
+    /// How long an open circuit stays open before letting trial requests through
+    pub open_time: Duration,
+    /// Trial requests let through a half-open circuit; as many successes close it
+    pub half_open_requests: usize,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        Self {
+            error_rate: 0.0,
+            window: Duration::from_secs(10),
+            min_requests: 20,
+            open_time: Duration::from_secs(30),
+            half_open_requests: 5,
+        }
+    }
+}
+
+/// A change of a breaker's state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transition {
+    None,
+    /// The circuit opened: too many errors, or a failed trial
+    Opened,
+    /// Enough trials succeeded for the circuit to close
+    Closed,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum State {
+    Closed,
+    Open {
+        until: Instant,
+    },
+    HalfOpen {
+        since: Instant,
+        trials: usize,
+        successes: usize,
+    },
+}
+
+#[derive(Debug)]
+struct Breaker {
+    backend: Backend,
+    state: State,
+    // Responses within the window, true for an error
+    outcomes: VecDeque<(Instant, bool)>,
+}
+
+/// Per-backend circuit breakers fed by real responses.
+///
+/// A closed circuit lets everything through until the error rate over the
+/// window crosses the threshold, which opens it. An open circuit lets
+/// nothing through for `open_time`, then becomes half-open: a few trial
+/// requests go through, closing the circuit if they all succeed and
+/// opening it again at the first error.
+#[derive(Debug)]
+pub struct CircuitBreakers {
+    config: BreakerConfig,
+    // Keyed by the backend's VCL_BACKEND address
+    backends: Mutex<HashMap<usize, Breaker>>,
+}
+
+fn key(backend: &Backend) -> usize {
+    backend.vcl_backend.0 as usize
+}
+
+impl CircuitBreakers {
+    pub fn new(config: BreakerConfig) -> Self {
+        Self {
+            config,
+            backends: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.config.error_rate > 0.0
+    }
+
+    /// Records the outcome of a real request.
+    ///
+    /// # Arguments
+    /// * `backend` - The backend that handled the request
+    /// * `error` - Whether the request failed
+    /// * `now` - The current time
+    pub fn record(&self, backend: &Backend, error: bool, now: Instant) -> Transition {
+        if !self.is_enabled() {
+            return Transition::None;
+        }
+        let Ok(mut backends) = self.backends.lock() else {
+            return Transition::None;
+        };
+        let breaker = backends.entry(key(backend)).or_insert_with(|| Breaker {
+            backend: backend.clone(),
+            state: State::Closed,
+            outcomes: VecDeque::new(),
+        });
+        let open = State::Open {
+            until: now + self.config.open_time,
+        };
+
+        match breaker.state {
+            State::Closed => {
+                breaker.outcomes.push_back((now, error));
+                while breaker
+                    .outcomes
+                    .front()
+                    .is_some_and(|(at, _)| now.saturating_duration_since(*at) > self.config.window)
+                {
+                    breaker.outcomes.pop_front();
+                }
+                let total = breaker.outcomes.len();
+                let errors = breaker.outcomes.iter().filter(|(_, e)| *e).count();
+                if total >= self.config.min_requests
+                    && errors as f64 / total as f64 >= self.config.error_rate
+                {
+                    breaker.state = open;
+                    breaker.outcomes.clear();
+                    return Transition::Opened;
+                }
+                Transition::None
+            }
+            // Responses to requests sent before the circuit opened
+            State::Open { .. } => Transition::None,
+            State::HalfOpen { .. } if error => {
+                breaker.state = open;
+                Transition::Opened
+            }
+            State::HalfOpen {
+                since,
+                trials,
+                successes,
+            } => {
+                if successes + 1 >= self.config.half_open_requests {
+                    breaker.state = State::Closed;
+                    return Transition::Closed;
+                }
+                breaker.state = State::HalfOpen {
+                    since,
+                    trials,
+                    successes: successes + 1,
+                };
+                Transition::None
+            }
+        }
+    }
+
+    /// Returns the backends requests must not go to: those with an open
+    /// circuit, or a half-open one with all its trials under way.
+    pub fn unavailable(&self, now: Instant) -> Vec<VCL_BACKEND> {
+        if !self.is_enabled() {
+            return Vec::new();
+        }
+        let Ok(mut backends) = self.backends.lock() else {
+            return Vec::new();
+        };
+        let mut unavailable = Vec::new();
+        for breaker in backends.values_mut() {
+            match breaker.state {
+                State::Open { until } if until <= now => {
+                    breaker.state = State::HalfOpen {
+                        since: now,
+                        trials: 0,
+                        successes: 0,
+                    };
+                }
+                // Trials that never reported back are given up on
+                State::HalfOpen { since, .. }
+                    if now.saturating_duration_since(since) > self.config.open_time =>
+                {
+                    breaker.state = State::HalfOpen {
+                        since: now,
+                        trials: 0,
+                        successes: 0,
+                    };
+                }
+                State::Open { .. } => unavailable.push(breaker.backend.vcl_backend),
+                State::HalfOpen { trials, .. } if trials >= self.config.half_open_requests => {
+                    unavailable.push(breaker.backend.vcl_backend)
+                }
+                _ => {}
+            }
+        }
+        unavailable
+    }
+
+    /// Counts a request sent to `backend`, as a trial if its circuit is half-open.
+    pub fn admit(&self, backend: &Backend) {
+        let Ok(mut backends) = self.backends.lock() else {
+            return;
+        };
+        if let Some(Breaker {
+            state: State::HalfOpen { trials, .. },
+            ..
+        }) = backends.get_mut(&key(backend))
+        {
+            *trials += 1;
+        }
+    }
+
+    /// Returns the number of backends whose circuit is open or half-open.
+    pub fn open_count(&self) -> usize {
+        self.backends
+            .lock()
+            .map(|backends| {
+                backends
+                    .values()
+                    .filter(|b| !matches!(b.state, State::Closed))
+                    .count()
+            })
+            .unwrap_or(0)
+    }
+
+    /// Returns the state of a backend's circuit: "closed", "open" or "half_open".
+    pub fn state(&self, backend: &Backend) -> &'static str {
+        let Ok(backends) = self.backends.lock() else {
+            return "closed";
+        };
+        match backends.get(&key(backend)).map(|b| b.state) {
+            Some(State::Open { .. }) => "open",
+            Some(State::HalfOpen { .. }) => "half_open",
+            Some(State::Closed) | None => "closed",
+        }
+    }
+
+    /// Describes the state of every breaker, one line per backend.
+    pub fn describe(&self, now: Instant) -> Vec<String> {
+        let Ok(backends) = self.backends.lock() else {
+            return Vec::new();
+        };
+        backends
+            .values()
+            .map(|breaker| {
+                let state = match breaker.state {
+                    State::Closed => format!(
+                        "state=closed, errors={}/{}",
+                        breaker.outcomes.iter().filter(|(_, e)| *e).count(),
+                        breaker.outcomes.len()
+                    ),
+                    State::Open { until } => format!(
+                        "state=open, remaining={:.1}s",
+                        until.saturating_duration_since(now).as_secs_f64()
+                    ),
+                    State::HalfOpen {
+                        trials, successes, ..
+                    } => format!(
+                        "state=half_open, trials={}/{}, successes={}",
+                        trials, self.config.half_open_requests, successes
+                    ),
+                };
+                format!(
+                    "backend={} ({}), {}",
+                    breaker.backend.name, breaker.backend.address, state
+                )
+            })
+            .collect()
+    }
+
+    /// Carries what is known of `old` over to `new`, the same server
+    /// through another VCL's backend.
+    pub fn rebind_backend(&self, old: &Backend, new: &Backend) {
+        if let Ok(mut backends) = self.backends.lock() {
+            if let Some(mut breaker) = backends.remove(&key(old)) {
+                breaker.backend = new.clone();
+                backends.insert(key(new), breaker);
+            }
+        }
+    }
+
+    /// Forgets everything about a backend.
+    pub fn remove_backend(&self, backend: &Backend) {
+        if let Ok(mut backends) = self.backends.lock() {
+            backends.remove(&key(backend));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+    use crate::backend::test_backend;
+
+    fn breakers() -> CircuitBreakers {
+        CircuitBreakers::new(BreakerConfig {
+            error_rate: 0.5,
+            window: Duration::from_secs(10),
+            min_requests: 4,
+            open_time: Duration::from_secs(30),
+            half_open_requests: 2,
+        })
+    }
+
+    #[test]
+    fn test_breaker_opens_on_error_rate() {
+        let breakers = breakers();
+        let backend = test_backend("b1", SocketAddr::from(([127, 0, 0, 1], 8080)), 1);
+        let now = Instant::now();
+
+        assert_eq!(breakers.record(&backend, true, now), Transition::None);
+        assert_eq!(breakers.record(&backend, true, now), Transition::None);
+        // Not enough requests yet
+        assert_eq!(breakers.record(&backend, false, now), Transition::None);
+        assert!(breakers.unavailable(now).is_empty());
+        assert_eq!(breakers.record(&backend, false, now), Transition::Opened);
+        assert_eq!(breakers.unavailable(now), [backend.vcl_backend]);
+        assert_eq!(breakers.open_count(), 1);
+        assert!(breakers.describe(now)[0].contains("state=open"));
+    }
+
+    #[test]
+    fn test_breaker_window() {
+        let breakers = breakers();
+        let backend = test_backend("b1", SocketAddr::from(([127, 0, 0, 1], 8080)), 1);
+        let now = Instant::now();
+
+        // Old errors fall out of the window
+        breakers.record(&backend, true, now);
+        breakers.record(&backend, true, now);
+        let later = now + Duration::from_secs(11);
+        for _ in 0..4 {
+            assert_eq!(breakers.record(&backend, false, later), Transition::None);
+        }
+    }
+
+    #[test]
+    fn test_breaker_half_open() {
+        let breakers = breakers();
+        let backend = test_backend("b1", SocketAddr::from(([127, 0, 0, 1], 8080)), 1);
+        let mut now = Instant::now();
+        for _ in 0..4 {
+            breakers.record(&backend, true, now);
+        }
+
+        // Half-open after open_time, letting two trials through
+        now += Duration::from_secs(30);
+        assert!(breakers.unavailable(now).is_empty());
+        breakers.admit(&backend);
+        assert!(breakers.unavailable(now).is_empty());
+        breakers.admit(&backend);
+        assert_eq!(breakers.unavailable(now), [backend.vcl_backend]);
+
+        // A failed trial opens it again
+        assert_eq!(breakers.record(&backend, true, now), Transition::Opened);
+        now += Duration::from_secs(30);
+        assert!(breakers.unavailable(now).is_empty());
+        breakers.admit(&backend);
+        breakers.admit(&backend);
+        assert_eq!(breakers.record(&backend, false, now), Transition::None);
+        assert_eq!(breakers.record(&backend, false, now), Transition::Closed);
+        assert!(breakers.unavailable(now).is_empty());
+        assert_eq!(breakers.open_count(), 0);
+    }
+
+    #[test]
+    fn test_breaker_disabled() {
+        let breakers = CircuitBreakers::new(BreakerConfig::default());
+        let backend = test_backend("b1", SocketAddr::from(([127, 0, 0, 1], 8080)), 1);
+        let now = Instant::now();
+        for _ in 0..100 {
+            assert_eq!(breakers.record(&backend, true, now), Transition::None);
+        }
+        assert!(breakers.unavailable(now).is_empty());
+    }
+}
@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::backend::Backend;
+
+/// Weight of a new observation in the moving averages.
+const EWMA_ALPHA: f64 = 0.1;
+/// Bounds for the correction factor, so a few outliers can't make an
+/// estimate meaningless.
+const MIN_BIAS: f64 = 0.1;
+const MAX_BIAS: f64 = 10.0;
+
+#[derive(Debug, Clone, Copy)]
+struct Calibration {
+    /// Moving average of the observed latency, in milliseconds
+    observed_ms: f64,
+    /// Moving average of observed / estimated latency, 1.0 until known
+    bias: f64,
+    has_bias: bool,
+}
+
+/// Per-backend comparison of estimated and observed latencies.
+///
+/// Estimates are corrected by a multiplicative bias, which works whatever
+/// unit a backend reports its estimate in. Observed latencies are kept in
+/// milliseconds, to stand in for the estimate of backends that send none.
+#[derive(Debug, Default)]
+pub struct LatencyCalibration {
+    // Keyed by the backend's VCL_BACKEND address
+    backends: Mutex<HashMap<usize, Calibration>>,
+}
+
+fn key(backend: &Backend) -> usize {
+    backend.vcl_backend.0 as usize
+}
+
+fn ewma(average: f64, sample: f64) -> f64 {
+    average + EWMA_ALPHA * (sample - average)
+}
+
+impl LatencyCalibration {
+    /// Records the latency of a real fetch.
+    ///
+    /// # Arguments
+    /// * `backend` - The backend that served the fetch
+    /// * `observed` - Its measured latency (e.g. time to first byte)
+    /// * `estimated` - What the backend estimated when last probed, if anything
+    pub fn observe(&self, backend: &Backend, observed: Duration, estimated: Option<usize>) {
+        let observed_ms = observed.as_secs_f64() * 1000.0;
+        let Ok(mut backends) = self.backends.lock() else {
+            return;
+        };
+        let calibration = backends.entry(key(backend)).or_insert(Calibration {
+            observed_ms,
+            bias: 1.0,
+            has_bias: false,
+        });
+        calibration.observed_ms = ewma(calibration.observed_ms, observed_ms);
+
+        if let Some(estimated) = estimated.filter(|e| *e > 0) {
+            let ratio = (observed_ms / estimated as f64).clamp(MIN_BIAS, MAX_BIAS);
+            calibration.bias = if calibration.has_bias {
+                ewma(calibration.bias, ratio)
+            } else {
+                ratio
+            };
+            calibration.has_bias = true;
+        }
+    }
+
+    /// Returns `estimated` corrected by the backend's observed bias.
+    pub fn correct(&self, backend: &Backend, estimated: usize) -> usize {
+        let bias = self
+            .backends
+            .lock()
+            .ok()
+            .and_then(|backends| backends.get(&key(backend)).map(|c| c.bias))
+            .unwrap_or(1.0);
+        (estimated as f64 * bias).round() as usize
+    }
+
+    /// Returns the average observed latency of a backend, in milliseconds.
+    pub fn observed_latency(&self, backend: &Backend) -> Option<usize> {
+        let backends = self.backends.lock().ok()?;
+        backends
+            .get(&key(backend))
+            .map(|c| c.observed_ms.round() as usize)
+    }
+
+    /// Carries what is known of `old` over to `new`, the same server
+    /// through another VCL's backend.
+    pub fn rebind_backend(&self, old: &Backend, new: &Backend) {
+        if let Ok(mut backends) = self.backends.lock() {
+            if let Some(calibration) = backends.remove(&key(old)) {
+                backends.insert(key(new), calibration);
+            }
+        }
+    }
+
+    /// Forgets everything about a backend.
+    pub fn remove_backend(&self, backend: &Backend) {
+        if let Ok(mut backends) = self.backends.lock() {
+            backends.remove(&key(backend));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+    use crate::backend::test_backend;
+
+    #[test]
+    fn test_calibration_corrects_biased_estimates() {
+        let calibration = LatencyCalibration::default();
+        let optimist = test_backend("b1", SocketAddr::from(([127, 0, 0, 1], 8080)), 1);
+        let honest = test_backend("b2", SocketAddr::from(([127, 0, 0, 1], 8080)), 2);
+
+        // Without observations, estimates are trusted
+        assert_eq!(calibration.correct(&optimist, 10), 10);
+
+        for _ in 0..50 {
+            calibration.observe(&optimist, Duration::from_millis(40), Some(10));
+            calibration.observe(&honest, Duration::from_millis(20), Some(20));
+        }
+        assert_eq!(calibration.correct(&optimist, 10), 40);
+        assert_eq!(calibration.correct(&honest, 20), 20);
+    }
+
+    #[test]
+    fn test_calibration_observed_latency() {
+        let calibration = LatencyCalibration::default();
+        let backend = test_backend("b1", SocketAddr::from(([127, 0, 0, 1], 8080)), 1);
+        assert_eq!(calibration.observed_latency(&backend), None);
+
+        calibration.observe(&backend, Duration::from_millis(100), None);
+        assert_eq!(calibration.observed_latency(&backend), Some(100));
+        // No estimate, no bias
+        assert_eq!(calibration.correct(&backend, 10), 10);
+
+        calibration.observe(&backend, Duration::from_millis(200), None);
+        assert_eq!(calibration.observed_latency(&backend), Some(110));
+
+        calibration.remove_backend(&backend);
+        assert_eq!(calibration.observed_latency(&backend), None);
+    }
+
+    #[test]
+    fn test_calibration_bias_is_bounded() {
+        let calibration = LatencyCalibration::default();
+        let backend = test_backend("b1", SocketAddr::from(([127, 0, 0, 1], 8080)), 1);
+        calibration.observe(&backend, Duration::from_secs(10), Some(1));
+        assert_eq!(calibration.correct(&backend, 10), 100);
+    }
+}
@@ -1,14 +1,24 @@
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::mpsc::{channel, Sender};
-use std::sync::{Arc, RwLock};
-use std::time::{Duration, SystemTime};
-
-use rand::seq::IteratorRandom;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+use rand::seq::SliceRandom;
+use rand::Rng;
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
 use varnish::ffi::VCL_BACKEND;
 use varnish::VscMetric;
 
 use crate::backend::Backend;
-use crate::probe::{ProbeResult, ProbeTable, PROBE_TABLE_SIZE};
+use crate::breaker::{BreakerConfig, CircuitBreakers, Transition};
+use crate::ejection::{Ejection, EjectionConfig, OutlierDetector};
+use crate::hashring::HashRing;
+use crate::probe::{Pick, ProbeResult, ProbeTable, ProbeTableConfig};
+use crate::prober::{self, ProbeError, ProbeSpec};
+use crate::scheduler::{ProbeBudget, ProbeScheduler};
+use crate::slowstart::{SlowStart, SlowStartCurve};
 
 /// Varnish statistics counters for the prequal director.
 /// These are exposed via Varnish's varnishstat tool.
@@ -25,10 +35,66 @@ pub struct DirectorStats {
     #[counter]
     pub selected_from_table: AtomicU64,
 
+    /// Backends selected by local connection counts, with `load_signal = local`
+    #[counter]
+    pub selected_by_local_load: AtomicU64,
+
     /// Fallback to random backend selection
     #[counter]
     pub fallback_random: AtomicU64,
 
+    /// Fallback to round-robin backend selection
+    #[counter]
+    pub fallback_round_robin: AtomicU64,
+
+    /// Fallback to the backend with the fewest local connections
+    #[counter]
+    pub fallback_least_local_connections: AtomicU64,
+
+    /// Fallback to the less loaded of two random backends
+    #[counter]
+    pub fallback_power_of_two: AtomicU64,
+
+    /// Fallback to the best backend according to expired probes
+    #[counter]
+    pub fallback_last_known: AtomicU64,
+
+    /// Keyed requests sent to the backend owning the key
+    #[counter]
+    pub hash_affinity: AtomicU64,
+
+    /// Keyed requests moved along the hash ring because of the load bound
+    #[counter]
+    pub hash_bounded: AtomicU64,
+
+    /// Keyed requests moved to the best probe because the owner was hot or slow
+    #[counter]
+    pub hash_spilled: AtomicU64,
+
+    /// Backends selected in the local zone
+    #[counter]
+    pub zone_local: AtomicU64,
+
+    /// Backends selected outside the local zone
+    #[counter]
+    pub zone_spillover: AtomicU64,
+
+    /// Candidate backends skipped because Varnish considers them sick
+    #[counter]
+    pub skipped_unhealthy: AtomicU64,
+
+    /// Candidate backends skipped because the request already tried them
+    #[counter]
+    pub skipped_tried: AtomicU64,
+
+    /// Candidate backends skipped because they are being drained
+    #[counter]
+    pub skipped_draining: AtomicU64,
+
+    /// Requests whose pick was held back because it is in its slow start
+    #[counter]
+    pub skipped_slow_start: AtomicU64,
+
     /// Total probe requests sent
     #[counter]
     pub probes_sent: AtomicU64,
@@ -41,14 +107,205 @@ pub struct DirectorStats {
     #[counter]
     pub probes_fail: AtomicU64,
 
-    /// Probes with missing required headers (X-In-Flight or X-Estimated-Latency)
+    /// Probes missing the in-flight header, or the latency header before any
+    /// latency was observed
     #[counter]
     pub probes_missing_headers: AtomicU64,
 
+    /// Probes whose JSON body could not be parsed or lacked the load fields
+    #[counter]
+    pub probes_invalid_body: AtomicU64,
+
+    /// Probes that did not complete within the probe timeout
+    #[counter]
+    pub probes_timeout: AtomicU64,
+
+    /// Probes not sent because too many were already in flight
+    #[counter]
+    pub probes_skipped: AtomicU64,
+
+    /// Probes not sent because max_probe_rate was reached
+    #[counter]
+    pub probes_rate_limited: AtomicU64,
+
+    /// Probe results dropped because their backend left the pool meanwhile
+    #[counter]
+    pub probes_dropped: AtomicU64,
+
+    /// Load signals reported from real backend responses
+    #[counter]
+    pub reports: AtomicU64,
+
+    /// Reports ignored because of unparsable values or an unknown backend
+    #[counter]
+    pub reports_invalid: AtomicU64,
+
+    /// Fetch latencies observed, to calibrate estimated latencies
+    #[counter]
+    pub observations: AtomicU64,
+
+    /// Backends ejected for failing their probes
+    #[counter]
+    pub ejections: AtomicU64,
+
+    /// Ejected backends returned to the pool
+    #[counter]
+    pub unejections: AtomicU64,
+
+    /// Ejections not done because max_ejected_percent was reached
+    #[counter]
+    pub ejections_prevented: AtomicU64,
+
+    /// Response statuses recorded with record_result
+    #[counter]
+    pub results: AtomicU64,
+
+    /// Recorded responses counted as errors (5xx)
+    #[counter]
+    pub result_errors: AtomicU64,
+
+    /// Circuit breakers opened by errors or a failed trial
+    #[counter]
+    pub breaker_opened: AtomicU64,
+
+    /// Circuit breakers closed after successful trials
+    #[counter]
+    pub breaker_closed: AtomicU64,
+
+    // Histograms, with a counter per bucket: each value is counted once,
+    // in the first bucket holding it
+    /// Probes answered in up to 1ms
+    #[counter]
+    pub probe_rtt_le_1ms: AtomicU64,
+
+    /// Probes answered in over 1ms, up to 5ms
+    #[counter]
+    pub probe_rtt_le_5ms: AtomicU64,
+
+    /// Probes answered in over 5ms, up to 10ms
+    #[counter]
+    pub probe_rtt_le_10ms: AtomicU64,
+
+    /// Probes answered in over 10ms, up to 50ms
+    #[counter]
+    pub probe_rtt_le_50ms: AtomicU64,
+
+    /// Probes answered in over 50ms, up to 100ms
+    #[counter]
+    pub probe_rtt_le_100ms: AtomicU64,
+
+    /// Probes answered in over 100ms, up to 500ms
+    #[counter]
+    pub probe_rtt_le_500ms: AtomicU64,
+
+    /// Probes answered in over 500ms
+    #[counter]
+    pub probe_rtt_gt_500ms: AtomicU64,
+
+    /// Probe results with up to 1 request in flight
+    #[counter]
+    pub probe_rif_le_1: AtomicU64,
+
+    /// Probe results with 2 to 5 requests in flight
+    #[counter]
+    pub probe_rif_le_5: AtomicU64,
+
+    /// Probe results with 6 to 10 requests in flight
+    #[counter]
+    pub probe_rif_le_10: AtomicU64,
+
+    /// Probe results with 11 to 50 requests in flight
+    #[counter]
+    pub probe_rif_le_50: AtomicU64,
+
+    /// Probe results with 51 to 100 requests in flight
+    #[counter]
+    pub probe_rif_le_100: AtomicU64,
+
+    /// Probe results with 101 to 500 requests in flight
+    #[counter]
+    pub probe_rif_le_500: AtomicU64,
+
+    /// Probe results with over 500 requests in flight
+    #[counter]
+    pub probe_rif_gt_500: AtomicU64,
+
+    /// Probe results estimating a latency up to 5ms
+    #[counter]
+    pub probe_latency_le_5ms: AtomicU64,
+
+    /// Probe results estimating a latency over 5ms, up to 10ms
+    #[counter]
+    pub probe_latency_le_10ms: AtomicU64,
+
+    /// Probe results estimating a latency over 10ms, up to 50ms
+    #[counter]
+    pub probe_latency_le_50ms: AtomicU64,
+
+    /// Probe results estimating a latency over 50ms, up to 100ms
+    #[counter]
+    pub probe_latency_le_100ms: AtomicU64,
+
+    /// Probe results estimating a latency over 100ms, up to 500ms
+    #[counter]
+    pub probe_latency_le_500ms: AtomicU64,
+
+    /// Probe results estimating a latency over 500ms, up to 1000ms
+    #[counter]
+    pub probe_latency_le_1000ms: AtomicU64,
+
+    /// Probe results estimating a latency over 1000ms
+    #[counter]
+    pub probe_latency_gt_1000ms: AtomicU64,
+
+    /// Probes selected from the table up to 10ms old
+    #[counter]
+    pub time_to_use_le_10ms: AtomicU64,
+
+    /// Probes selected from the table over 10ms, up to 50ms old
+    #[counter]
+    pub time_to_use_le_50ms: AtomicU64,
+
+    /// Probes selected from the table over 50ms, up to 100ms old
+    #[counter]
+    pub time_to_use_le_100ms: AtomicU64,
+
+    /// Probes selected from the table over 100ms, up to 500ms old
+    #[counter]
+    pub time_to_use_le_500ms: AtomicU64,
+
+    /// Probes selected from the table over 500ms, up to 1000ms old
+    #[counter]
+    pub time_to_use_le_1000ms: AtomicU64,
+
+    /// Probes selected from the table over 1000ms, up to 5000ms old
+    #[counter]
+    pub time_to_use_le_5000ms: AtomicU64,
+
+    /// Probes selected from the table over 5000ms old
+    #[counter]
+    pub time_to_use_gt_5000ms: AtomicU64,
+
+    /// Probe requests currently in flight
+    #[gauge]
+    pub probes_in_flight: AtomicU64,
+
     /// Currently registered backends
     #[gauge]
     pub backends: AtomicU64,
 
+    /// Backends currently being drained
+    #[gauge]
+    pub backends_draining: AtomicU64,
+
+    /// Backends currently ejected for failing their probes
+    #[gauge]
+    pub backends_ejected: AtomicU64,
+
+    /// Backends whose circuit breaker is open or half-open
+    #[gauge]
+    pub breakers_open: AtomicU64,
+
     /// Current probe table size
     #[gauge]
     pub probe_table_size: AtomicU64,
@@ -78,383 +335,3377 @@ pub struct DirectorStats {
     pub probe_max_rif: AtomicU64,
 }
 
+/// Upper bounds of the histogram buckets in `DirectorStats`; each histogram
+/// has one more bucket, for the values above the last bound.
+const PROBE_RTT_BOUNDS_MS: [u64; 6] = [1, 5, 10, 50, 100, 500];
+const PROBE_RIF_BOUNDS: [u64; 6] = [1, 5, 10, 50, 100, 500];
+const PROBE_LATENCY_BOUNDS_MS: [u64; 6] = [5, 10, 50, 100, 500, 1000];
+const TIME_TO_USE_BOUNDS_MS: [u64; 6] = [10, 50, 100, 500, 1000, 5000];
+
+/// Counts `value` in the first bucket whose bound holds it.
+fn count_in_bucket(buckets: [&AtomicU64; 7], bounds: &[u64; 6], value: u64) {
+    let bucket = bounds
+        .iter()
+        .position(|bound| value <= *bound)
+        .unwrap_or(bounds.len());
+    buckets[bucket].fetch_add(1, Ordering::Relaxed);
+}
+
+impl DirectorStats {
+    fn probe_rtt_buckets(&self) -> [&AtomicU64; 7] {
+        [
+            &self.probe_rtt_le_1ms,
+            &self.probe_rtt_le_5ms,
+            &self.probe_rtt_le_10ms,
+            &self.probe_rtt_le_50ms,
+            &self.probe_rtt_le_100ms,
+            &self.probe_rtt_le_500ms,
+            &self.probe_rtt_gt_500ms,
+        ]
+    }
+
+    fn probe_rif_buckets(&self) -> [&AtomicU64; 7] {
+        [
+            &self.probe_rif_le_1,
+            &self.probe_rif_le_5,
+            &self.probe_rif_le_10,
+            &self.probe_rif_le_50,
+            &self.probe_rif_le_100,
+            &self.probe_rif_le_500,
+            &self.probe_rif_gt_500,
+        ]
+    }
+
+    fn probe_latency_buckets(&self) -> [&AtomicU64; 7] {
+        [
+            &self.probe_latency_le_5ms,
+            &self.probe_latency_le_10ms,
+            &self.probe_latency_le_50ms,
+            &self.probe_latency_le_100ms,
+            &self.probe_latency_le_500ms,
+            &self.probe_latency_le_1000ms,
+            &self.probe_latency_gt_1000ms,
+        ]
+    }
+
+    fn time_to_use_buckets(&self) -> [&AtomicU64; 7] {
+        [
+            &self.time_to_use_le_10ms,
+            &self.time_to_use_le_50ms,
+            &self.time_to_use_le_100ms,
+            &self.time_to_use_le_500ms,
+            &self.time_to_use_le_1000ms,
+            &self.time_to_use_le_5000ms,
+            &self.time_to_use_gt_5000ms,
+        ]
+    }
+
+    /// Returns the buckets of every histogram, e.g. to copy them elsewhere.
+    pub fn histograms(&self) -> [[&AtomicU64; 7]; 4] {
+        [
+            self.probe_rtt_buckets(),
+            self.probe_rif_buckets(),
+            self.probe_latency_buckets(),
+            self.time_to_use_buckets(),
+        ]
+    }
+
+    /// Returns the counters and gauges as a JSON object, each histogram
+    /// as an array of its bucket counts.
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut json = serde_json::Map::new();
+        macro_rules! insert {
+            ($($field:ident),* $(,)?) => {
+                $(json.insert(
+                    stringify!($field).to_string(),
+                    self.$field.load(Ordering::Relaxed).into(),
+                );)*
+            };
+        }
+        insert!(
+            req,
+            selected_from_table,
+            selected_by_local_load,
+            fallback_random,
+            fallback_round_robin,
+            fallback_least_local_connections,
+            fallback_power_of_two,
+            fallback_last_known,
+            hash_affinity,
+            hash_bounded,
+            hash_spilled,
+            zone_local,
+            zone_spillover,
+            skipped_unhealthy,
+            skipped_tried,
+            skipped_draining,
+            skipped_slow_start,
+            probes_sent,
+            probes_success,
+            probes_fail,
+            probes_missing_headers,
+            probes_invalid_body,
+            probes_timeout,
+            probes_skipped,
+            probes_rate_limited,
+            probes_dropped,
+            reports,
+            reports_invalid,
+            observations,
+            ejections,
+            unejections,
+            ejections_prevented,
+            results,
+            result_errors,
+            breaker_opened,
+            breaker_closed,
+            probes_in_flight,
+            backends,
+            backends_draining,
+            backends_ejected,
+            breakers_open,
+            probe_table_size,
+            probe_p50_rif,
+            probe_p80_rif,
+            probe_p50_latency,
+            probe_p80_latency,
+            probe_min_rif,
+            probe_max_rif,
+        );
+        let counts = |buckets: [&AtomicU64; 7]| buckets.map(|b| b.load(Ordering::Relaxed));
+        json.insert(
+            "histograms".to_string(),
+            serde_json::json!({
+                "probe_rtt_ms": {
+                    "bounds": PROBE_RTT_BOUNDS_MS,
+                    "counts": counts(self.probe_rtt_buckets()),
+                },
+                "probe_rif": {
+                    "bounds": PROBE_RIF_BOUNDS,
+                    "counts": counts(self.probe_rif_buckets()),
+                },
+                "probe_latency_ms": {
+                    "bounds": PROBE_LATENCY_BOUNDS_MS,
+                    "counts": counts(self.probe_latency_buckets()),
+                },
+                "time_to_use_ms": {
+                    "bounds": TIME_TO_USE_BOUNDS_MS,
+                    "counts": counts(self.time_to_use_buckets()),
+                },
+            }),
+        );
+        serde_json::Value::Object(json)
+    }
+
+    /// Records how long an answered probe took.
+    fn observe_probe_rtt(&self, rtt: Duration) {
+        count_in_bucket(
+            self.probe_rtt_buckets(),
+            &PROBE_RTT_BOUNDS_MS,
+            rtt.as_millis() as u64,
+        );
+    }
+
+    /// Records the load signals of a probe result.
+    fn observe_probe_result(&self, rif: usize, est_latency: usize) {
+        count_in_bucket(self.probe_rif_buckets(), &PROBE_RIF_BOUNDS, rif as u64);
+        count_in_bucket(
+            self.probe_latency_buckets(),
+            &PROBE_LATENCY_BOUNDS_MS,
+            est_latency as u64,
+        );
+    }
+
+    /// Records how old a probe result was when selection used it.
+    fn observe_time_to_use(&self, age: Duration) {
+        count_in_bucket(
+            self.time_to_use_buckets(),
+            &TIME_TO_USE_BOUNDS_MS,
+            age.as_millis() as u64,
+        );
+    }
+}
+
+/// Varnish statistics counters for a single backend of the director, so
+/// the backend behind a change in the director-wide figures stands out.
+/// The VCL wrapper gives each backend its own segment.
+#[derive(VscMetric, Default)]
+#[repr(C)]
+pub struct BackendStats {
+    /// Times this backend was selected
+    #[counter]
+    pub selected: AtomicU64,
+
+    /// Probes sent to this backend
+    #[counter]
+    pub probes_sent: AtomicU64,
+
+    /// Probes of this backend added to the probe table
+    #[counter]
+    pub probes_success: AtomicU64,
+
+    /// Probes of this backend that failed or timed out
+    #[counter]
+    pub probes_fail: AtomicU64,
+
+    /// Probes of this backend evicted from the table: stale, over-used,
+    /// or the worst of a full table
+    #[counter]
+    pub evictions: AtomicU64,
+
+    /// Requests in flight according to the latest probe or report
+    #[gauge]
+    pub last_rif: AtomicU64,
+
+    /// Estimated latency according to the latest probe or report
+    #[gauge]
+    pub last_latency: AtomicU64,
+
+    /// Age of the latest probe or report (ms)
+    #[gauge]
+    pub probe_age: AtomicU64,
+}
+
+/// Varnish statistics counters for a zone of the director's backends, so
+/// the spillover counted director-wide can be traced to the zones that
+/// took it. The VCL wrapper gives each zone its own segment.
+#[derive(VscMetric, Default)]
+#[repr(C)]
+pub struct ZoneStats {
+    /// Times a backend of this zone was selected
+    #[counter]
+    pub selected: AtomicU64,
+}
+
 #[derive(Debug)]
 pub enum DirectorError {
     BackendLockError(String),
+    InvalidConfig(String),
+    NoHealthyBackend,
+    UnknownBackend,
+    MissingLatency,
 }
 
 impl std::fmt::Display for DirectorError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             DirectorError::BackendLockError(msg) => write!(f, "Backend lock error: {}", msg),
+            DirectorError::InvalidConfig(msg) => write!(f, "Invalid configuration: {}", msg),
+            DirectorError::NoHealthyBackend => write!(f, "No healthy backend available"),
+            DirectorError::UnknownBackend => write!(f, "Backend is not part of this director"),
+            DirectorError::MissingLatency => {
+                write!(f, "No estimated or observed latency for this backend")
+            }
         }
     }
 }
 
 impl std::error::Error for DirectorError {}
 
-pub struct Director {
-    backends: RwLock<Vec<Backend>>,
-    probe_table: ProbeTable,
-    probe_trigger: Sender<()>,
-    probe_path: RwLock<String>,
-    stats: Arc<DirectorStats>,
+/// How `Director::get_backend` picks a backend when the probe table has
+/// nothing usable, e.g. during cold start or a probe outage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FallbackMode {
+    /// Weighted random choice
+    #[default]
+    Random,
+    /// Each backend in turn, ignoring weights
+    RoundRobin,
+    /// The backend with the fewest connections from this Varnish, relative to its weight
+    LeastLocalConnections,
+    /// The less loaded of two weighted random backends, by local connections
+    PowerOfTwo,
+    /// The best backend according to the latest probe of each backend, however old
+    LastKnown,
 }
 
-const PROBE_INTERVAL: Duration = Duration::from_secs(5);
-const DEFAULT_PROBE_COUNT: usize = 3;
-
-impl Director {
-    /// Creates a new Director instance along with its probe loop closure.
-    ///
-    /// # Arguments
-    /// * `stats` - An `Arc<DirectorStats>` for recording metrics. The Director
-    ///   will update these stats directly; the caller can share this Arc
-    ///   or sync it to other stats storage (e.g., Vsc for varnishstat).
-    ///
-    /// Returns a tuple containing:
-    /// - An Arc-wrapped Director instance
-    /// - A closure that runs the probe loop when spawned in a thread
-    pub fn new(stats: Arc<DirectorStats>) -> (Arc<Self>, impl FnOnce()) {
-        let (tx, rx) = channel();
-
-        let inner = Arc::new(Self {
-            backends: RwLock::new(Vec::new()),
-            probe_table: ProbeTable::new(),
-            probe_trigger: tx,
-            probe_path: RwLock::new("/probe".to_string()),
-            stats,
-        });
+impl FromStr for FallbackMode {
+    type Err = DirectorError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "random" => Ok(FallbackMode::Random),
+            "round_robin" => Ok(FallbackMode::RoundRobin),
+            "least_local_connections" => Ok(FallbackMode::LeastLocalConnections),
+            "power_of_two" => Ok(FallbackMode::PowerOfTwo),
+            "last_known" => Ok(FallbackMode::LastKnown),
+            _ => Err(DirectorError::InvalidConfig(format!(
+                "unknown fallback mode \"{}\"",
+                s
+            ))),
+        }
+    }
+}
 
-        let probe_loop = {
-            let inner = Arc::downgrade(&inner);
-            move || {
-                while let Some(director) = inner.upgrade() {
-                    // Wait for trigger or timeout
-                    if rx.recv_timeout(PROBE_INTERVAL).is_ok() {
-                        director.probe_backends(DEFAULT_PROBE_COUNT);
-                    } else {
-                        // Ensure probe pool every interval
-                        director.ensure_probe_pool();
-                    }
-                    // Update computed metrics after probing
-                    director.compute_metrics();
-                }
-            }
+impl std::fmt::Display for FallbackMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            FallbackMode::Random => "random",
+            FallbackMode::RoundRobin => "round_robin",
+            FallbackMode::LeastLocalConnections => "least_local_connections",
+            FallbackMode::PowerOfTwo => "power_of_two",
+            FallbackMode::LastKnown => "last_known",
         };
-
-        (inner, probe_loop)
+        write!(f, "{}", name)
     }
+}
 
-    /// Sets the HTTP path used for health check probes.
-    ///
-    /// # Arguments
-    /// * `path` - The URL path to use for probe requests (e.g. "/probe")
-    pub fn set_probe_path(&self, path: &str) {
-        if let Ok(mut probe_path) = self.probe_path.write() {
-            *probe_path = path.to_string();
+/// Which requests-in-flight signal selection relies on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LoadSignal {
+    /// The RIF reported by probes
+    #[default]
+    Probe,
+    /// This Varnish's own connections to each backend, without probing
+    Local,
+    /// The probed RIF plus this Varnish's connections since
+    Blended,
+}
+
+impl FromStr for LoadSignal {
+    type Err = DirectorError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "probe" => Ok(LoadSignal::Probe),
+            "local" => Ok(LoadSignal::Local),
+            "blended" => Ok(LoadSignal::Blended),
+            _ => Err(DirectorError::InvalidConfig(format!(
+                "unknown load signal \"{}\"",
+                s
+            ))),
         }
     }
+}
 
-    /// Adds a backend to the director's pool.
-    ///
-    /// # Arguments
-    /// * `backend` - The backend to add
-    ///
-    /// # Returns
-    /// * `Ok(())` if the backend was added successfully
-    /// * `Err(DirectorError)` if the backend could not be added
-    pub fn add_backend(&self, backend: Backend) -> Result<(), DirectorError> {
-        let mut backends = self
-            .backends
-            .write()
-            .map_err(|e| DirectorError::BackendLockError(e.to_string()))?;
-
-        backends.push(backend);
-        Ok(())
+impl std::fmt::Display for LoadSignal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            LoadSignal::Probe => "probe",
+            LoadSignal::Local => "local",
+            LoadSignal::Blended => "blended",
+        };
+        write!(f, "{}", name)
     }
+}
 
-    /// Removes a backend from the pool by its VCL_BACKEND reference.
-    /// Also removes any probe results for this backend.
-    ///
-    /// # Arguments
-    /// * `vcl_backend` - The VCL_BACKEND reference to remove
-    pub fn remove_backend(&self, vcl_backend: VCL_BACKEND) {
-        if let Ok(mut backends) = self.backends.write() {
-            if let Some(backend) = backends.iter().find(|b| **b == vcl_backend).cloned() {
-                self.probe_table.remove_backend(backend);
-                backends.retain(|b| *b != vcl_backend);
-            }
+/// What the VCL wrapper logs to VSL on each selection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum LogLevel {
+    /// Nothing
+    #[default]
+    Off,
+    /// One compact record per selection, see `Decision`
+    Decision,
+    /// The decision, then the whole probe table state
+    Table,
+}
+
+impl FromStr for LogLevel {
+    type Err = DirectorError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(LogLevel::Off),
+            "decision" => Ok(LogLevel::Decision),
+            "table" => Ok(LogLevel::Table),
+            _ => Err(DirectorError::InvalidConfig(format!(
+                "unknown log level \"{}\"",
+                s
+            ))),
         }
     }
+}
 
-    pub fn trigger_probe(&self) {
-        let _ = self.probe_trigger.send(());
+impl std::fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            LogLevel::Off => "off",
+            LogLevel::Decision => "decision",
+            LogLevel::Table => "table",
+        };
+        write!(f, "{}", name)
     }
+}
 
-    /// Returns a string representation of the probe table, for debugging.
-    ///
-    /// # Returns
+/// Where a selected backend came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Selection {
+    /// The best entry of the probe table
+    ProbeTable,
+    /// The best backend by local connection counts, see `LoadSignal::Local`
+    LocalLoad,
+    /// The given fallback, the table had no usable entry
+    Fallback(FallbackMode),
+    /// The backend owning the request key on the hash ring
+    Hash,
+    /// A backend further along the hash ring, the owner being over its load bound
+    HashBounded,
+    /// The best entry of the probe table, the key's backend being hot or slow
+    HashSpilled,
+}
+
+/// A selected backend, where it came from and, for a backend picked from
+/// the probe table, what its probe said.
+#[derive(Debug, Clone)]
+pub struct Decision {
+    pub backend: Backend,
+    pub selection: Selection,
+    pub pick: Option<Pick>,
+}
+
+impl Decision {
+    fn new(backend: Backend, selection: Selection) -> Self {
+        Self {
+            backend,
+            selection,
+            pick: None,
+        }
+    }
+
+    fn picked(pick: Pick, selection: Selection) -> Self {
+        Self {
+            backend: pick.backend.clone(),
+            selection,
+            pick: Some(pick),
+        }
+    }
+}
+
+/// A compact one-line summary, e.g.
+/// `backend=s1 kind=cold rif=3 latency=30 age=0.012 runner_up=s2`.
+impl std::fmt::Display for Decision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "backend={}", self.backend.name)?;
+        match (&self.pick, self.selection) {
+            (Some(pick), selection) => {
+                write!(
+                    f,
+                    " kind={} rif={} latency={} age={:.3} runner_up={}",
+                    if pick.hot { "hot" } else { "cold" },
+                    pick.rif,
+                    pick.est_latency,
+                    pick.age.as_secs_f64(),
+                    pick.runner_up.as_ref().map_or("-", |b| b.name.as_str()),
+                )?;
+                if selection == Selection::HashSpilled {
+                    write!(f, " hash=spilled")?;
+                }
+                Ok(())
+            }
+            (None, Selection::Fallback(mode)) => write!(f, " kind=fallback mode={}", mode),
+            (None, Selection::Hash) => write!(f, " kind=hash"),
+            (None, Selection::HashBounded) => write!(f, " kind=hash_bounded"),
+            (None, Selection::LocalLoad) => write!(f, " kind=local_load"),
+            (None, Selection::ProbeTable | Selection::HashSpilled) => Ok(()),
+        }
+    }
+}
+
+pub const DEFAULT_PROBE_INTERVAL: Duration = Duration::from_secs(5);
+pub const DEFAULT_PROBE_RATIO: f64 = 3.0;
+pub const DEFAULT_MAX_PROBE_RATE: f64 = 200.0;
+pub const DEFAULT_IDLE_PROBE_RATE: f64 = 1.0;
+pub const DEFAULT_PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+pub const DEFAULT_MAX_PROBES_IN_FLIGHT: usize = 32;
+pub const DEFAULT_HASH_BALANCE_FACTOR: f64 = 1.25;
+/// Largest backend weight, which bounds the points a backend takes on the hash ring.
+pub const MAX_WEIGHT: f64 = 100.0;
+
+/// Tuning knobs for a `Director`, set once at construction time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DirectorConfig {
+    /// How often the probe loop tops up the table, or probes at the idle rate
+    pub probe_interval: Duration,
+    /// Probes sent per request, on average
+    pub probe_ratio: f64,
+    /// Maximum probes sent per second, excess probes are dropped
+    pub max_probe_rate: f64,
+    /// Probes sent per second when there is no traffic
+    pub idle_probe_rate: f64,
+    /// Upper bound for a single probe, connection included
+    pub probe_timeout: Duration,
+    /// Maximum number of probes in flight at once
+    pub max_probes_in_flight: usize,
+    /// Settings for the probe table
+    pub probe_table: ProbeTableConfig,
+    /// Selection strategy when the probe table has no usable entry
+    pub fallback: FallbackMode,
+    /// With a key, a backend takes at most this factor times its weighted
+    /// share of the local connections before keys move along the ring
+    pub hash_balance_factor: f64,
+    /// With a key, requests leave the key's backend if its probed latency
+    /// is above this; 0 disables the ceiling
+    pub hash_max_latency: usize,
+    /// Where the requests in flight of each backend are taken from
+    pub load_signal: LoadSignal,
+    /// How long new or recovered backends take to get their full share of
+    /// requests; zero disables slow start
+    pub slow_start: Duration,
+    /// How their share grows over `slow_start`
+    pub slow_start_curve: SlowStartCurve,
+    /// When backends failing their probes are ejected
+    pub ejection: EjectionConfig,
+    /// When errors in real responses open a backend's circuit breaker
+    pub breaker: BreakerConfig,
+}
+
+impl Default for DirectorConfig {
+    fn default() -> Self {
+        Self {
+            probe_interval: DEFAULT_PROBE_INTERVAL,
+            probe_ratio: DEFAULT_PROBE_RATIO,
+            max_probe_rate: DEFAULT_MAX_PROBE_RATE,
+            idle_probe_rate: DEFAULT_IDLE_PROBE_RATE,
+            probe_timeout: DEFAULT_PROBE_TIMEOUT,
+            max_probes_in_flight: DEFAULT_MAX_PROBES_IN_FLIGHT,
+            probe_table: ProbeTableConfig::default(),
+            fallback: FallbackMode::default(),
+            hash_balance_factor: DEFAULT_HASH_BALANCE_FACTOR,
+            hash_max_latency: 0,
+            load_signal: LoadSignal::default(),
+            slow_start: Duration::ZERO,
+            slow_start_curve: SlowStartCurve::default(),
+            ejection: EjectionConfig::default(),
+            breaker: BreakerConfig::default(),
+        }
+    }
+}
+
+impl DirectorConfig {
+    /// Checks that every setting is within its allowed range.
+    ///
+    /// # Returns
+    /// * `Ok(())` if the configuration is usable
+    /// * `Err(DirectorError::InvalidConfig)` describing the first invalid setting
+    pub fn validate(&self) -> Result<(), DirectorError> {
+        let invalid = |msg: &str| Err(DirectorError::InvalidConfig(msg.to_string()));
+
+        if self.probe_interval.is_zero() {
+            return invalid("probe_interval must be greater than zero");
+        }
+        if !(self.probe_ratio > 0.0 && self.probe_ratio.is_finite()) {
+            return invalid("probe_ratio must be greater than zero");
+        }
+        if !(self.max_probe_rate > 0.0 && self.max_probe_rate.is_finite()) {
+            return invalid("max_probe_rate must be greater than zero");
+        }
+        if !(self.idle_probe_rate >= 0.0 && self.idle_probe_rate.is_finite()) {
+            return invalid("idle_probe_rate must not be negative");
+        }
+        if self.probe_timeout.is_zero() {
+            return invalid("probe_timeout must be greater than zero");
+        }
+        if self.max_probes_in_flight == 0 {
+            return invalid("max_probes_in_flight must be at least 1");
+        }
+        if self.probe_table.size < 2 {
+            return invalid("probe_table_size must be at least 2");
+        }
+        if self.probe_table.max_probe_age.is_zero() {
+            return invalid("max_probe_age must be greater than zero");
+        }
+        if self.probe_table.max_uses == 0 {
+            return invalid("max_uses must be at least 1");
+        }
+        if !(self.probe_table.hot_threshold > 0.0 && self.probe_table.hot_threshold <= 1.0) {
+            return invalid("hot_threshold must be greater than 0 and at most 1");
+        }
+        if !(self.hash_balance_factor >= 1.0 && self.hash_balance_factor.is_finite()) {
+            return invalid("hash_balance_factor must be at least 1");
+        }
+        if !(0.0..=1.0).contains(&self.ejection.failure_rate) {
+            return invalid("eject_failure_rate must be between 0 and 1");
+        }
+        if self.ejection.ejection_time.is_zero() {
+            return invalid("ejection_time must be greater than zero");
+        }
+        if self.ejection.max_ejected_percent > 100 {
+            return invalid("max_ejected_percent must be at most 100");
+        }
+        if !(0.0..=1.0).contains(&self.breaker.error_rate) {
+            return invalid("breaker_error_rate must be between 0 and 1");
+        }
+        if self.breaker.window.is_zero() {
+            return invalid("breaker_window must be greater than zero");
+        }
+        if self.breaker.open_time.is_zero() {
+            return invalid("breaker_open_time must be greater than zero");
+        }
+        if self.breaker.min_requests == 0 {
+            return invalid("breaker_min_requests must be at least 1");
+        }
+        if self.breaker.half_open_requests == 0 {
+            return invalid("breaker_half_open_requests must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+/// A backend taken out of selection until `until`, see `Director::drain_backend`.
+#[derive(Debug, Clone)]
+struct Drain {
+    backend: Backend,
+    until: Instant,
+    // Whether the backend is removed, rather than selected again, afterwards
+    remove: bool,
+}
+
+pub struct Director {
+    backends: RwLock<Vec<Backend>>,
+    // Rebuilt with `backends` held for writing, so its indices match it
+    hash_ring: RwLock<HashRing>,
+    probe_table: ProbeTable,
+    // Wakes the probe loop; notifications coalesce while it is busy
+    probe_wakeup: Arc<Notify>,
+    // Requests served since the probe loop last woke up
+    pending_requests: AtomicU64,
+    seed_requested: AtomicBool,
+    // Set while no VCL using the director is warm
+    paused: AtomicBool,
+    stopped: AtomicBool,
+    // The thread running the probe loop, to join when stopping it
+    probe_thread: Mutex<Option<JoinHandle<()>>>,
+    // Bounds the number of concurrent probes
+    probe_slots: Arc<Semaphore>,
+    // Swapped as a whole, so in-flight probes keep the settings they started with
+    probe_spec: RwLock<Arc<ProbeSpec>>,
+    local_zone: RwLock<Option<String>>,
+    drains: RwLock<Vec<Drain>>,
+    slow_start: SlowStart,
+    outliers: OutlierDetector,
+    breakers: CircuitBreakers,
+    // Keyed by the backend's VCL_BACKEND address
+    backend_stats: RwLock<HashMap<usize, Arc<BackendStats>>>,
+    // Keyed by zone name, kept once a zone's backends are gone
+    zone_stats: RwLock<HashMap<String, Arc<ZoneStats>>>,
+    // Next position for the round-robin fallback
+    round_robin: AtomicUsize,
+    stats: Arc<DirectorStats>,
+    config: DirectorConfig,
+}
+
+impl Director {
+    /// Creates a new Director instance along with its probe loop closure.
+    ///
+    /// # Arguments
+    /// * `stats` - An `Arc<DirectorStats>` for recording metrics. The Director
+    ///   will update these stats directly; the caller can share this Arc
+    ///   or sync it to other stats storage (e.g., Vsc for varnishstat).
+    /// * `config` - Tuning knobs for probing and selection; callers should
+    ///   check it with `DirectorConfig::validate` first
+    ///
+    /// Returns a tuple containing:
+    /// - An Arc-wrapped Director instance
+    /// - A closure that runs the probe loop when spawned in a thread.
+    ///   It drives its own single-threaded tokio runtime, on which probes
+    ///   are sent concurrently, and returns once the Director is dropped.
+    pub fn new(stats: Arc<DirectorStats>, config: DirectorConfig) -> (Arc<Self>, impl FnOnce()) {
+        let wakeup = Arc::new(Notify::new());
+        let table_config = ProbeTableConfig {
+            local_load: config.load_signal == LoadSignal::Blended,
+            ..config.probe_table
+        };
+
+        let inner = Arc::new(Self {
+            backends: RwLock::new(Vec::new()),
+            hash_ring: RwLock::new(HashRing::default()),
+            probe_table: ProbeTable::new(table_config),
+            probe_wakeup: wakeup.clone(),
+            pending_requests: AtomicU64::new(0),
+            seed_requested: AtomicBool::new(false),
+            paused: AtomicBool::new(false),
+            stopped: AtomicBool::new(false),
+            probe_thread: Mutex::new(None),
+            probe_slots: Arc::new(Semaphore::new(config.max_probes_in_flight)),
+            probe_spec: RwLock::new(Arc::new(ProbeSpec::default())),
+            local_zone: RwLock::new(None),
+            drains: RwLock::new(Vec::new()),
+            slow_start: SlowStart::new(config.slow_start, config.slow_start_curve),
+            outliers: OutlierDetector::new(config.ejection),
+            breakers: CircuitBreakers::new(config.breaker),
+            backend_stats: RwLock::new(HashMap::new()),
+            zone_stats: RwLock::new(HashMap::new()),
+            round_robin: AtomicUsize::new(0),
+            stats,
+            config,
+        });
+
+        let probe_loop = {
+            let inner = Arc::downgrade(&inner);
+            move || {
+                // Nothing to probe, selection only looks at local connections
+                if config.load_signal == LoadSignal::Local {
+                    return;
+                }
+                let runtime = tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                    .expect("failed to build probe runtime");
+
+                runtime.block_on(async move {
+                    let mut scheduler = ProbeScheduler::new(
+                        config.probe_ratio,
+                        config.max_probe_rate,
+                        config.idle_probe_rate,
+                        Instant::now(),
+                    );
+                    let mut last_tick = Instant::now();
+                    let mut had_traffic = false;
+
+                    loop {
+                        // Wait for requests, a seed, or the next interval
+                        let _ =
+                            tokio::time::timeout(config.probe_interval, wakeup.notified()).await;
+                        // The Director wakes us up one last time when dropped
+                        let Some(director) = inner.upgrade() else {
+                            break;
+                        };
+                        if director.stopped.load(Ordering::Relaxed) {
+                            break;
+                        }
+                        if director.paused.load(Ordering::Relaxed) {
+                            continue;
+                        }
+                        let now = Instant::now();
+
+                        if director.seed_requested.swap(false, Ordering::Relaxed) {
+                            let size = config.probe_table.size;
+                            director.send_probes(scheduler.on_refill(size, now));
+                        }
+
+                        let requests = director.pending_requests.swap(0, Ordering::Relaxed);
+                        if requests > 0 {
+                            had_traffic = true;
+                            director.send_probes(scheduler.on_requests(requests, now));
+                        }
+
+                        let elapsed = now.duration_since(last_tick);
+                        if elapsed >= config.probe_interval {
+                            if had_traffic {
+                                // Ensure probe pool every interval
+                                director.ensure_probe_pool(&mut scheduler, now);
+                            } else {
+                                director.send_probes(scheduler.on_idle(elapsed, now));
+                            }
+                            had_traffic = false;
+                            last_tick = now;
+                        }
+
+                        // Drains and ejections also end without traffic
+                        director.finish_drains();
+                        director.return_ejected();
+
+                        // Update computed metrics; results of probes still in
+                        // flight are picked up on the next iteration
+                        director.compute_metrics();
+                    }
+                });
+            }
+        };
+
+        (inner, probe_loop)
+    }
+
+    /// Sets the HTTP path used for health check probes.
+    ///
+    /// # Arguments
+    /// * `path` - The URL path to use for probe requests (e.g. "/probe"),
+    ///   optionally with a query string
+    ///
+    /// # Returns
+    /// * `Err(DirectorError::InvalidConfig)` if the path is not a valid request target
+    pub fn set_probe_path(&self, path: &str) -> Result<(), DirectorError> {
+        self.update_probe_spec(|spec| spec.set_path(path))
+    }
+
+    /// Sets the HTTP method used for probe requests.
+    ///
+    /// # Arguments
+    /// * `method` - The request method (e.g. "HEAD")
+    pub fn set_probe_method(&self, method: &str) -> Result<(), DirectorError> {
+        self.update_probe_spec(|spec| spec.set_method(method))
+    }
+
+    /// Adds a static header to every probe request.
+    ///
+    /// # Arguments
+    /// * `name` - The header name; a `Host` header replaces the backend name
+    /// * `value` - The header value
+    pub fn add_probe_header(&self, name: &str, value: &str) -> Result<(), DirectorError> {
+        self.update_probe_spec(|spec| spec.add_header(name, value))
+    }
+
+    /// Sets the probe response headers holding the load signals.
+    ///
+    /// # Arguments
+    /// * `in_flight` - Header holding the requests in flight (default "X-In-Flight")
+    /// * `latency` - Header holding the estimated latency (default "X-Estimated-Latency")
+    pub fn set_load_headers(&self, in_flight: &str, latency: &str) -> Result<(), DirectorError> {
+        self.update_probe_spec(|spec| spec.set_load_headers(in_flight, latency))
+    }
+
+    /// Sets where probe responses carry the load signals.
+    ///
+    /// # Arguments
+    /// * `format` - "headers", "json" or "auto" (JSON if the Content-Type says so)
+    /// * `in_flight_field` - JSON field holding the requests in flight, if not "in_flight"
+    /// * `latency_field` - JSON field holding the estimated latency, if not "estimated_latency"
+    ///
+    /// # Returns
+    /// * `Err(DirectorError::InvalidConfig)` if the format is unknown
+    pub fn set_probe_format(
+        &self,
+        format: &str,
+        in_flight_field: Option<&str>,
+        latency_field: Option<&str>,
+    ) -> Result<(), DirectorError> {
+        self.update_probe_spec(|spec| spec.set_format(format, in_flight_field, latency_field))
+    }
+
+    /// Applies `update` to the probe settings, used by probes started from now on.
+    pub(crate) fn update_probe_spec(
+        &self,
+        update: impl FnOnce(&mut ProbeSpec) -> Result<(), DirectorError>,
+    ) -> Result<(), DirectorError> {
+        let mut spec = self
+            .probe_spec
+            .write()
+            .map_err(|e| DirectorError::BackendLockError(e.to_string()))?;
+        update(Arc::make_mut(&mut spec))
+    }
+
+    /// Sets the zone this director runs in. Selection then prefers cold
+    /// backends of that zone, and only goes to other zones when all local
+    /// probes are hot or missing.
+    ///
+    /// # Arguments
+    /// * `zone` - The local zone, matched against the backends' zones
+    pub fn set_local_zone(&self, zone: &str) {
+        if let Ok(mut local_zone) = self.local_zone.write() {
+            *local_zone = Some(zone.to_string());
+        }
+    }
+
+    /// Replaces the probe settings and the local zone at once, e.g. with
+    /// those a VCL staged for a shared director.
+    pub fn replace_probe_settings(&self, spec: ProbeSpec, local_zone: Option<String>) {
+        if let Ok(mut current) = self.probe_spec.write() {
+            *current = Arc::new(spec);
+        }
+        if let Ok(mut current) = self.local_zone.write() {
+            *current = local_zone;
+        }
+    }
+
+    /// Returns the local zone, if one was set.
+    fn local_zone(&self) -> Option<String> {
+        self.local_zone.read().ok().and_then(|zone| zone.clone())
+    }
+
+    /// Adds a backend to the director's pool.
+    ///
+    /// A backend for a server already in the pool, with the same name and
+    /// address, takes the place of the one there, keeping its probes and
+    /// state: that is how a shared director moves over to the backends of
+    /// a newly loaded VCL.
+    ///
+    /// # Arguments
+    /// * `backend` - The backend to add; its weight must be positive and at
+    ///   most `MAX_WEIGHT`
+    ///
+    /// # Returns
+    /// * `Ok(())` if the backend was added successfully
+    /// * `Err(DirectorError)` if the backend could not be added
+    pub fn add_backend(&self, backend: Backend) -> Result<(), DirectorError> {
+        if !(backend.weight > 0.0 && backend.weight <= MAX_WEIGHT) {
+            return Err(DirectorError::InvalidConfig(format!(
+                "weight of backend {} must be greater than zero and at most {}",
+                backend.name, MAX_WEIGHT
+            )));
+        }
+
+        let mut backends = self
+            .backends
+            .write()
+            .map_err(|e| DirectorError::BackendLockError(e.to_string()))?;
+
+        if let Some(bound) = backends.iter_mut().find(|b| b.is_same_server(&backend)) {
+            let old = std::mem::replace(bound, backend.clone());
+            self.rebind_backend(&old, &backend);
+            self.rebuild_hash_ring(&backends);
+            return Ok(());
+        }
+        self.slow_start.start(&backend, Instant::now());
+        if let Ok(mut stats) = self.backend_stats.write() {
+            stats.insert(stats_key(&backend), Arc::default());
+        }
+        backends.push(backend);
+        self.rebuild_hash_ring(&backends);
+        Ok(())
+    }
+
+    /// Moves the state kept about `old` over to `new`, the same server.
+    fn rebind_backend(&self, old: &Backend, new: &Backend) {
+        self.probe_table.rebind_backend(old, new);
+        self.slow_start.rebind_backend(old, new);
+        self.outliers.rebind_backend(old, new);
+        self.breakers.rebind_backend(old, new);
+        if let Ok(mut stats) = self.backend_stats.write() {
+            if let Some(backend_stats) = stats.remove(&stats_key(old)) {
+                stats.insert(stats_key(new), backend_stats);
+            }
+        }
+        self.update_drains(|drains| {
+            for drain in drains.iter_mut().filter(|d| d.backend == *old) {
+                drain.backend = new.clone();
+            }
+        });
+    }
+
+    /// Removes a backend from the pool by its VCL_BACKEND reference.
+    /// Also removes any probe results for this backend.
+    ///
+    /// # Arguments
+    /// * `vcl_backend` - The VCL_BACKEND reference to remove
+    pub fn remove_backend(&self, vcl_backend: VCL_BACKEND) {
+        if let Ok(mut backends) = self.backends.write() {
+            if let Some(backend) = backends.iter().find(|b| **b == vcl_backend).cloned() {
+                self.slow_start.remove_backend(&backend);
+                self.outliers.remove_backend(&backend);
+                self.breakers.remove_backend(&backend);
+                if let Ok(mut stats) = self.backend_stats.write() {
+                    stats.remove(&stats_key(&backend));
+                }
+                self.probe_table.remove_backend(backend);
+                backends.retain(|b| *b != vcl_backend);
+                self.rebuild_hash_ring(&backends);
+            }
+        }
+        self.update_drains(|drains| drains.retain(|d| d.backend != vcl_backend));
+    }
+
+    /// Stops selecting a backend for new requests, while still probing it,
+    /// until `duration` has passed. Requests already sent to it are left to
+    /// complete. Draining a backend again restarts its drain.
+    ///
+    /// # Arguments
+    /// * `vcl_backend` - The backend to drain
+    /// * `duration` - How long the drain lasts
+    /// * `remove` - Whether to remove the backend once drained, rather than
+    ///   selecting it again
+    ///
+    /// # Returns
+    /// * `Err(DirectorError::UnknownBackend)` if the backend isn't in the pool
+    pub fn drain_backend(
+        &self,
+        vcl_backend: VCL_BACKEND,
+        duration: Duration,
+        remove: bool,
+    ) -> Result<(), DirectorError> {
+        let backend = self.find_backend(vcl_backend)?;
+        self.update_drains(|drains| {
+            drains.retain(|d| d.backend != backend);
+            drains.push(Drain {
+                backend,
+                until: Instant::now() + duration,
+                remove,
+            });
+        });
+        Ok(())
+    }
+
+    /// Ends the drains whose period is over, removing their backend if asked.
+    fn finish_drains(&self) {
+        let now = Instant::now();
+        let over = |d: &Drain| d.until <= now;
+        let has_over = self
+            .drains
+            .read()
+            .is_ok_and(|drains| drains.iter().any(over));
+        if !has_over {
+            return;
+        }
+
+        let mut finished = Vec::new();
+        self.update_drains(|drains| {
+            finished = drains.iter().filter(|d| over(d)).cloned().collect();
+            drains.retain(|d| !over(d));
+        });
+        for drain in finished.into_iter().filter(|d| d.remove) {
+            self.remove_backend(drain.backend.vcl_backend);
+        }
+    }
+
+    /// Returns the ejected backends whose ejection is over to the pool.
+    fn return_ejected(&self) {
+        let returned = self.outliers.return_expired(Instant::now());
+        if returned > 0 {
+            self.stats
+                .unejections
+                .fetch_add(returned as u64, Ordering::Relaxed);
+            self.stats
+                .backends_ejected
+                .store(self.outliers.ejected().len() as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Applies `update` to the drains, keeping the `backends_draining` gauge current.
+    fn update_drains(&self, update: impl FnOnce(&mut Vec<Drain>)) {
+        if let Ok(mut drains) = self.drains.write() {
+            update(&mut drains);
+            self.stats
+                .backends_draining
+                .store(drains.len() as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the backends currently being drained.
+    fn draining(&self) -> Vec<VCL_BACKEND> {
+        self.drains
+            .read()
+            .map(|drains| drains.iter().map(|d| d.backend.vcl_backend).collect())
+            .unwrap_or_default()
+    }
+
+    fn rebuild_hash_ring(&self, backends: &[Backend]) {
+        if let Ok(mut ring) = self.hash_ring.write() {
+            *ring = HashRing::new(backends);
+        }
+    }
+
+    /// Records load signals a backend attached to a real response, as a
+    /// fresh probe result.
+    ///
+    /// # Arguments
+    /// * `vcl_backend` - The backend that sent the response
+    /// * `in_flight` - Its requests in flight
+    /// * `est_latency` - Its estimated latency, if it sent one; its observed
+    ///   latency stands in otherwise
+    ///
+    /// # Returns
+    /// * `Err(DirectorError::UnknownBackend)` if the backend isn't in the pool
+    /// * `Err(DirectorError::MissingLatency)` if it has no latency at all
+    pub fn report(
+        &self,
+        vcl_backend: VCL_BACKEND,
+        in_flight: usize,
+        est_latency: Option<usize>,
+    ) -> Result<(), DirectorError> {
+        let backend = self.find_backend(vcl_backend)?;
+        let est_latency = est_latency
+            .or_else(|| self.probe_table.observed_latency(&backend))
+            .ok_or(DirectorError::MissingLatency)?;
+
+        self.add_probe_result(backend, in_flight, est_latency);
+        self.stats.reports.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Records the measured latency of a real fetch, e.g. its time to first
+    /// byte. The director keeps, per backend, the bias between estimated and
+    /// observed latencies to correct future estimates, and uses the observed
+    /// latency for backends that don't estimate theirs.
+    ///
+    /// # Arguments
+    /// * `vcl_backend` - The backend that served the fetch
+    /// * `latency` - How long the fetch took
+    ///
+    /// # Returns
+    /// * `Err(DirectorError::UnknownBackend)` if the backend isn't in the pool
+    pub fn observe(
+        &self,
+        vcl_backend: VCL_BACKEND,
+        latency: Duration,
+    ) -> Result<(), DirectorError> {
+        let backend = self.find_backend(vcl_backend)?;
+        self.probe_table.observe(&backend, latency);
+        self.stats.observations.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Records the status of a real response, for the backend's circuit
+    /// breaker. 5xx statuses count as errors.
+    ///
+    /// # Arguments
+    /// * `vcl_backend` - The backend that sent the response
+    /// * `status` - The response status, e.g. 503 for a fetch that failed
+    ///
+    /// # Returns
+    /// * `Err(DirectorError::UnknownBackend)` if the backend isn't in the pool
+    pub fn record_result(
+        &self,
+        vcl_backend: VCL_BACKEND,
+        status: u16,
+    ) -> Result<(), DirectorError> {
+        let backend = self.find_backend(vcl_backend)?;
+        let error = status >= 500;
+        self.stats.results.fetch_add(1, Ordering::Relaxed);
+        if error {
+            self.stats.result_errors.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let counter = match self.breakers.record(&backend, error, Instant::now()) {
+            Transition::Opened => &self.stats.breaker_opened,
+            Transition::Closed => &self.stats.breaker_closed,
+            Transition::None => return Ok(()),
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        self.stats
+            .breakers_open
+            .store(self.breakers.open_count() as u64, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Returns the backend of the pool for the same server as `backend`,
+    /// which may belong to another VCL.
+    pub fn find_server(&self, backend: &Backend) -> Option<Backend> {
+        self.backends
+            .read()
+            .ok()?
+            .iter()
+            .find(|b| b.is_same_server(backend))
+            .cloned()
+    }
+
+    /// Updates the statistics of `backend`, if it is still in the pool.
+    fn with_backend_stats(&self, backend: &Backend, update: impl FnOnce(&BackendStats)) {
+        if let Some(stats) = self
+            .backend_stats
+            .read()
+            .ok()
+            .and_then(|stats| stats.get(&stats_key(backend)).cloned())
+        {
+            update(&stats);
+        }
+    }
+
+    /// Returns the statistics of every backend in the pool.
+    pub fn backend_stats(&self) -> Vec<(Backend, Arc<BackendStats>)> {
+        let Ok(backends) = self.backends.read() else {
+            return Vec::new();
+        };
+        let Ok(stats) = self.backend_stats.read() else {
+            return Vec::new();
+        };
+        backends
+            .iter()
+            .filter_map(|b| Some((b.clone(), stats.get(&stats_key(b))?.clone())))
+            .collect()
+    }
+
+    /// Counts a selection of `backend` in its zone, if it has one.
+    fn count_zone_selection(&self, backend: &Backend) {
+        let Some(zone) = &backend.zone else {
+            return;
+        };
+        let stats = self
+            .zone_stats
+            .read()
+            .ok()
+            .and_then(|stats| stats.get(zone).cloned());
+        let stats = match stats {
+            Some(stats) => stats,
+            None => match self.zone_stats.write() {
+                Ok(mut stats) => stats.entry(zone.clone()).or_default().clone(),
+                Err(_) => return,
+            },
+        };
+        stats.selected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the statistics of every zone a backend was selected in.
+    pub fn zone_stats(&self) -> Vec<(String, Arc<ZoneStats>)> {
+        let Ok(stats) = self.zone_stats.read() else {
+            return Vec::new();
+        };
+        stats
+            .iter()
+            .map(|(zone, stats)| (zone.clone(), stats.clone()))
+            .collect()
+    }
+
+    fn find_backend(&self, vcl_backend: VCL_BACKEND) -> Result<Backend, DirectorError> {
+        self.backends
+            .read()
+            .map_err(|e| DirectorError::BackendLockError(e.to_string()))?
+            .iter()
+            .find(|b| **b == vcl_backend)
+            .cloned()
+            .ok_or(DirectorError::UnknownBackend)
+    }
+
+    /// Records the thread running the probe loop, for `stop` to join it.
+    pub fn set_probe_thread(&self, handle: JoinHandle<()>) {
+        if let Ok(mut thread) = self.probe_thread.lock() {
+            *thread = Some(handle);
+        }
+    }
+
+    /// Pauses probing, e.g. while the VCL is cold. Probe results age out
+    /// meanwhile, so selection falls back once they are gone.
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::Relaxed);
+    }
+
+    /// Resumes probing after `pause`, with an immediate round of probes to
+    /// refill the table.
+    pub fn resume(&self) {
+        if self.paused.swap(false, Ordering::Relaxed) {
+            self.trigger_probe();
+        }
+    }
+
+    /// Tells whether probing is paused.
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// Stops the probe loop for good and waits for its thread to finish.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        self.probe_wakeup.notify_one();
+        let handle = self.probe_thread.lock().ok().and_then(|mut t| t.take());
+        if let Some(handle) = handle {
+            // The probe loop may hold the last reference, and drop us itself
+            if handle.thread().id() != thread::current().id() {
+                let _ = handle.join();
+            }
+        }
+    }
+
+    /// Asks the probe loop to fill the probe table right away.
+    pub fn trigger_probe(&self) {
+        self.seed_requested.store(true, Ordering::Relaxed);
+        self.probe_wakeup.notify_one();
+    }
+
+    /// Returns a string representation of the probe table, for debugging.
+    ///
+    /// # Returns
     /// * `Some(String)` - The probe table as a string
     /// * `None` - If the probe table could not be locked
     pub fn debug_probe_table(&self) -> Option<String> {
-        self.probe_table.display_results()
+        let mut output = self.probe_table.display_results()?;
+        let now = Instant::now();
+        for drain in self.drains.read().ok()?.iter() {
+            output.push_str(&format!(
+                "drain: backend={} ({}), remaining={:.1}s, then={}\n",
+                drain.backend.name,
+                drain.backend.address,
+                drain.until.saturating_duration_since(now).as_secs_f64(),
+                if drain.remove { "remove" } else { "resume" },
+            ));
+        }
+        for breaker in self.breakers.describe(now) {
+            output.push_str(&format!("breaker: {}\n", breaker));
+        }
+        for (vcl_backend, until) in self.outliers.ejected() {
+            let Ok(backend) = self.find_backend(vcl_backend) else {
+                continue;
+            };
+            output.push_str(&format!(
+                "ejected: backend={} ({}), remaining={:.1}s\n",
+                backend.name,
+                backend.address,
+                until.saturating_duration_since(now).as_secs_f64(),
+            ));
+        }
+        for backend in self.backends.read().ok()?.iter() {
+            if let Some(progress) = self.slow_start.progress(backend, now) {
+                output.push_str(&format!(
+                    "slow_start: backend={} ({}), progress={:.0}%, share={:.2}, curve={}\n",
+                    backend.name,
+                    backend.address,
+                    progress * 100.0,
+                    self.slow_start.curve().fraction(progress),
+                    self.slow_start.curve(),
+                ));
+            }
+        }
+        Some(output)
+    }
+
+    /// Renders the director state for `varnishadm backend.list`.
+    ///
+    /// Follows the layout of Varnish's built-in directors: a one-line
+    /// `probed/total` health summary by default, and one row per probe
+    /// table entry when `detailed` (`-p`) is set.
+    ///
+    /// # Arguments
+    /// * `detailed` - Whether to list every probe table entry
+    /// * `json` - Whether to output JSON instead of text
+    pub fn list_backends(&self, detailed: bool, json: bool) -> String {
+        let probes = self.probe_table.entries();
+        let total = self.backends.read().map(|b| b.len()).unwrap_or(0);
+        // The table holds at most one entry per backend
+        let probed = probes.len();
+        let health = if probes.is_empty() { "sick" } else { "healthy" };
+        let now = SystemTime::now();
+        let age = |p: &ProbeResult| now.duration_since(p.timestamp).unwrap_or_default();
+
+        match (detailed, json) {
+            (false, false) => format!("{}/{}\t{}", probed, total, health),
+            (false, true) => format!("[{}, {}, \"{}\"]", probed, total, health),
+            (true, false) => {
+                let mut output =
+                    String::from("\n\n\tBackend\tWeight\tIn-flight\tLatency\tUsed\tAge\n");
+                for probe in &probes {
+                    output.push_str(&format!(
+                        "\t{}\t{}\t{}\t{}\t{}\t{:.3}\n",
+                        probe.backend.name,
+                        probe.backend.weight,
+                        probe.rif,
+                        probe.est_latency,
+                        probe.used_count.load(Ordering::Relaxed),
+                        age(probe).as_secs_f64()
+                    ));
+                }
+                output
+            }
+            (true, true) => {
+                let entries: Vec<_> = probes
+                    .iter()
+                    .map(|probe| {
+                        serde_json::json!({
+                            "backend": probe.backend.name,
+                            "address": probe.backend.address.to_string(),
+                            "weight": probe.backend.weight,
+                            "in_flight": probe.rif,
+                            "latency": probe.est_latency,
+                            "used": probe.used_count.load(Ordering::Relaxed),
+                            "age": age(probe).as_secs_f64(),
+                        })
+                    })
+                    .collect();
+                // Like the built-in directors, this is a fragment of the
+                // backend.list JSON output, hence the trailing comma
+                format!(
+                    "{},\n",
+                    serde_json::json!({
+                        "backends": total,
+                        "healthy": !probes.is_empty(),
+                        "probes": entries,
+                    })
+                )
+            }
+        }
+    }
+
+    /// Returns a JSON snapshot of the director: its configuration, its
+    /// backends with their state, the probe table entries and the stats.
+    ///
+    /// A backend's state is the first that applies of `draining`,
+    /// `ejected`, `breaker_open`, `breaker_half_open`, `slow_start` and
+    /// `active`.
+    pub fn dump_json(&self) -> String {
+        let config = &self.config;
+        let now = Instant::now();
+        let draining = self.draining();
+        let ejected: Vec<_> = self
+            .outliers
+            .ejected()
+            .into_iter()
+            .map(|(b, _)| b)
+            .collect();
+        let backends: Vec<_> = self
+            .backends
+            .read()
+            .map(|backends| backends.clone())
+            .unwrap_or_default()
+            .iter()
+            .map(|backend| {
+                let slow_start = self.slow_start.progress(backend, now);
+                let state = if draining.contains(&backend.vcl_backend) {
+                    "draining"
+                } else if ejected.contains(&backend.vcl_backend) {
+                    "ejected"
+                } else {
+                    match self.breakers.state(backend) {
+                        "open" => "breaker_open",
+                        "half_open" => "breaker_half_open",
+                        _ if slow_start.is_some() => "slow_start",
+                        _ => "active",
+                    }
+                };
+                serde_json::json!({
+                    "name": backend.name,
+                    "address": backend.address.to_string(),
+                    "weight": backend.weight,
+                    "zone": backend.zone,
+                    "state": state,
+                    "slow_start_progress": slow_start,
+                })
+            })
+            .collect();
+        let system_now = SystemTime::now();
+        let table: Vec<_> = self
+            .probe_table
+            .entries()
+            .iter()
+            .map(|probe| {
+                serde_json::json!({
+                    "backend": probe.backend.name,
+                    "rif": probe.rif,
+                    "latency": probe.est_latency,
+                    "used_count": probe.used_count.load(Ordering::Relaxed),
+                    "age": system_now
+                        .duration_since(probe.timestamp)
+                        .unwrap_or_default()
+                        .as_secs_f64(),
+                })
+            })
+            .collect();
+        let zones: serde_json::Map<_, _> = self
+            .zone_stats()
+            .into_iter()
+            .map(|(zone, stats)| {
+                let selected = stats.selected.load(Ordering::Relaxed);
+                (zone, serde_json::json!({ "selected": selected }))
+            })
+            .collect();
+
+        serde_json::json!({
+            "config": {
+                "probe_interval": config.probe_interval.as_secs_f64(),
+                "probe_ratio": config.probe_ratio,
+                "max_probe_rate": config.max_probe_rate,
+                "idle_probe_rate": config.idle_probe_rate,
+                "probe_timeout": config.probe_timeout.as_secs_f64(),
+                "max_probes_in_flight": config.max_probes_in_flight,
+                "probe_table_size": config.probe_table.size,
+                "max_probe_age": config.probe_table.max_probe_age.as_secs_f64(),
+                "max_uses": config.probe_table.max_uses,
+                "hot_threshold": config.probe_table.hot_threshold,
+                "fallback": config.fallback.to_string(),
+                "hash_balance_factor": config.hash_balance_factor,
+                "hash_max_latency": config.hash_max_latency,
+                "load_signal": config.load_signal.to_string(),
+                "slow_start": config.slow_start.as_secs_f64(),
+                "slow_start_curve": config.slow_start_curve.to_string(),
+                "eject_consecutive_failures": config.ejection.consecutive_failures,
+                "eject_failure_rate": config.ejection.failure_rate,
+                "ejection_time": config.ejection.ejection_time.as_secs_f64(),
+                "max_ejected_percent": config.ejection.max_ejected_percent,
+                "breaker_error_rate": config.breaker.error_rate,
+                "breaker_window": config.breaker.window.as_secs_f64(),
+                "breaker_min_requests": config.breaker.min_requests,
+                "breaker_open_time": config.breaker.open_time.as_secs_f64(),
+                "breaker_half_open_requests": config.breaker.half_open_requests,
+                "local_zone": self.local_zone.read().ok().and_then(|z| z.clone()),
+            },
+            "backends": backends,
+            "table": table,
+            "zones": zones,
+            "stats": self.stats.to_json(),
+        })
+        .to_string()
+    }
+
+    /// Gets the best available backend based on probe results.
+    /// Falls back to the configured `FallbackMode` if no probe results are available.
+    ///
+    /// # Arguments
+    /// * `is_healthy` - Varnish's view of a backend's health (VCL probe and
+    ///   admin state); backends for which it returns `false` are skipped
+    /// * `tried` - Backends already used by this request (e.g. before a
+    ///   `return(retry)`); they are skipped unless no other backend is usable
+    /// * `key` - Sticks the request to the backend owning this key on the
+    ///   hash ring, unless that backend is overloaded
+    ///
+    /// # Returns
+    /// * `Ok((Backend, Selection))` - The selected backend and where it came from
+    /// * `Err(DirectorError)` - If no healthy backends are available
+    pub fn get_backend(
+        &self,
+        is_healthy: impl Fn(&Backend) -> bool,
+        tried: &[VCL_BACKEND],
+        key: Option<&str>,
+    ) -> Result<(Backend, Selection), DirectorError> {
+        self.select_backend(is_healthy, tried, key)
+            .map(|decision| (decision.backend, decision.selection))
+    }
+
+    /// Like `get_backend`, also telling what the choice was based on.
+    pub fn select_backend(
+        &self,
+        is_healthy: impl Fn(&Backend) -> bool,
+        tried: &[VCL_BACKEND],
+        key: Option<&str>,
+    ) -> Result<Decision, DirectorError> {
+        // Before taking the backends lock, as a finished drain may remove one
+        self.finish_drains();
+        self.return_ejected();
+        let draining = self.draining();
+        let ejected: Vec<VCL_BACKEND> = self.outliers.ejected().iter().map(|e| e.0).collect();
+        let open_circuits = self.breakers.unavailable(Instant::now());
+
+        let backends = self
+            .backends
+            .read()
+            .map_err(|e| DirectorError::BackendLockError(e.to_string()))?;
+
+        if backends.is_empty() {
+            return Err(DirectorError::BackendLockError(
+                "No backends available".to_string(),
+            ));
+        }
+
+        // Let the probe loop schedule probes for this request
+        self.pending_requests.fetch_add(1, Ordering::Relaxed);
+        self.probe_wakeup.notify_one();
+
+        // Slow start follows the health of every backend, once per request,
+        // whatever else keeps a backend from being selected. Failed probes
+        // only count once they got the backend ejected, so a dropped probe
+        // doesn't restart a ramp
+        let now = Instant::now();
+        let health: Vec<bool> = backends
+            .iter()
+            .map(|backend| {
+                let healthy = is_healthy(backend);
+                let up = healthy && !ejected.contains(&backend.vcl_backend);
+                self.slow_start.update(backend, up, now);
+                healthy
+            })
+            .collect();
+
+        // Which backends can take the request is settled once, selection
+        // then asking about each backend many times
+        let eligible: Vec<VCL_BACKEND> = backends
+            .iter()
+            .zip(health)
+            .filter(|(backend, healthy)| {
+                if draining.contains(&backend.vcl_backend) {
+                    self.stats.skipped_draining.fetch_add(1, Ordering::Relaxed);
+                    return false;
+                }
+                if ejected.contains(&backend.vcl_backend)
+                    || open_circuits.contains(&backend.vcl_backend)
+                {
+                    return false;
+                }
+                if !healthy {
+                    self.stats.skipped_unhealthy.fetch_add(1, Ordering::Relaxed);
+                }
+                *healthy
+            })
+            .map(|(backend, _)| backend.vcl_backend)
+            .collect();
+        let healthy = |backend: &Backend| eligible.contains(&backend.vcl_backend);
+        let fresh: Vec<VCL_BACKEND> = eligible
+            .iter()
+            .filter(|vcl_backend| !tried.contains(vcl_backend))
+            .copied()
+            .collect();
+        self.stats
+            .skipped_tried
+            .fetch_add((eligible.len() - fresh.len()) as u64, Ordering::Relaxed);
+        let untried = |backend: &Backend| fresh.contains(&backend.vcl_backend);
+
+        // Backends in their slow start sit this request out with the
+        // probability of their ramp not being complete
+        let mut rng = rand::thread_rng();
+        let held_back: Vec<VCL_BACKEND> = backends
+            .iter()
+            .filter(|b| untried(b))
+            .filter(|b| rng.gen::<f64>() >= self.slow_start.fraction(b, now))
+            .map(|b| b.vcl_backend)
+            .collect();
+        let ramped =
+            |backend: &Backend| untried(backend) && !held_back.contains(&backend.vcl_backend);
+
+        let local_zone = self.local_zone();
+        let local_zone = local_zone.as_deref();
+        let selected = match self.select(&backends, untried, key, local_zone) {
+            // Another backend takes the request of the one held back, unless
+            // there is none: a backend still warming up beats a tried one
+            Some(selected) if held_back.contains(&selected.backend.vcl_backend) => {
+                self.stats
+                    .skipped_slow_start
+                    .fetch_add(1, Ordering::Relaxed);
+                self.select(&backends, ramped, key, local_zone)
+                    .unwrap_or(selected)
+            }
+            Some(selected) => selected,
+            // Every healthy backend was already tried, going back to one beats failing
+            None if !tried.is_empty() => self
+                .select(&backends, healthy, key, local_zone)
+                .ok_or(DirectorError::NoHealthyBackend)?,
+            None => return Err(DirectorError::NoHealthyBackend),
+        };
+
+        if let Some(zone) = local_zone {
+            if selected.backend.in_zone(zone) {
+                self.stats.zone_local.fetch_add(1, Ordering::Relaxed);
+            } else {
+                self.stats.zone_spillover.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count_zone_selection(&selected.backend);
+        if let Some(pick) = &selected.pick {
+            self.stats.observe_time_to_use(pick.age);
+        }
+        self.breakers.admit(&selected.backend);
+        self.with_backend_stats(&selected.backend, |stats| {
+            stats.selected.fetch_add(1, Ordering::Relaxed);
+        });
+        Ok(selected)
+    }
+
+    /// Picks the best usable backend from the probe table, or falls back to
+    /// the configured `FallbackMode` if the table has none.
+    ///
+    /// With a `local_zone`, a cold probe in that zone wins over any other
+    /// probe, and the fallback only considers local backends if one is usable.
+    fn select(
+        &self,
+        backends: &[Backend],
+        usable: impl Fn(&Backend) -> bool,
+        key: Option<&str>,
+        local_zone: Option<&str>,
+    ) -> Option<Decision> {
+        if let Some(selected) = key.and_then(|key| self.select_by_key(backends, &usable, key)) {
+            return Some(selected);
+        }
+        if self.config.load_signal == LoadSignal::Local {
+            return self
+                .select_by_local_load(backends, usable, local_zone)
+                .map(|(backend, selection)| Decision::new(backend, selection));
+        }
+        if let Some(zone) = local_zone {
+            let local = |b: &Backend| b.in_zone(zone) && usable(b);
+            if let Some(pick) = self.probe_table.pick_cold(local) {
+                return Some(Decision::picked(pick, Selection::ProbeTable));
+            }
+        }
+        if let Some(pick) = self.probe_table.pick_best(&usable) {
+            return Some(Decision::picked(pick, Selection::ProbeTable));
+        }
+
+        let mut candidates: Vec<&Backend> = backends.iter().filter(|b| usable(b)).collect();
+        if candidates.is_empty() {
+            return None;
+        }
+        if let Some(zone) = local_zone {
+            if candidates.iter().any(|b| b.in_zone(zone)) {
+                candidates.retain(|b| b.in_zone(zone));
+            }
+        }
+        let mut rng = rand::thread_rng();
+        // Fewer connections per unit of weight is better
+        let load = |b: &&Backend| b.local_connections() as f64 / b.weight;
+
+        let mode = self.config.fallback;
+        let chosen = match mode {
+            FallbackMode::Random => None,
+            FallbackMode::RoundRobin => {
+                let next = self.round_robin.fetch_add(1, Ordering::Relaxed);
+                Some(candidates[next % candidates.len()].clone())
+            }
+            FallbackMode::LeastLocalConnections => {
+                // Shuffle first so ties don't always go to the same backend
+                candidates.shuffle(&mut rng);
+                candidates
+                    .iter()
+                    .min_by(|a, b| load(a).total_cmp(&load(b)))
+                    .map(|b| (*b).clone())
+            }
+            FallbackMode::PowerOfTwo => candidates
+                .choose_multiple_weighted(&mut rng, 2, |b| b.weight)
+                .ok()
+                .and_then(|pair| pair.min_by(|a, b| load(a).total_cmp(&load(b))))
+                .map(|b| (*b).clone()),
+            FallbackMode::LastKnown => self
+                .probe_table
+                .find_last_known(|b| candidates.contains(&b)),
+        };
+        if let Some(backend) = chosen {
+            return Some(Decision::new(backend, Selection::Fallback(mode)));
+        }
+
+        // Random, or the chosen mode had nothing to go on
+        candidates
+            .choose_weighted(&mut rng, |b| b.weight)
+            .ok()
+            .map(|backend| {
+                Decision::new(
+                    (*backend).clone(),
+                    Selection::Fallback(FallbackMode::Random),
+                )
+            })
+    }
+
+    /// Picks a backend the way `select` does with probes, taking this
+    /// Varnish's connections to each backend as its RIF: the cold backend
+    /// with the lowest observed latency, preferably in `local_zone`, or the
+    /// least loaded one when all are hot. Ties are broken at random, by weight.
+    fn select_by_local_load(
+        &self,
+        backends: &[Backend],
+        usable: impl Fn(&Backend) -> bool,
+        local_zone: Option<&str>,
+    ) -> Option<(Backend, Selection)> {
+        let load = |b: &Backend| b.local_load() as f64 / b.weight;
+        let max_load = backends.iter().map(load).fold(0.0, f64::max);
+        let threshold = self.config.probe_table.rif_threshold(max_load);
+
+        let candidates: Vec<&Backend> = backends.iter().filter(|b| usable(b)).collect();
+        let mut rng = rand::thread_rng();
+        let shuffled: Vec<&Backend> = candidates
+            .choose_multiple_weighted(&mut rng, candidates.len(), |b| b.weight)
+            .ok()?
+            .copied()
+            .collect();
+
+        let latency = |b: &Backend| self.probe_table.observed_latency(b).unwrap_or(0);
+        let coldest = |in_zone: &dyn Fn(&Backend) -> bool| {
+            shuffled
+                .iter()
+                .filter(|b| load(b) <= threshold && in_zone(b))
+                .min_by(|a, b| {
+                    latency(a)
+                        .cmp(&latency(b))
+                        .then(load(a).total_cmp(&load(b)))
+                })
+                .copied()
+        };
+
+        let best = local_zone
+            .and_then(|zone| coldest(&|b| b.in_zone(zone)))
+            .or_else(|| coldest(&|_| true))
+            .or_else(|| {
+                shuffled
+                    .iter()
+                    .min_by(|a, b| load(a).total_cmp(&load(b)))
+                    .copied()
+            })?;
+        Some((best.clone(), Selection::LocalLoad))
+    }
+
+    /// Picks the backend for `key` by consistent hashing with bounded loads:
+    /// the first usable backend along the ring whose local connections are
+    /// within `hash_balance_factor` of its weighted share. If its probe shows
+    /// it hot or above `hash_max_latency`, the request goes to the best other
+    /// probe, unless that one is just as bad; without a probe, affinity is kept.
+    fn select_by_key(
+        &self,
+        backends: &[Backend],
+        usable: impl Fn(&Backend) -> bool,
+        key: &str,
+    ) -> Option<Decision> {
+        let ring = self.hash_ring.read().ok()?;
+
+        let total_weight: f64 = backends.iter().map(|b| b.weight).sum();
+        // Counting the request being placed, as in "Consistent Hashing with Bounded Loads"
+        let total_load = backends
+            .iter()
+            .map(Backend::local_connections)
+            .sum::<usize>()
+            + 1;
+        let within_bound = |b: &Backend| {
+            let capacity = (self.config.hash_balance_factor * total_load as f64 * b.weight
+                / total_weight)
+                .ceil();
+            (b.local_connections() as f64) < capacity
+        };
+
+        let mut owner = None;
+        let mut selected = None;
+        for backend in ring.lookup(key).filter_map(|idx| backends.get(idx)) {
+            if !usable(backend) {
+                continue;
+            }
+            if within_bound(backend) {
+                let selection = if owner.is_some() {
+                    Selection::HashBounded
+                } else {
+                    Selection::Hash
+                };
+                selected = Some((backend, selection));
+                break;
+            }
+            owner.get_or_insert(backend);
+        }
+        // Everyone is over the bound, which weights can cause: stick to the owner
+        let (backend, selection) = selected.or(owner.map(|b| (b, Selection::Hash)))?;
+
+        let max_latency = self.config.hash_max_latency;
+        if self.probe_table.is_overloaded(backend, max_latency) {
+            // Only worth leaving the key's backend for one that isn't as loaded
+            let spill = self
+                .probe_table
+                .pick_best(|b| b != backend && usable(b))
+                .filter(|best| !self.probe_table.is_overloaded(&best.backend, max_latency));
+            if let Some(best) = spill {
+                return Some(Decision::picked(best, Selection::HashSpilled));
+            }
+        }
+        Some(Decision::new(backend.clone(), selection))
+    }
+
+    /// Adds a probe result to the table, recording what it says in the
+    /// director's and the backend's statistics.
+    ///
+    /// The result goes to the backend the pool now has for the same server,
+    /// which a shared director may have rebound to another VCL's backend
+    /// while the probe was in flight. It is dropped if the server left the
+    /// pool, so that it can't be selected again.
+    fn add_probe_result(&self, backend: Backend, rif: usize, est_latency: usize) {
+        // Held while adding, so remove_backend and rebinds update the table after us
+        let Ok(backends) = self.backends.read() else {
+            return;
+        };
+        let Some(backend) = backends
+            .iter()
+            .find(|b| b.is_same_server(&backend))
+            .cloned()
+        else {
+            self.stats.probes_dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        };
+        self.stats.observe_probe_result(rif, est_latency);
+        self.with_backend_stats(&backend, |stats| {
+            stats.last_rif.store(rif as u64, Ordering::Relaxed);
+            stats
+                .last_latency
+                .store(est_latency as u64, Ordering::Relaxed);
+        });
+        self.probe_table.add_result(ProbeResult::new(
+            SystemTime::now(),
+            rif,
+            est_latency,
+            backend,
+        ));
     }
 
-    /// Gets the best available backend based on probe results.
-    /// Falls back to random selection if no probe results are available.
+    /// Feeds a probe outcome to outlier detection, dropping the backend's
+    /// probe right away if it gets ejected.
+    fn record_probe_outcome(&self, backend: &Backend, answered: bool) {
+        let pool_size = self.backends.read().map(|b| b.len()).unwrap_or(0);
+        match self
+            .outliers
+            .record(backend, answered, pool_size, Instant::now())
+        {
+            Ejection::Ejected(_) => {
+                self.probe_table.purge_backend(backend);
+                self.stats.ejections.fetch_add(1, Ordering::Relaxed);
+                self.stats
+                    .backends_ejected
+                    .store(self.outliers.ejected().len() as u64, Ordering::Relaxed);
+            }
+            Ejection::Prevented => {
+                self.stats
+                    .ejections_prevented
+                    .fetch_add(1, Ordering::Relaxed);
+            }
+            Ejection::None => {}
+        }
+    }
+
+    /// Returns the current probe request settings.
+    fn probe_spec(&self) -> Arc<ProbeSpec> {
+        self.probe_spec
+            .read()
+            .map(|spec| spec.clone())
+            .unwrap_or_default()
+    }
+
+    /// Starts the probes allowed by the scheduler, counting the dropped ones.
+    fn send_probes(self: &Arc<Self>, budget: ProbeBudget) {
+        self.stats
+            .probes_rate_limited
+            .fetch_add(budget.dropped as u64, Ordering::Relaxed);
+        if budget.send > 0 {
+            self.probe_backends(budget.send);
+        }
+    }
+
+    /// Randomly selects a subset of backends, weighted by capacity, and
+    /// starts probing them.
+    ///
+    /// Probes run concurrently on the probe loop's runtime, so this must be
+    /// called from within it. Results are added to the probe table as they
+    /// arrive. Backends are skipped when `max_probes_in_flight` probes are
+    /// already outstanding.
+    fn probe_backends(self: &Arc<Self>, count: usize) {
+        let backends_to_probe = if let Ok(backends) = self.backends.read() {
+            // Bigger backends are probed more often, in proportion to their weight
+            let mut rng = rand::thread_rng();
+            match backends.choose_multiple_weighted(&mut rng, count, |b| b.weight) {
+                Ok(chosen) => chosen.cloned().collect::<Vec<_>>(),
+                Err(_) => return,
+            }
+        } else {
+            return;
+        };
+
+        let probe_spec = self.probe_spec();
+        for backend in backends_to_probe {
+            let Ok(permit) = self.probe_slots.clone().try_acquire_owned() else {
+                self.stats.probes_skipped.fetch_add(1, Ordering::Relaxed);
+                continue;
+            };
+            tokio::spawn(
+                self.clone()
+                    .probe_backend(backend, probe_spec.clone(), permit),
+            );
+        }
+    }
+
+    /// Sends one probe and records its result in the probe table.
+    async fn probe_backend(
+        self: Arc<Self>,
+        backend: Backend,
+        probe_spec: Arc<ProbeSpec>,
+        _permit: OwnedSemaphorePermit,
+    ) {
+        self.stats.probes_sent.fetch_add(1, Ordering::Relaxed);
+        self.stats.probes_in_flight.fetch_add(1, Ordering::Relaxed);
+        self.with_backend_stats(&backend, |stats| {
+            stats.probes_sent.fetch_add(1, Ordering::Relaxed);
+        });
+
+        let sent_at = Instant::now();
+        let result = prober::probe(
+            backend.address,
+            &backend.name,
+            &probe_spec,
+            self.config.probe_timeout,
+        )
+        .await;
+        let rtt = sent_at.elapsed();
+
+        self.stats.probes_in_flight.fetch_sub(1, Ordering::Relaxed);
+
+        // The server may have moved to another VCL's backend meanwhile, or left
+        let Some(backend) = self.find_server(&backend) else {
+            self.stats.probes_dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        };
+
+        // Any answer shows the backend up, whatever its content
+        let answered = matches!(
+            result,
+            Ok(_)
+                | Err(ProbeError::MissingHeaders
+                    | ProbeError::InvalidBody(_)
+                    | ProbeError::MissingFields)
+        );
+        if answered {
+            self.stats.observe_probe_rtt(rtt);
+        }
+        self.record_probe_outcome(&backend, answered);
+
+        match result {
+            Ok(report) => {
+                // Backends that don't estimate their latency are judged on
+                // what fetches from them actually took
+                let est_latency = report
+                    .est_latency
+                    .or_else(|| self.probe_table.observed_latency(&backend));
+                match est_latency {
+                    Some(est_latency) => {
+                        self.stats.probes_success.fetch_add(1, Ordering::Relaxed);
+                        self.with_backend_stats(&backend, |stats| {
+                            stats.probes_success.fetch_add(1, Ordering::Relaxed);
+                        });
+                        self.add_probe_result(backend, report.in_flight, est_latency);
+                    }
+                    None => {
+                        self.stats
+                            .probes_missing_headers
+                            .fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            }
+            Err(ProbeError::MissingHeaders) => {
+                self.stats
+                    .probes_missing_headers
+                    .fetch_add(1, Ordering::Relaxed);
+            }
+            Err(ProbeError::InvalidBody(_) | ProbeError::MissingFields) => {
+                self.stats
+                    .probes_invalid_body
+                    .fetch_add(1, Ordering::Relaxed);
+            }
+            Err(ProbeError::Timeout) => {
+                self.stats.probes_timeout.fetch_add(1, Ordering::Relaxed);
+                self.stats.probes_fail.fetch_add(1, Ordering::Relaxed);
+                self.with_backend_stats(&backend, |stats| {
+                    stats.probes_fail.fetch_add(1, Ordering::Relaxed);
+                });
+            }
+            Err(_) => {
+                self.stats.probes_fail.fetch_add(1, Ordering::Relaxed);
+                self.with_backend_stats(&backend, |stats| {
+                    stats.probes_fail.fetch_add(1, Ordering::Relaxed);
+                });
+            }
+        }
+    }
+
+    /// Checks if the director has any valid probe results.
     ///
     /// # Returns
-    /// * `Ok((Backend, bool))` - The selected backend and whether it was from probe table (true) or random fallback (false)
-    /// * `Err(DirectorError)` - If no backends are available
-    pub fn get_backend(&self) -> Result<(Backend, bool), DirectorError> {
-        let backends = self
+    /// `true` if there are valid probe results, `false` otherwise
+    pub fn is_healthy(&self) -> bool {
+        if self.config.load_signal == LoadSignal::Local {
+            // Without probes, there is nothing better to go on
+            return self.backends.read().is_ok_and(|b| !b.is_empty());
+        }
+        // Only healthy if we have valid probe results
+        self.probe_table.has_probes()
+    }
+
+    /// Returns a reference to the stats Arc for syncing to Vsc
+    pub fn stats(&self) -> &Arc<DirectorStats> {
+        &self.stats
+    }
+
+    /// Computes aggregate metrics from the probe table
+    /// Called periodically from the probe loop to update gauges
+    fn compute_metrics(&self) {
+        // Update backend count
+        let backend_count = self.backends.read().map(|b| b.len()).unwrap_or(0);
+        self.stats
             .backends
-            .read()
-            .map_err(|e| DirectorError::BackendLockError(e.to_string()))?;
+            .store(backend_count as u64, Ordering::Relaxed);
 
-        if backends.is_empty() {
-            return Err(DirectorError::BackendLockError(
-                "No backends available".to_string(),
-            ));
+        // Per-backend evictions and probe ages
+        for (backend, evictions) in self.probe_table.take_evicted() {
+            self.with_backend_stats(&backend, |stats| {
+                stats.evictions.fetch_add(evictions, Ordering::Relaxed);
+            });
+        }
+        let now = SystemTime::now();
+        for probe in self.probe_table.last_known() {
+            let age = now.duration_since(probe.timestamp).unwrap_or_default();
+            self.with_backend_stats(&probe.backend, |stats| {
+                stats
+                    .probe_age
+                    .store(age.as_millis() as u64, Ordering::Relaxed);
+            });
         }
 
-        let _ = self.probe_trigger.send(());
+        // Update probe table size
+        let table_size = self.probe_table.len();
+        self.stats
+            .probe_table_size
+            .store(table_size as u64, Ordering::Relaxed);
+
+        // Compute percentiles from probe values
+        if let Some((mut rifs, mut latencies)) = self.probe_table.get_probe_values() {
+            // Sort for percentile computation
+            rifs.sort_unstable();
+            latencies.sort_unstable();
 
-        if let Some(backend) = self.probe_table.find_best() {
-            Ok((backend, true))
-        } else {
-            // Fallback: random selection
-            Ok((
-                backends[rand::random::<usize>() % backends.len()].clone(),
-                false,
+            let len = rifs.len();
+            if len > 0 {
+                // Min/max
+                self.stats
+                    .probe_min_rif
+                    .store(rifs[0] as u64, Ordering::Relaxed);
+                self.stats
+                    .probe_max_rif
+                    .store(rifs[len - 1] as u64, Ordering::Relaxed);
+
+                // p50 (median)
+                let p50_idx = len / 2;
+                self.stats
+                    .probe_p50_rif
+                    .store(rifs[p50_idx] as u64, Ordering::Relaxed);
+                self.stats
+                    .probe_p50_latency
+                    .store(latencies[p50_idx] as u64, Ordering::Relaxed);
+
+                // p80
+                let p80_idx = (len * 80) / 100;
+                let p80_idx = p80_idx.min(len - 1);
+                self.stats
+                    .probe_p80_rif
+                    .store(rifs[p80_idx] as u64, Ordering::Relaxed);
+                self.stats
+                    .probe_p80_latency
+                    .store(latencies[p80_idx] as u64, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Tops the probe table up when it runs low, within the rate cap.
+    fn ensure_probe_pool(self: &Arc<Self>, scheduler: &mut ProbeScheduler, now: Instant) {
+        if !self.probe_table.has_enough_probes() {
+            let wanted = self.config.probe_table.size / 2;
+            self.send_probes(scheduler.on_refill(wanted, now));
+        }
+    }
+}
+
+/// Keys the per-backend statistics, like the other per-backend state, by
+/// the backend's VCL_BACKEND address.
+fn stats_key(backend: &Backend) -> usize {
+    backend.vcl_backend.0 as usize
+}
+
+impl Drop for Director {
+    fn drop(&mut self) {
+        // Let the probe loop notice that we are gone
+        self.probe_wakeup.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::Debug;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{SocketAddr, TcpListener};
+    use std::thread;
+    use std::time::Duration;
+
+    use varnish::ffi::{backend, director, VCL_BACKEND};
+
+    use super::*;
+    use crate::backend::test_backend;
+
+    #[test]
+    fn test_director_add_remove_backend() {
+        let stats = Arc::new(DirectorStats::default());
+        let (director, _) = Director::new(stats, DirectorConfig::default());
+
+        let backend = test_backend("test1", SocketAddr::from(([127, 0, 0, 1], 8080)), 1);
+        let backend1_ref = backend.vcl_backend;
+        let backend2 = test_backend("test2", SocketAddr::from(([127, 0, 0, 2], 8081)), 2);
+
+        // Add backend and verify
+        director.add_backend(backend).unwrap();
+        assert_eq!(director.backends.read().unwrap().len(), 1);
+
+        // Verify the backend name
+        assert_eq!(director.backends.read().unwrap()[0].name, "test1");
+
+        // Add another backend
+        director.add_backend(backend2).unwrap();
+        assert_eq!(director.backends.read().unwrap().len(), 2);
+
+        // Remove the first backend
+        director.remove_backend(backend1_ref);
+        assert_eq!(director.backends.read().unwrap().len(), 1);
+
+        // Verify the remaining backend
+        assert_eq!(director.backends.read().unwrap()[0].name, "test2");
+        assert_eq!(
+            director.backends.read().unwrap()[0].address,
+            SocketAddr::from(([127, 0, 0, 2], 8081))
+        );
+    }
+
+    #[test]
+    fn test_director_config_validate() {
+        assert!(DirectorConfig::default().validate().is_ok());
+
+        let mut config = DirectorConfig::default();
+        config.probe_table.hot_threshold = 1.5;
+        assert!(matches!(
+            config.validate(),
+            Err(DirectorError::InvalidConfig(_))
+        ));
+
+        let mut config = DirectorConfig::default();
+        config.probe_table.size = 0;
+        assert!(matches!(
+            config.validate(),
+            Err(DirectorError::InvalidConfig(_))
+        ));
+
+        let config = DirectorConfig {
+            probe_interval: Duration::ZERO,
+            ..DirectorConfig::default()
+        };
+        assert!(matches!(
+            config.validate(),
+            Err(DirectorError::InvalidConfig(_))
+        ));
+
+        let mut config = DirectorConfig::default();
+        config.ejection.failure_rate = 2.0;
+        assert!(matches!(
+            config.validate(),
+            Err(DirectorError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn test_director_get_backend() {
+        let stats = Arc::new(DirectorStats::default());
+        let (director, _) = Director::new(stats, DirectorConfig::default());
+        let backend = test_backend("test1", SocketAddr::from(([127, 0, 0, 1], 8080)), 1);
+        director.add_backend(backend).unwrap();
+        let (backend, _selection) = director.get_backend(|_| true, &[], None).unwrap();
+        assert_eq!(backend.name, "test1");
+    }
+
+    #[test]
+    fn test_director_list_backends() {
+        let stats = Arc::new(DirectorStats::default());
+        let (director, _) = Director::new(stats, DirectorConfig::default());
+        let backend = test_backend("test1", SocketAddr::from(([127, 0, 0, 1], 8080)), 1);
+        director.add_backend(backend.clone()).unwrap();
+        director
+            .add_backend(test_backend(
+                "test2",
+                SocketAddr::from(([127, 0, 0, 1], 8081)),
+                2,
             ))
+            .unwrap();
+
+        assert_eq!(director.list_backends(false, false), "0/2\tsick");
+        assert_eq!(director.list_backends(false, true), "[0, 2, \"sick\"]");
+
+        director
+            .probe_table
+            .add_result(ProbeResult::new(SystemTime::now(), 4, 120, backend));
+
+        assert_eq!(director.list_backends(false, false), "1/2\thealthy");
+        assert!(director
+            .list_backends(true, false)
+            .contains("\ttest1\t1\t4\t120\t0\t"));
+
+        let json = director.list_backends(true, true);
+        let value: serde_json::Value =
+            serde_json::from_str(json.trim_end().trim_end_matches(',')).unwrap();
+        assert_eq!(value["backends"], 2);
+        assert_eq!(value["probes"][0]["backend"], "test1");
+        assert_eq!(value["probes"][0]["in_flight"], 4);
+        assert_eq!(value["probes"][0]["latency"], 120);
+    }
+
+    #[test]
+    fn test_director_get_backend_skips_unhealthy() {
+        let stats = Arc::new(DirectorStats::default());
+        let (director, _) = Director::new(stats.clone(), DirectorConfig::default());
+        let sick = test_backend("sick", SocketAddr::from(([127, 0, 0, 1], 8080)), 1);
+        let healthy = test_backend("healthy", SocketAddr::from(([127, 0, 0, 1], 8081)), 2);
+        director.add_backend(sick.clone()).unwrap();
+        director.add_backend(healthy).unwrap();
+
+        // The sick backend has the best probe, but must not be selected
+        director
+            .probe_table
+            .add_result(ProbeResult::new(SystemTime::now(), 1, 10, sick));
+
+        for _ in 0..10 {
+            let (backend, selection) = director
+                .get_backend(|b| b.name != "sick", &[], None)
+                .unwrap();
+            assert_eq!(backend.name, "healthy");
+            assert_eq!(selection, Selection::Fallback(FallbackMode::Random));
+        }
+        assert!(stats.skipped_unhealthy.load(Ordering::Relaxed) >= 10);
+
+        assert!(matches!(
+            director.get_backend(|_| false, &[], None),
+            Err(DirectorError::NoHealthyBackend)
+        ));
+    }
+
+    #[test]
+    fn test_director_get_backend_skips_tried() {
+        let stats = Arc::new(DirectorStats::default());
+        let (director, _) = Director::new(stats.clone(), DirectorConfig::default());
+        let first = test_backend("first", SocketAddr::from(([127, 0, 0, 1], 8080)), 1);
+        let second = test_backend("second", SocketAddr::from(([127, 0, 0, 1], 8081)), 2);
+        let third = test_backend("third", SocketAddr::from(([127, 0, 0, 1], 8082)), 3);
+        director.add_backend(first.clone()).unwrap();
+        director.add_backend(second.clone()).unwrap();
+        director.add_backend(third.clone()).unwrap();
+
+        // "first" has the best probe, "second" the next best
+        director
+            .probe_table
+            .add_result(ProbeResult::new(SystemTime::now(), 1, 10, first.clone()));
+        director
+            .probe_table
+            .add_result(ProbeResult::new(SystemTime::now(), 1, 20, second.clone()));
+
+        let (backend, selection) = director.get_backend(|_| true, &[], None).unwrap();
+        assert_eq!(backend.name, "first");
+        assert_eq!(selection, Selection::ProbeTable);
+
+        // A retry gets the next best probe
+        let (backend, selection) = director
+            .get_backend(|_| true, &[first.vcl_backend], None)
+            .unwrap();
+        assert_eq!(backend.name, "second");
+        assert_eq!(selection, Selection::ProbeTable);
+
+        // Without a probe left, a random backend that wasn't tried
+        let (backend, selection) = director
+            .get_backend(|_| true, &[first.vcl_backend, second.vcl_backend], None)
+            .unwrap();
+        assert_eq!(backend.name, "third");
+        assert_eq!(selection, Selection::Fallback(FallbackMode::Random));
+        // Each tried backend counts once per request, not once per pass
+        assert_eq!(stats.skipped_tried.load(Ordering::Relaxed), 3);
+
+        // Once everything was tried, backends are reused rather than failing
+        let all = [first.vcl_backend, second.vcl_backend, third.vcl_backend];
+        assert!(director.get_backend(|_| true, &all, None).is_ok());
+        assert!(matches!(
+            director.get_backend(|b| b.name == "second", &all, None),
+            Ok((backend, _)) if backend.name == "second"
+        ));
+    }
+
+    /// Creates a backend whose Varnish side has `n_conn` connections open.
+    fn create_test_backend_with_connections(
+        name: &str,
+        director_id: usize,
+        n_conn: u32,
+    ) -> Backend {
+        let mut vbe: Box<backend> = Box::new(unsafe { std::mem::zeroed() });
+        vbe.n_conn = n_conn;
+        Backend {
+            vbe: Box::leak(vbe),
+            ..test_backend(name, SocketAddr::from(([127, 0, 0, 1], 8080)), director_id)
+        }
+    }
+
+    fn fallback_director(fallback: FallbackMode) -> Arc<Director> {
+        let config = DirectorConfig {
+            fallback,
+            ..Default::default()
+        };
+        Director::new(Arc::new(DirectorStats::default()), config).0
+    }
+
+    #[test]
+    fn test_fallback_mode_parse() {
+        for mode in [
+            FallbackMode::Random,
+            FallbackMode::RoundRobin,
+            FallbackMode::LeastLocalConnections,
+            FallbackMode::PowerOfTwo,
+            FallbackMode::LastKnown,
+        ] {
+            assert_eq!(mode.to_string().parse::<FallbackMode>().unwrap(), mode);
+        }
+        assert!(matches!(
+            "fastest".parse::<FallbackMode>(),
+            Err(DirectorError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn test_load_signal_parse() {
+        for signal in [LoadSignal::Probe, LoadSignal::Local, LoadSignal::Blended] {
+            assert_eq!(signal.to_string().parse::<LoadSignal>().unwrap(), signal);
+        }
+        assert!(matches!(
+            "passive".parse::<LoadSignal>(),
+            Err(DirectorError::InvalidConfig(_))
+        ));
+    }
+
+    fn load_signal_director(load_signal: LoadSignal) -> Arc<Director> {
+        let config = DirectorConfig {
+            load_signal,
+            ..Default::default()
+        };
+        Director::new(Arc::new(DirectorStats::default()), config).0
+    }
+
+    #[test]
+    fn test_director_local_load_signal() {
+        let director = load_signal_director(LoadSignal::Local);
+        assert!(!director.is_healthy());
+
+        let busy = create_test_backend_with_connections("busy", 1, 10);
+        let idle = create_test_backend_with_connections("idle", 2, 1);
+        let fast = create_test_backend_with_connections("fast", 3, 2);
+        director.add_backend(busy.clone()).unwrap();
+        director.add_backend(idle.clone()).unwrap();
+        director.add_backend(fast.clone()).unwrap();
+        assert!(director.is_healthy());
+
+        // Among cold backends, the one with the lowest observed latency wins,
+        // a backend without observations being presumed fast
+        director
+            .observe(idle.vcl_backend, Duration::from_millis(50))
+            .unwrap();
+        director
+            .observe(fast.vcl_backend, Duration::from_millis(5))
+            .unwrap();
+        director
+            .observe(busy.vcl_backend, Duration::from_millis(100))
+            .unwrap();
+        for _ in 0..10 {
+            let (backend, selection) = director.get_backend(|_| true, &[], None).unwrap();
+            assert_eq!(backend, fast);
+            assert_eq!(selection, Selection::LocalLoad);
+        }
+
+        // Waiting fetches count as load too
+        unsafe { (*(fast.vbe as *mut backend)).cw_count = 20 };
+        let (backend, _) = director.get_backend(|_| true, &[], None).unwrap();
+        assert_eq!(backend, idle);
+
+        // Hot backends are only used when no cold one is
+        let (backend, _) = director.get_backend(|b| *b == fast, &[], None).unwrap();
+        assert_eq!(backend, fast);
+        assert_eq!(director.probe_table.len(), 0);
+    }
+
+    #[test]
+    fn test_director_blended_load_signal() {
+        let director = load_signal_director(LoadSignal::Blended);
+        let crowded = create_test_backend_with_connections("crowded", 1, 8);
+        let quiet = create_test_backend_with_connections("quiet", 2, 0);
+        let hot = create_test_backend_with_connections("hot", 3, 0);
+        director.add_backend(crowded.clone()).unwrap();
+        director.add_backend(quiet.clone()).unwrap();
+        director.add_backend(hot.clone()).unwrap();
+
+        // Both probes look alike, but this Varnish already sent 8 requests
+        // to the faster one since it was probed
+        let now = SystemTime::now();
+        let table = &director.probe_table;
+        table.add_result(ProbeResult::new(now, 2, 10, crowded.clone()));
+        table.add_result(ProbeResult::new(now, 2, 20, quiet.clone()));
+        table.add_result(ProbeResult::new(now, 10, 5, hot.clone()));
+        let (backend, selection) = director.get_backend(|_| true, &[], None).unwrap();
+        assert_eq!(backend, quiet);
+        assert_eq!(selection, Selection::ProbeTable);
+
+        // Probe-only selection ignores local connections
+        let director = load_signal_director(LoadSignal::Probe);
+        director.add_backend(crowded.clone()).unwrap();
+        director.add_backend(quiet.clone()).unwrap();
+        director
+            .probe_table
+            .add_result(ProbeResult::new(now, 2, 10, crowded.clone()));
+        director
+            .probe_table
+            .add_result(ProbeResult::new(now, 2, 20, quiet));
+        director
+            .probe_table
+            .add_result(ProbeResult::new(now, 10, 5, hot));
+        let (backend, _) = director.get_backend(|_| true, &[], None).unwrap();
+        assert_eq!(backend, crowded);
+    }
+
+    #[test]
+    fn test_director_round_robin_fallback() {
+        let director = fallback_director(FallbackMode::RoundRobin);
+        for id in 1..=3 {
+            let addr = SocketAddr::from(([127, 0, 0, 1], 8080 + id as u16));
+            director
+                .add_backend(test_backend(&format!("b{}", id), addr, id))
+                .unwrap();
+        }
+
+        let picks: Vec<String> = (0..6)
+            .map(|_| {
+                let (backend, selection) = director.get_backend(|_| true, &[], None).unwrap();
+                assert_eq!(selection, Selection::Fallback(FallbackMode::RoundRobin));
+                backend.name
+            })
+            .collect();
+        assert_eq!(picks, ["b1", "b2", "b3", "b1", "b2", "b3"]);
+    }
+
+    #[test]
+    fn test_director_least_local_connections_fallback() {
+        let director = fallback_director(FallbackMode::LeastLocalConnections);
+        director
+            .add_backend(create_test_backend_with_connections("busy", 1, 10))
+            .unwrap();
+        director
+            .add_backend(create_test_backend_with_connections("quiet", 2, 2))
+            .unwrap();
+        // Twice the capacity, but more than twice the connections
+        director
+            .add_backend(create_test_backend_with_connections("big", 3, 5).with_weight(2.0))
+            .unwrap();
+
+        for _ in 0..10 {
+            let (backend, selection) = director.get_backend(|_| true, &[], None).unwrap();
+            assert_eq!(backend.name, "quiet");
+            assert_eq!(
+                selection,
+                Selection::Fallback(FallbackMode::LeastLocalConnections)
+            );
+        }
+    }
+
+    #[test]
+    fn test_director_power_of_two_fallback() {
+        let director = fallback_director(FallbackMode::PowerOfTwo);
+        director
+            .add_backend(create_test_backend_with_connections("busy", 1, 10))
+            .unwrap();
+        director
+            .add_backend(create_test_backend_with_connections("quiet", 2, 1))
+            .unwrap();
+
+        // With two backends, both are always compared
+        for _ in 0..10 {
+            let (backend, selection) = director.get_backend(|_| true, &[], None).unwrap();
+            assert_eq!(backend.name, "quiet");
+            assert_eq!(selection, Selection::Fallback(FallbackMode::PowerOfTwo));
+        }
+    }
+
+    #[test]
+    fn test_director_last_known_fallback() {
+        let director = fallback_director(FallbackMode::LastKnown);
+        let loaded = test_backend("loaded", SocketAddr::from(([127, 0, 0, 1], 8080)), 1);
+        let idle = test_backend("idle", SocketAddr::from(([127, 0, 0, 1], 8081)), 2);
+        director.add_backend(loaded.clone()).unwrap();
+        director.add_backend(idle.clone()).unwrap();
+
+        // Without any probe ever, falls back to random
+        let (_, selection) = director.get_backend(|_| true, &[], None).unwrap();
+        assert_eq!(selection, Selection::Fallback(FallbackMode::Random));
+
+        let expired = SystemTime::now() - Duration::from_secs(60);
+        director
+            .probe_table
+            .add_result(ProbeResult::new(expired, 50, 10, loaded));
+        director
+            .probe_table
+            .add_result(ProbeResult::new(expired, 1, 10, idle));
+
+        for _ in 0..5 {
+            let (backend, selection) = director.get_backend(|_| true, &[], None).unwrap();
+            assert_eq!(backend.name, "idle");
+            assert_eq!(selection, Selection::Fallback(FallbackMode::LastKnown));
+        }
+        let (backend, _) = director
+            .get_backend(|b| b.name != "idle", &[], None)
+            .unwrap();
+        assert_eq!(backend.name, "loaded");
+    }
+
+    #[test]
+    fn test_director_get_backend_by_key() {
+        let stats = Arc::new(DirectorStats::default());
+        let (director, _) = Director::new(stats.clone(), DirectorConfig::default());
+        for id in 1..=4 {
+            director
+                .add_backend(create_test_backend_with_connections(
+                    &format!("b{}", id),
+                    id,
+                    0,
+                ))
+                .unwrap();
+        }
+
+        // The same key always lands on the same backend
+        let (owner, selection) = director.get_backend(|_| true, &[], Some("/a")).unwrap();
+        assert_eq!(selection, Selection::Hash);
+        for _ in 0..10 {
+            let (backend, _) = director.get_backend(|_| true, &[], Some("/a")).unwrap();
+            assert_eq!(backend, owner);
+        }
+
+        // Keys spread over the backends
+        let owners: std::collections::HashSet<String> = (0..100)
+            .map(|k| {
+                let key = format!("/{}", k);
+                director
+                    .get_backend(|_| true, &[], Some(&key))
+                    .unwrap()
+                    .0
+                    .name
+            })
+            .collect();
+        assert_eq!(owners.len(), 4);
+
+        // A retry moves along the ring
+        let (next, selection) = director
+            .get_backend(|_| true, &[owner.vcl_backend], Some("/a"))
+            .unwrap();
+        assert_ne!(next, owner);
+        assert_eq!(selection, Selection::Hash);
+    }
+
+    #[test]
+    fn test_director_get_backend_by_key_bounded_load() {
+        let (director, _) = Director::new(
+            Arc::new(DirectorStats::default()),
+            DirectorConfig::default(),
+        );
+        director
+            .add_backend(create_test_backend_with_connections("b1", 1, 0))
+            .unwrap();
+        director
+            .add_backend(create_test_backend_with_connections("b2", 2, 0))
+            .unwrap();
+        let (owner, _) = director.get_backend(|_| true, &[], Some("/a")).unwrap();
+
+        // Load the owner well above its share
+        unsafe { (*(owner.vbe as *mut backend)).n_conn = 10 };
+        let (backend, selection) = director.get_backend(|_| true, &[], Some("/a")).unwrap();
+        assert_ne!(backend, owner);
+        assert_eq!(selection, Selection::HashBounded);
+    }
+
+    #[test]
+    fn test_director_get_backend_by_key_spills_when_hot() {
+        let config = DirectorConfig {
+            hash_max_latency: 100,
+            ..Default::default()
+        };
+        let (director, _) = Director::new(Arc::new(DirectorStats::default()), config);
+        for id in 1..=3 {
+            director
+                .add_backend(create_test_backend_with_connections(
+                    &format!("b{}", id),
+                    id,
+                    0,
+                ))
+                .unwrap();
         }
+        let (owner, _) = director.get_backend(|_| true, &[], Some("/a")).unwrap();
+        let others: Vec<Backend> = director
+            .backends
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|b| **b != owner)
+            .cloned()
+            .collect();
+        let (other, busiest) = (&others[0], &others[1]);
+
+        // Lightly loaded owner keeps the key
+        let now = SystemTime::now();
+        director
+            .probe_table
+            .add_result(ProbeResult::new(now, 10, 10, busiest.clone()));
+        director
+            .probe_table
+            .add_result(ProbeResult::new(now, 1, 10, other.clone()));
+        director
+            .probe_table
+            .add_result(ProbeResult::new(now, 1, 10, owner.clone()));
+        let (backend, selection) = director.get_backend(|_| true, &[], Some("/a")).unwrap();
+        assert_eq!(backend, owner);
+        assert_eq!(selection, Selection::Hash);
+
+        // Hot owner: the key goes to the best probe
+        director
+            .probe_table
+            .add_result(ProbeResult::new(now, 9, 10, owner.clone()));
+        let (backend, selection) = director.get_backend(|_| true, &[], Some("/a")).unwrap();
+        assert_eq!(backend, *other);
+        assert_eq!(selection, Selection::HashSpilled);
+
+        // Same for a slow owner
+        director
+            .probe_table
+            .add_result(ProbeResult::new(now, 1, 500, owner.clone()));
+        let (backend, selection) = director.get_backend(|_| true, &[], Some("/a")).unwrap();
+        assert_eq!(backend, *other);
+        assert_eq!(selection, Selection::HashSpilled);
+
+        // Nowhere better to go: affinity is kept
+        director
+            .probe_table
+            .add_result(ProbeResult::new(now, 1, 500, other.clone()));
+        let (backend, selection) = director.get_backend(|_| true, &[], Some("/a")).unwrap();
+        assert_eq!(backend, owner);
+        assert_eq!(selection, Selection::Hash);
     }
 
-    /// Constructs a probe request for a backend.
-    ///
-    /// # Arguments
-    /// * `backend` - The backend to probe
-    ///
-    /// # Returns
-    /// A configured HTTP request ready to be sent
-    fn construct_probe_request(&self, backend: &Backend) -> ureq::Request {
-        let probe_path = self
-            .probe_path
-            .read()
-            .map(|p| p.clone())
-            .unwrap_or_else(|_| "/probe".to_string());
+    #[test]
+    fn test_director_prefers_local_zone() {
+        let stats = Arc::new(DirectorStats::default());
+        let (director, _) = Director::new(stats.clone(), DirectorConfig::default());
+        director.set_local_zone("a");
+        let local = test_backend("local", SocketAddr::from(([127, 0, 0, 1], 8080)), 1)
+            .with_zone(Some("a".to_string()));
+        let remote = test_backend("remote", SocketAddr::from(([127, 0, 0, 1], 8081)), 2)
+            .with_zone(Some("b".to_string()));
+        let busy = test_backend("busy", SocketAddr::from(([127, 0, 0, 1], 8082)), 3)
+            .with_zone(Some("b".to_string()));
+        director.add_backend(local.clone()).unwrap();
+        director.add_backend(remote.clone()).unwrap();
+        director.add_backend(busy.clone()).unwrap();
+
+        // Without probes, the fallback stays in the local zone
+        for _ in 0..5 {
+            assert_eq!(director.get_backend(|_| true, &[], None).unwrap().0, local);
+        }
 
-        let url = format!("http://{}{}", backend.address, probe_path);
-        ureq::get(&url)
-            .timeout(Duration::from_secs(5))
-            .set("Host", &backend.name)
+        // A cold local backend wins over a faster remote one
+        let now = SystemTime::now();
+        director
+            .probe_table
+            .add_result(ProbeResult::new(now, 10, 10, busy));
+        director
+            .probe_table
+            .add_result(ProbeResult::new(now, 1, 5, remote.clone()));
+        director
+            .probe_table
+            .add_result(ProbeResult::new(now, 2, 50, local.clone()));
+        let (backend, selection) = director.get_backend(|_| true, &[], None).unwrap();
+        assert_eq!(backend, local);
+        assert_eq!(selection, Selection::ProbeTable);
+
+        // Once the local backend is hot, requests spill over
+        director
+            .probe_table
+            .add_result(ProbeResult::new(now, 9, 50, local));
+        let (backend, _) = director.get_backend(|_| true, &[], None).unwrap();
+        assert_eq!(backend, remote);
+
+        assert_eq!(stats.zone_local.load(Ordering::Relaxed), 6);
+        assert_eq!(stats.zone_spillover.load(Ordering::Relaxed), 1);
+        let mut zones: Vec<_> = director
+            .zone_stats()
+            .into_iter()
+            .map(|(zone, stats)| (zone, stats.selected.load(Ordering::Relaxed)))
+            .collect();
+        zones.sort();
+        assert_eq!(zones, [("a".to_string(), 6), ("b".to_string(), 1)]);
     }
 
-    /// Randomly selects and probes a subset of backends.
-    /// Updates the probe table with results from successful probes.
-    fn probe_backends(&self, count: usize) {
-        let backends_to_probe = if let Ok(backends) = self.backends.read() {
-            let mut rng = rand::thread_rng();
-            backends
-                .iter()
-                .choose_multiple(&mut rng, count)
-                .into_iter()
-                .cloned()
-                .collect::<Vec<_>>()
-        } else {
-            return;
-        };
-
-        for backend in backends_to_probe {
-            self.stats.probes_sent.fetch_add(1, Ordering::Relaxed);
-            let request = self.construct_probe_request(&backend);
-
-            match request.call() {
-                Ok(response) => {
-                    if response.status() != 200 {
-                        self.stats.probes_fail.fetch_add(1, Ordering::Relaxed);
-                        continue;
-                    }
+    #[test]
+    fn test_director_drops_results_of_removed_backends() {
+        let stats = Arc::new(DirectorStats::default());
+        let (director, _) = Director::new(stats.clone(), DirectorConfig::default());
+        let b1 = test_backend("b1", SocketAddr::from(([127, 0, 0, 1], 8080)), 1);
+        director.add_backend(b1.clone()).unwrap();
+
+        // A probe still in flight when its backend is removed
+        director.remove_backend(b1.vcl_backend);
+        director.add_probe_result(b1, 1, 10);
+        assert_eq!(director.probe_table.len(), 0);
+        assert_eq!(stats.probes_dropped.load(Ordering::Relaxed), 1);
+    }
 
-                    let in_flight = match response
-                        .header("X-In-Flight")
-                        .and_then(|s| s.parse::<usize>().ok())
-                    {
-                        Some(val) => val,
-                        None => {
-                            self.stats
-                                .probes_missing_headers
-                                .fetch_add(1, Ordering::Relaxed);
-                            continue;
-                        }
-                    };
-
-                    let est_latency = match response
-                        .header("X-Estimated-Latency")
-                        .and_then(|s| s.parse::<usize>().ok())
-                    {
-                        Some(val) => val,
-                        None => {
-                            self.stats
-                                .probes_missing_headers
-                                .fetch_add(1, Ordering::Relaxed);
-                            continue;
-                        }
-                    };
-
-                    self.stats.probes_success.fetch_add(1, Ordering::Relaxed);
-                    let now = SystemTime::now();
-                    self.probe_table.add_result(ProbeResult::new(
-                        now,
-                        in_flight,
-                        est_latency,
-                        backend,
-                    ));
-                }
-                Err(_) => {
-                    self.stats.probes_fail.fetch_add(1, Ordering::Relaxed);
-                    continue;
-                }
-            }
+    #[test]
+    fn test_director_checks_health_once_per_request() {
+        let stats = Arc::new(DirectorStats::default());
+        let (director, _) = Director::new(stats.clone(), DirectorConfig::default());
+        director.set_local_zone("a");
+        let addr = SocketAddr::from(([127, 0, 0, 1], 8080));
+        let draining = test_backend("draining", addr, 1).with_zone(Some("a".to_string()));
+        let sick = test_backend("sick", addr, 2).with_zone(Some("a".to_string()));
+        let healthy = test_backend("healthy", addr, 3).with_zone(Some("a".to_string()));
+        for backend in [&draining, &sick, &healthy] {
+            director.add_backend(backend.clone()).unwrap();
         }
+        director.report(draining.vcl_backend, 1, Some(10)).unwrap();
+        director.report(sick.vcl_backend, 1, Some(10)).unwrap();
+        // Hot, so selection looks at the table twice: for a cold local probe, then any
+        director.report(healthy.vcl_backend, 10, Some(10)).unwrap();
+        director
+            .drain_backend(draining.vcl_backend, Duration::from_secs(60), false)
+            .unwrap();
+
+        let checks = AtomicUsize::new(0);
+        let is_healthy = |b: &Backend| {
+            checks.fetch_add(1, Ordering::Relaxed);
+            *b != sick
+        };
+        let (backend, _) = director.get_backend(is_healthy, &[], None).unwrap();
+        assert_eq!(backend, healthy);
+        assert_eq!(checks.load(Ordering::Relaxed), 3);
+        assert_eq!(stats.skipped_draining.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.skipped_unhealthy.load(Ordering::Relaxed), 1);
     }
 
-    /// Checks if the director has any valid probe results.
-    ///
-    /// # Returns
-    /// `true` if there are valid probe results, `false` otherwise
-    pub fn is_healthy(&self) -> bool {
-        // Only healthy if we have valid probe results
-        self.probe_table.has_probes()
+    #[test]
+    fn test_director_report() {
+        let stats = Arc::new(DirectorStats::default());
+        let (director, _) = Director::new(stats.clone(), DirectorConfig::default());
+        let busy = test_backend("busy", SocketAddr::from(([127, 0, 0, 1], 8080)), 1);
+        let idle = test_backend("idle", SocketAddr::from(([127, 0, 0, 1], 8081)), 2);
+        director.add_backend(busy.clone()).unwrap();
+        director.add_backend(idle.clone()).unwrap();
+
+        director.report(busy.vcl_backend, 20, Some(100)).unwrap();
+        director.report(idle.vcl_backend, 1, Some(10)).unwrap();
+        assert_eq!(director.probe_table.len(), 2);
+        assert_eq!(stats.reports.load(Ordering::Relaxed), 2);
+
+        let (backend, selection) = director.get_backend(|_| true, &[], None).unwrap();
+        assert_eq!(backend, idle);
+        assert_eq!(selection, Selection::ProbeTable);
+
+        // A newer report replaces the previous one
+        director.report(idle.vcl_backend, 50, Some(500)).unwrap();
+        director.report(busy.vcl_backend, 1, Some(10)).unwrap();
+        assert_eq!(director.probe_table.len(), 2);
+        let (backend, _) = director.get_backend(|_| true, &[], None).unwrap();
+        assert_eq!(backend, busy);
+
+        assert!(matches!(
+            director.report(VCL_BACKEND(99 as *const director), 1, Some(10)),
+            Err(DirectorError::UnknownBackend)
+        ));
     }
 
-    /// Returns a reference to the stats Arc for syncing to Vsc
-    pub fn stats(&self) -> &Arc<DirectorStats> {
-        &self.stats
+    #[test]
+    fn test_director_observe() {
+        let stats = Arc::new(DirectorStats::default());
+        let (director, _) = Director::new(stats.clone(), DirectorConfig::default());
+        let optimist = test_backend("optimist", SocketAddr::from(([127, 0, 0, 1], 8080)), 1);
+        let silent = test_backend("silent", SocketAddr::from(([127, 0, 0, 1], 8081)), 2);
+        director.add_backend(optimist.clone()).unwrap();
+        director.add_backend(silent.clone()).unwrap();
+
+        // Without an estimate or an observation, there is nothing to go on
+        assert!(matches!(
+            director.report(silent.vcl_backend, 1, None),
+            Err(DirectorError::MissingLatency)
+        ));
+
+        director.report(optimist.vcl_backend, 1, Some(10)).unwrap();
+        for _ in 0..20 {
+            director
+                .observe(optimist.vcl_backend, Duration::from_millis(100))
+                .unwrap();
+            director
+                .observe(silent.vcl_backend, Duration::from_millis(30))
+                .unwrap();
+        }
+        assert_eq!(stats.observations.load(Ordering::Relaxed), 40);
+
+        // The silent backend is judged on its observed latency, the optimist
+        // on its estimate corrected by ten
+        director.report(silent.vcl_backend, 1, None).unwrap();
+        director.report(optimist.vcl_backend, 1, Some(10)).unwrap();
+        let (backend, selection) = director.get_backend(|_| true, &[], None).unwrap();
+        assert_eq!(backend, silent);
+        assert_eq!(selection, Selection::ProbeTable);
+
+        assert!(matches!(
+            director.observe(VCL_BACKEND(99 as *const director), Duration::from_millis(1)),
+            Err(DirectorError::UnknownBackend)
+        ));
     }
 
-    /// Computes aggregate metrics from the probe table
-    /// Called periodically from the probe loop to update gauges
-    fn compute_metrics(&self) {
-        // Update backend count
-        let backend_count = self.backends.read().map(|b| b.len()).unwrap_or(0);
-        self.stats
-            .backends
-            .store(backend_count as u64, Ordering::Relaxed);
+    #[test]
+    fn test_director_drain_backend() {
+        let stats = Arc::new(DirectorStats::default());
+        let (director, _) = Director::new(stats.clone(), DirectorConfig::default());
+        let leaving = test_backend("leaving", SocketAddr::from(([127, 0, 0, 1], 8080)), 1);
+        let staying = test_backend("staying", SocketAddr::from(([127, 0, 0, 1], 8081)), 2);
+        director.add_backend(leaving.clone()).unwrap();
+        director.add_backend(staying.clone()).unwrap();
+
+        director
+            .drain_backend(leaving.vcl_backend, Duration::from_secs(3600), true)
+            .unwrap();
+        assert_eq!(stats.backends_draining.load(Ordering::Relaxed), 1);
+        assert!(director
+            .debug_probe_table()
+            .unwrap()
+            .contains("drain: backend=leaving"));
+
+        // Its probes are kept, it just isn't selected
+        director
+            .probe_table
+            .add_result(ProbeResult::new(SystemTime::now(), 0, 1, leaving.clone()));
+        for _ in 0..10 {
+            let (backend, _) = director.get_backend(|_| true, &[], None).unwrap();
+            assert_eq!(backend, staying);
+        }
+        assert!(stats.skipped_draining.load(Ordering::Relaxed) >= 10);
+        assert!(matches!(
+            director.get_backend(|b| *b != staying, &[], None),
+            Err(DirectorError::NoHealthyBackend)
+        ));
+
+        // Once drained, it is removed, or selected again
+        director
+            .drain_backend(leaving.vcl_backend, Duration::ZERO, false)
+            .unwrap();
+        let (backend, _) = director.get_backend(|b| *b != staying, &[], None).unwrap();
+        assert_eq!(backend, leaving);
+        assert_eq!(stats.backends_draining.load(Ordering::Relaxed), 0);
+
+        director
+            .drain_backend(leaving.vcl_backend, Duration::ZERO, true)
+            .unwrap();
+        director.get_backend(|_| true, &[], None).unwrap();
+        assert_eq!(director.backends.read().unwrap().len(), 1);
+        assert_eq!(director.probe_table.len(), 0);
 
-        // Update probe table size
-        let table_size = self.probe_table.len();
-        self.stats
-            .probe_table_size
-            .store(table_size as u64, Ordering::Relaxed);
+        assert!(matches!(
+            director.drain_backend(leaving.vcl_backend, Duration::ZERO, true),
+            Err(DirectorError::UnknownBackend)
+        ));
+    }
 
-        // Compute percentiles from probe values
-        if let Some((mut rifs, mut latencies)) = self.probe_table.get_probe_values() {
-            // Sort for percentile computation
-            rifs.sort_unstable();
-            latencies.sort_unstable();
+    #[test]
+    fn test_director_slow_start_follows_unselectable_backends() {
+        let config = DirectorConfig {
+            slow_start: Duration::from_secs(60),
+            ..Default::default()
+        };
+        let (director, _) = Director::new(Arc::new(DirectorStats::default()), config);
+        let b1 = test_backend("b1", SocketAddr::from(([127, 0, 0, 1], 8080)), 1);
+        let b2 = test_backend("b2", SocketAddr::from(([127, 0, 0, 1], 8081)), 2);
+        director.add_backend(b1.clone()).unwrap();
+        director.add_backend(b2.clone()).unwrap();
+        director
+            .drain_backend(b1.vcl_backend, Duration::from_secs(60), false)
+            .unwrap();
+
+        // A draining backend going down starts its slow start over once back
+        director.get_backend(|b| *b != b1, &[], None).unwrap();
+        assert!(director.slow_start.progress(&b1, Instant::now()).is_none());
+        director.get_backend(|_| true, &[], None).unwrap();
+        assert!(director.slow_start.progress(&b1, Instant::now()).is_some());
+    }
 
-            let len = rifs.len();
-            if len > 0 {
-                // Min/max
-                self.stats
-                    .probe_min_rif
-                    .store(rifs[0] as u64, Ordering::Relaxed);
-                self.stats
-                    .probe_max_rif
-                    .store(rifs[len - 1] as u64, Ordering::Relaxed);
+    #[test]
+    fn test_director_slow_start() {
+        let stats = Arc::new(DirectorStats::default());
+        let config = DirectorConfig {
+            slow_start: Duration::from_millis(300),
+            ..Default::default()
+        };
+        let (director, _) = Director::new(stats.clone(), config);
+        let veteran = test_backend("veteran", SocketAddr::from(([127, 0, 0, 1], 8080)), 1);
+        let newcomer = test_backend("newcomer", SocketAddr::from(([127, 0, 0, 1], 8081)), 2);
+        director.add_backend(veteran.clone()).unwrap();
+        thread::sleep(Duration::from_millis(350));
+        director.add_backend(newcomer.clone()).unwrap();
+        assert!(director
+            .debug_probe_table()
+            .unwrap()
+            .contains("slow_start: backend=newcomer"));
+
+        // Starting at a tenth of its share, half of the requests
+        let newcomer_count = (0..1000)
+            .filter(|_| director.get_backend(|_| true, &[], None).unwrap().0 == newcomer)
+            .count();
+        assert!(
+            (1..300).contains(&newcomer_count),
+            "newcomer selected {} times out of 1000",
+            newcomer_count
+        );
+        assert!(stats.skipped_slow_start.load(Ordering::Relaxed) > 0);
+        // Held back, but still used before going back to a tried backend
+        for _ in 0..10 {
+            let tried = [veteran.vcl_backend];
+            let (backend, _) = director.get_backend(|_| true, &tried, None).unwrap();
+            assert_eq!(backend, newcomer);
+        }
 
-                // p50 (median)
-                let p50_idx = len / 2;
-                self.stats
-                    .probe_p50_rif
-                    .store(rifs[p50_idx] as u64, Ordering::Relaxed);
-                self.stats
-                    .probe_p50_latency
-                    .store(latencies[p50_idx] as u64, Ordering::Relaxed);
+        thread::sleep(Duration::from_millis(350));
+        assert_eq!(
+            director.slow_start.progress(&newcomer, Instant::now()),
+            None
+        );
+        assert!(!director
+            .debug_probe_table()
+            .unwrap()
+            .contains("slow_start:"));
+
+        // Back from being down, it ramps up again
+        director.get_backend(|b| *b != newcomer, &[], None).unwrap();
+        director.get_backend(|_| true, &[], None).unwrap();
+        assert!(director
+            .slow_start
+            .progress(&newcomer, Instant::now())
+            .is_some());
+        assert_eq!(director.slow_start.progress(&veteran, Instant::now()), None);
+    }
 
-                // p80
-                let p80_idx = (len * 80) / 100;
-                let p80_idx = p80_idx.min(len - 1);
-                self.stats
-                    .probe_p80_rif
-                    .store(rifs[p80_idx] as u64, Ordering::Relaxed);
-                self.stats
-                    .probe_p80_latency
-                    .store(latencies[p80_idx] as u64, Ordering::Relaxed);
-            }
+    #[test]
+    fn test_director_counts_slow_start_skips_once() {
+        let stats = Arc::new(DirectorStats::default());
+        let config = DirectorConfig {
+            slow_start: Duration::from_secs(60),
+            ..Default::default()
+        };
+        let (director, _) = Director::new(stats.clone(), config);
+        let veteran = test_backend("veteran", SocketAddr::from(([127, 0, 0, 1], 8080)), 1);
+        let best = test_backend("best", SocketAddr::from(([127, 0, 0, 1], 8081)), 2);
+        let other = test_backend("other", SocketAddr::from(([127, 0, 0, 1], 8082)), 3);
+        for backend in [&veteran, &best, &other] {
+            director.add_backend(backend.clone()).unwrap();
         }
+        director.slow_start.remove_backend(&veteran);
+
+        // Only "best" would be picked, so only its holds back count
+        let best_count = (0..100)
+            .filter(|_| {
+                director.probe_table.add_result(ProbeResult::new(
+                    SystemTime::now(),
+                    1,
+                    10,
+                    best.clone(),
+                ));
+                director.get_backend(|_| true, &[], None).unwrap().0 == best
+            })
+            .count();
+        assert_eq!(
+            stats.skipped_slow_start.load(Ordering::Relaxed),
+            100 - best_count as u64
+        );
     }
 
-    fn ensure_probe_pool(&self) {
-        if !self.probe_table.has_enough_probes() {
-            self.probe_backends(PROBE_TABLE_SIZE / 2);
+    #[test]
+    fn test_director_slow_start_ignores_dropped_probes() {
+        let config = DirectorConfig {
+            slow_start: Duration::from_millis(400),
+            ..Default::default()
+        };
+        let (director, _) = Director::new(Arc::new(DirectorStats::default()), config);
+        // Nothing listens there, so probes are refused
+        let closed = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let veteran = test_backend("veteran", closed, 1);
+        let newcomer = test_backend("newcomer", closed, 2);
+        director.add_backend(veteran.clone()).unwrap();
+        thread::sleep(Duration::from_millis(450));
+        director.add_backend(newcomer.clone()).unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        for backend in [&veteran, &newcomer] {
+            let permit = director.probe_slots.clone().try_acquire_owned().unwrap();
+            runtime.block_on(director.clone().probe_backend(
+                backend.clone(),
+                director.probe_spec(),
+                permit,
+            ));
         }
-    }
-}
+        assert_eq!(director.stats.probes_fail.load(Ordering::Relaxed), 2);
+        director.get_backend(|_| true, &[], None).unwrap();
 
-#[cfg(test)]
-mod tests {
-    use std::fmt::Debug;
-    use std::io::{BufRead, BufReader, Write};
-    use std::net::{SocketAddr, TcpListener};
-    use std::thread;
-    use std::time::Duration;
+        // The ramp goes on where it was, and the veteran stays at full traffic
+        let progress = director.slow_start.progress(&newcomer, Instant::now());
+        assert!(progress.unwrap() >= 0.2, "progress {:?}", progress);
+        assert_eq!(director.slow_start.progress(&veteran, Instant::now()), None);
+    }
 
-    use varnish::ffi::{director, VCL_BACKEND};
+    #[test]
+    fn test_director_ejects_failing_backends() {
+        let stats = Arc::new(DirectorStats::default());
+        let config = DirectorConfig {
+            ejection: EjectionConfig {
+                consecutive_failures: 2,
+                ejection_time: Duration::from_millis(200),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (director, _) = Director::new(stats.clone(), config);
+        let backends: Vec<_> = (1..=3)
+            .map(|i| {
+                let addr = SocketAddr::from(([127, 0, 0, 1], 8080 + i as u16));
+                test_backend(&format!("b{}", i), addr, i)
+            })
+            .collect();
+        for backend in &backends {
+            director.add_backend(backend.clone()).unwrap();
+        }
+        let (failing, flaky) = (&backends[0], &backends[1]);
+        director
+            .probe_table
+            .add_result(ProbeResult::new(SystemTime::now(), 0, 1, failing.clone()));
+
+        director.record_probe_outcome(failing, false);
+        assert_eq!(director.probe_table.len(), 1);
+        director.record_probe_outcome(failing, false);
+        assert_eq!(stats.ejections.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.backends_ejected.load(Ordering::Relaxed), 1);
+        // Its probe is gone, and so is it from selection
+        assert_eq!(director.probe_table.len(), 0);
+        for _ in 0..20 {
+            let (backend, _) = director.get_backend(|_| true, &[], None).unwrap();
+            assert_ne!(backend, *failing);
+        }
+        assert!(director
+            .debug_probe_table()
+            .unwrap()
+            .contains("ejected: backend=b1"));
+
+        // Half of three backends is one
+        director.record_probe_outcome(flaky, false);
+        director.record_probe_outcome(flaky, false);
+        assert_eq!(stats.ejections.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.ejections_prevented.load(Ordering::Relaxed), 1);
+
+        thread::sleep(Duration::from_millis(250));
+        let selected: Vec<_> = (0..100)
+            .map(|_| director.get_backend(|_| true, &[], None).unwrap().0)
+            .collect();
+        assert!(selected.contains(failing));
+        assert_eq!(stats.unejections.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.backends_ejected.load(Ordering::Relaxed), 0);
+    }
 
-    use super::*;
+    #[test]
+    fn test_director_circuit_breaker() {
+        let stats = Arc::new(DirectorStats::default());
+        let config = DirectorConfig {
+            breaker: BreakerConfig {
+                error_rate: 0.5,
+                min_requests: 4,
+                open_time: Duration::from_millis(200),
+                half_open_requests: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (director, _) = Director::new(stats.clone(), config);
+        let failing = test_backend("failing", SocketAddr::from(([127, 0, 0, 1], 8080)), 1);
+        let working = test_backend("working", SocketAddr::from(([127, 0, 0, 1], 8081)), 2);
+        director.add_backend(failing.clone()).unwrap();
+        director.add_backend(working.clone()).unwrap();
+
+        // Probes say all is well, real responses don't
+        director
+            .probe_table
+            .add_result(ProbeResult::new(SystemTime::now(), 0, 1, failing.clone()));
+        for status in [200, 503, 503, 500] {
+            director.record_result(failing.vcl_backend, status).unwrap();
+        }
+        assert_eq!(stats.results.load(Ordering::Relaxed), 4);
+        assert_eq!(stats.result_errors.load(Ordering::Relaxed), 3);
+        assert_eq!(stats.breaker_opened.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.breakers_open.load(Ordering::Relaxed), 1);
+        assert!(director
+            .debug_probe_table()
+            .unwrap()
+            .contains("breaker: backend=failing (127.0.0.1:8080), state=open"));
+        for _ in 0..10 {
+            let (backend, _) = director.get_backend(|_| true, &[], None).unwrap();
+            assert_eq!(backend, working);
+        }
 
-    fn create_test_backend(name: &str, addr: SocketAddr, director_id: u32) -> Backend {
-        Backend {
-            name: name.to_string(),
-            address: addr,
-            vcl_backend: VCL_BACKEND(director_id as *const director), // fake VCL_BACKEND reference
+        // Half-open, a single trial goes through
+        thread::sleep(Duration::from_millis(250));
+        let (backend, _) = director.get_backend(|b| *b == failing, &[], None).unwrap();
+        assert_eq!(backend, failing);
+        for _ in 0..10 {
+            let (backend, _) = director.get_backend(|_| true, &[], None).unwrap();
+            assert_eq!(backend, working);
         }
+        director.record_result(failing.vcl_backend, 200).unwrap();
+        assert_eq!(stats.breaker_closed.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.breakers_open.load(Ordering::Relaxed), 0);
+        let (backend, _) = director.get_backend(|b| *b == failing, &[], None).unwrap();
+        assert_eq!(backend, failing);
+
+        assert!(matches!(
+            director.record_result(VCL_BACKEND(99 as *const director), 200),
+            Err(DirectorError::UnknownBackend)
+        ));
     }
 
     #[test]
-    fn test_director_add_remove_backend() {
+    fn test_director_backend_stats() {
         let stats = Arc::new(DirectorStats::default());
-        let (director, _) = Director::new(stats);
+        let config = DirectorConfig {
+            probe_table: ProbeTableConfig {
+                size: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (director, _) = Director::new(stats, config);
+        let b1 = test_backend("b1", SocketAddr::from(([127, 0, 0, 1], 8080)), 1);
+        let b2 = test_backend("b2", SocketAddr::from(([127, 0, 0, 1], 8081)), 2);
+        director.add_backend(b1.clone()).unwrap();
+        director.add_backend(b2.clone()).unwrap();
+        let stats_of = |backend: &Backend| {
+            director
+                .backend_stats()
+                .into_iter()
+                .find(|(b, _)| b == backend)
+                .map(|(_, stats)| stats)
+                .unwrap()
+        };
 
-        let backend = create_test_backend("test1", SocketAddr::from(([127, 0, 0, 1], 8080)), 1);
-        let backend1_ref = backend.vcl_backend;
-        let backend2 = create_test_backend("test2", SocketAddr::from(([127, 0, 0, 2], 8081)), 2);
+        director.report(b1.vcl_backend, 3, Some(40)).unwrap();
+        for _ in 0..2 {
+            let (selected, _) = director.get_backend(|_| true, &[], None).unwrap();
+            assert_eq!(selected, b1);
+        }
+        // The table holds a single probe, b1's hot one goes when b2 reports
+        director.report(b2.vcl_backend, 1, Some(20)).unwrap();
+        director.compute_metrics();
+
+        let b1_stats = stats_of(&b1);
+        assert_eq!(b1_stats.selected.load(Ordering::Relaxed), 2);
+        assert_eq!(b1_stats.last_rif.load(Ordering::Relaxed), 3);
+        assert_eq!(b1_stats.last_latency.load(Ordering::Relaxed), 40);
+        assert_eq!(b1_stats.evictions.load(Ordering::Relaxed), 1);
+        assert!(b1_stats.probe_age.load(Ordering::Relaxed) < 1000);
+        let b2_stats = stats_of(&b2);
+        assert_eq!(b2_stats.selected.load(Ordering::Relaxed), 0);
+        assert_eq!(b2_stats.last_rif.load(Ordering::Relaxed), 1);
+        assert_eq!(b2_stats.evictions.load(Ordering::Relaxed), 0);
+
+        director.remove_backend(b1.vcl_backend);
+        assert_eq!(director.backend_stats().len(), 1);
+    }
 
-        // Add backend and verify
-        director.add_backend(backend).unwrap();
-        assert_eq!(director.backends.read().unwrap().len(), 1);
+    #[test]
+    fn test_director_dump_json() {
+        let (director, _) = Director::new(Arc::new(DirectorStats::default()), Default::default());
+        let b1 = test_backend("b1", SocketAddr::from(([127, 0, 0, 1], 8080)), 1);
+        let b2 = test_backend("b2", SocketAddr::from(([127, 0, 0, 1], 8081)), 2);
+        director.add_backend(b1.clone()).unwrap();
+        director.add_backend(b2.clone()).unwrap();
+        director.report(b1.vcl_backend, 3, Some(40)).unwrap();
+        director
+            .drain_backend(b2.vcl_backend, Duration::from_secs(60), false)
+            .unwrap();
+
+        let dump: serde_json::Value = serde_json::from_str(&director.dump_json()).unwrap();
+        assert_eq!(dump["config"]["probe_table_size"], 16);
+        assert_eq!(dump["config"]["fallback"], "random");
+        assert_eq!(dump["backends"][0]["name"], "b1");
+        assert_eq!(dump["backends"][0]["state"], "active");
+        assert_eq!(dump["backends"][1]["state"], "draining");
+        assert_eq!(dump["table"][0]["backend"], "b1");
+        assert_eq!(dump["table"][0]["rif"], 3);
+        assert_eq!(dump["table"][0]["latency"], 40);
+        assert_eq!(dump["table"][0]["used_count"], 0);
+        assert_eq!(dump["stats"]["reports"], 1);
+        assert_eq!(
+            dump["stats"]["histograms"]["probe_rif"]["counts"],
+            serde_json::json!([0, 1, 0, 0, 0, 0, 0])
+        );
+    }
 
-        // Verify the backend name
-        assert_eq!(director.backends.read().unwrap()[0].name, "test1");
+    #[test]
+    fn test_director_decision() {
+        let (director, _) = Director::new(Arc::new(DirectorStats::default()), Default::default());
+        let b1 = test_backend("b1", SocketAddr::from(([127, 0, 0, 1], 8080)), 1);
+        let b2 = test_backend("b2", SocketAddr::from(([127, 0, 0, 1], 8081)), 2);
+        director.add_backend(b1.clone()).unwrap();
+        director.add_backend(b2.clone()).unwrap();
+
+        let decision = director.select_backend(|b| *b == b1, &[], None).unwrap();
+        assert_eq!(decision.to_string(), "backend=b1 kind=fallback mode=random");
+
+        director.report(b1.vcl_backend, 1, Some(40)).unwrap();
+        director.report(b2.vcl_backend, 2, Some(20)).unwrap();
+        let decision = director.select_backend(|_| true, &[], None).unwrap();
+        assert_eq!(decision.selection, Selection::ProbeTable);
+        // b2 has the max RIF, so it is hot and comes second despite its latency
+        let summary = decision.to_string();
+        assert!(
+            summary.starts_with("backend=b1 kind=cold rif=1 latency=40 age=0.0"),
+            "{}",
+            summary
+        );
+        assert!(summary.ends_with(" runner_up=b2"), "{}", summary);
+    }
 
-        // Add another backend
-        director.add_backend(backend2).unwrap();
-        assert_eq!(director.backends.read().unwrap().len(), 2);
+    #[test]
+    fn test_director_histograms() {
+        let stats = Arc::new(DirectorStats::default());
+        let (director, _) = Director::new(stats.clone(), DirectorConfig::default());
+        let b1 = test_backend("b1", SocketAddr::from(([127, 0, 0, 1], 8080)), 1);
+        director.add_backend(b1.clone()).unwrap();
+
+        director.report(b1.vcl_backend, 0, Some(5)).unwrap();
+        director.report(b1.vcl_backend, 7, Some(20)).unwrap();
+        director.report(b1.vcl_backend, 600, Some(2000)).unwrap();
+        director.get_backend(|_| true, &[], None).unwrap();
+
+        let counts = |buckets: [&AtomicU64; 7]| buckets.map(|b| b.load(Ordering::Relaxed));
+        assert_eq!(counts(stats.probe_rif_buckets()), [1, 0, 1, 0, 0, 0, 1]);
+        assert_eq!(counts(stats.probe_latency_buckets()), [1, 0, 1, 0, 0, 0, 1]);
+        // Selection used a probe a few microseconds old
+        assert_eq!(counts(stats.time_to_use_buckets()), [1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(counts(stats.probe_rtt_buckets()), [0; 7]);
+    }
 
-        // Remove the first backend
-        director.remove_backend(backend1_ref);
+    #[test]
+    fn test_director_rebind_backend() {
+        let stats = Arc::new(DirectorStats::default());
+        let config = DirectorConfig {
+            slow_start: Duration::from_secs(60),
+            ..Default::default()
+        };
+        let (director, _) = Director::new(stats, config);
+        let addr = SocketAddr::from(([127, 0, 0, 1], 8080));
+        let old = test_backend("b1", addr, 1);
+        director.add_backend(old.clone()).unwrap();
+        director
+            .probe_table
+            .add_result(ProbeResult::new(SystemTime::now(), 3, 10, old.clone()));
+        director
+            .drain_backend(old.vcl_backend, Duration::from_secs(60), false)
+            .unwrap();
+
+        // The same server through another VCL's backend keeps the probes and state
+        let new = test_backend("b1", addr, 2);
+        director.add_backend(new.clone()).unwrap();
         assert_eq!(director.backends.read().unwrap().len(), 1);
-
-        // Verify the remaining backend
-        assert_eq!(director.backends.read().unwrap()[0].name, "test2");
-        assert_eq!(
-            director.backends.read().unwrap()[0].address,
-            SocketAddr::from(([127, 0, 0, 2], 8081))
-        );
+        assert_eq!(director.find_server(&old), Some(new.clone()));
+        assert!(director
+            .probe_table
+            .entries()
+            .iter()
+            .all(|p| p.backend == new));
+        assert_eq!(director.draining(), [new.vcl_backend]);
+        assert!(director.slow_start.progress(&new, Instant::now()).is_some());
+        assert!(matches!(
+            director.drain_backend(old.vcl_backend, Duration::from_secs(1), false),
+            Err(DirectorError::UnknownBackend)
+        ));
+
+        // A probe sent through the old backend lands on the new one
+        director.add_probe_result(old.clone(), 5, 20);
+        let entries = director.probe_table.entries();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].backend, new);
+        assert_eq!(entries[0].rif, 5);
+
+        // Another server of the same name is another backend
+        let other = test_backend("b1", SocketAddr::from(([127, 0, 0, 2], 8080)), 3);
+        director.add_backend(other).unwrap();
+        assert_eq!(director.backends.read().unwrap().len(), 2);
     }
 
     #[test]
-    fn test_director_get_backend() {
+    fn test_director_weighted_fallback() {
         let stats = Arc::new(DirectorStats::default());
-        let (director, _) = Director::new(stats);
-        let backend = create_test_backend("test1", SocketAddr::from(([127, 0, 0, 1], 8080)), 1);
-        director.add_backend(backend).unwrap();
-        let (backend, _from_table) = director.get_backend().unwrap();
-        assert_eq!(backend.name, "test1");
+        let (director, _) = Director::new(stats, DirectorConfig::default());
+        let small = test_backend("small", SocketAddr::from(([127, 0, 0, 1], 8080)), 1);
+        let big = test_backend("big", SocketAddr::from(([127, 0, 0, 1], 8081)), 2);
+        director.add_backend(small).unwrap();
+        director.add_backend(big.with_weight(9.0)).unwrap();
+
+        let big_count = (0..1000)
+            .filter(|_| director.get_backend(|_| true, &[], None).unwrap().0.name == "big")
+            .count();
+        assert!(
+            (800..=980).contains(&big_count),
+            "big backend selected {} times out of 1000",
+            big_count
+        );
+
+        let zero = test_backend("zero", SocketAddr::from(([127, 0, 0, 1], 8082)), 3);
+        assert!(matches!(
+            director.add_backend(zero.with_weight(0.0)),
+            Err(DirectorError::InvalidConfig(_))
+        ));
+        let huge = test_backend("huge", SocketAddr::from(([127, 0, 0, 1], 8083)), 4);
+        assert!(matches!(
+            director.add_backend(huge.clone().with_weight(1e12)),
+            Err(DirectorError::InvalidConfig(_))
+        ));
+        assert!(director.add_backend(huge.with_weight(MAX_WEIGHT)).is_ok());
     }
 
     struct TestServer {
@@ -466,6 +3717,20 @@ mod tests {
 
     impl TestServer {
         fn new(in_flight: usize, latency: usize) -> Self {
+            let response = format!(
+                "HTTP/1.1 200 OK\r\n\
+                 X-In-Flight: {}\r\n\
+                 X-Estimated-Latency: {}\r\n\
+                 Content-Length: 2\r\n\
+                 \r\n\
+                 OK",
+                in_flight, latency
+            );
+            Self::with_response(response, in_flight, latency)
+        }
+
+        /// Answers every request with `response`, a raw HTTP/1.1 response.
+        fn with_response(response: String, in_flight: usize, latency: usize) -> Self {
             // Bind to a random high port
             let listener = TcpListener::bind("127.0.0.1:0").unwrap();
             let addr = listener.local_addr().unwrap();
@@ -485,15 +3750,6 @@ mod tests {
                     }
 
                     // Send response
-                    let response = format!(
-                        "HTTP/1.1 200 OK\r\n\
-                         X-In-Flight: {}\r\n\
-                         X-Estimated-Latency: {}\r\n\
-                         Content-Length: 2\r\n\
-                         \r\n\
-                         OK",
-                        in_flight, latency
-                    );
                     stream.write_all(response.as_bytes()).unwrap();
                 }
             });
@@ -527,14 +3783,14 @@ mod tests {
         ];
 
         let stats = Arc::new(DirectorStats::default());
-        let (director, probe_loop) = Director::new(stats);
+        let (director, probe_loop) = Director::new(stats, DirectorConfig::default());
 
         thread::scope(|s| {
             s.spawn(probe_loop);
 
             // Add backends
             for (idx, server) in servers.iter().enumerate() {
-                let backend = create_test_backend(&format!("test{}", idx), server.addr, idx as u32);
+                let backend = test_backend(&format!("test{}", idx), server.addr, idx);
                 director.add_backend(backend).unwrap();
             }
 
@@ -549,7 +3805,7 @@ mod tests {
             }
 
             // The director should prefer the backend with lowest in_flight count
-            let (selected, _from_table) = director.get_backend().unwrap();
+            let (selected, _selection) = director.get_backend(|_| true, &[], None).unwrap();
             assert_eq!(selected.address, servers[0].addr);
 
             // Drop director so probe loop can exit and scope can complete
@@ -557,6 +3813,165 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_director_pause_resume_stop() {
+        let server = TestServer::new(5, 100);
+        let stats = Arc::new(DirectorStats::default());
+        let (director, probe_loop) = Director::new(stats.clone(), DirectorConfig::default());
+        director.set_probe_thread(thread::spawn(probe_loop));
+        let backend = test_backend("test", server.addr, 1);
+        director.add_backend(backend).unwrap();
+
+        // No probes while paused, even when asked for
+        director.pause();
+        director.trigger_probe();
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(stats.probes_sent.load(Ordering::Relaxed), 0);
+
+        // Resuming seeds the table right away
+        director.resume();
+        thread::sleep(Duration::from_millis(500));
+        assert!(stats.probes_sent.load(Ordering::Relaxed) > 0);
+        assert!(director.is_healthy());
+
+        // Stopping returns once the probe thread is gone
+        director.stop();
+        assert!(director.probe_thread.lock().unwrap().is_none());
+    }
+
+    fn json_response(body: &str) -> String {
+        format!(
+            "HTTP/1.1 200 OK\r\n\
+             Content-Type: application/json\r\n\
+             Content-Length: {}\r\n\
+             \r\n\
+             {}",
+            body.len(),
+            body
+        )
+    }
+
+    #[test]
+    fn test_director_probing_json_body() {
+        let servers = [
+            TestServer::with_response(json_response(r#"{"load": {"rif": 2, "ms": 20}}"#), 2, 20),
+            TestServer::new(4, 40),
+            TestServer::with_response(json_response(r#"{"rif": 2}"#), 0, 0),
+        ];
+
+        let stats = Arc::new(DirectorStats::default());
+        let (director, probe_loop) = Director::new(stats.clone(), DirectorConfig::default());
+        director
+            .set_probe_format("auto", Some("load.rif"), Some("load.ms"))
+            .unwrap();
+        assert!(matches!(
+            director.set_probe_format("xml", None, None),
+            Err(DirectorError::InvalidConfig(_))
+        ));
+
+        thread::scope(|s| {
+            s.spawn(probe_loop);
+
+            for (idx, server) in servers.iter().enumerate() {
+                let backend = test_backend(&format!("test{}", idx), server.addr, idx);
+                director.add_backend(backend).unwrap();
+            }
+            director.trigger_probe();
+            thread::sleep(Duration::from_secs(1));
+
+            // JSON and header probes both land in the table, the bad body doesn't
+            let mut entries: Vec<_> = director
+                .probe_table
+                .entries()
+                .iter()
+                .map(|p| (p.backend.name.clone(), p.rif, p.est_latency))
+                .collect();
+            entries.sort();
+            assert_eq!(
+                entries,
+                [("test0".to_string(), 2, 20), ("test1".to_string(), 4, 40)]
+            );
+            assert!(stats.probes_invalid_body.load(Ordering::Relaxed) >= 1);
+
+            drop(director);
+        });
+    }
+
+    #[test]
+    fn test_director_probing_black_holed_backend() {
+        // Accepts connections but never answers
+        let black_hole = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = TestServer::new(5, 100);
+
+        let stats = Arc::new(DirectorStats::default());
+        let config = DirectorConfig {
+            probe_timeout: Duration::from_millis(300),
+            ..DirectorConfig::default()
+        };
+        let (director, probe_loop) = Director::new(stats.clone(), config);
+
+        thread::scope(|s| {
+            s.spawn(probe_loop);
+
+            director
+                .add_backend(test_backend(
+                    "black-hole",
+                    black_hole.local_addr().unwrap(),
+                    1,
+                ))
+                .unwrap();
+            director
+                .add_backend(test_backend("test", server.addr, 2))
+                .unwrap();
+
+            director.trigger_probe();
+
+            // The responsive backend is not held up by the black-holed one
+            thread::sleep(Duration::from_millis(150));
+            assert_eq!(director.probe_table.len(), 1);
+            assert_eq!(stats.probes_timeout.load(Ordering::Relaxed), 0);
+
+            thread::sleep(Duration::from_millis(500));
+            assert_eq!(stats.probes_timeout.load(Ordering::Relaxed), 1);
+            assert_eq!(stats.probes_in_flight.load(Ordering::Relaxed), 0);
+
+            drop(director);
+        });
+    }
+
+    #[test]
+    fn test_director_probe_rate_limit() {
+        let servers: Vec<_> = (0..20).map(|_| TestServer::new(5, 100)).collect();
+
+        let stats = Arc::new(DirectorStats::default());
+        let config = DirectorConfig {
+            max_probe_rate: 10.0,
+            ..DirectorConfig::default()
+        };
+        let (director, probe_loop) = Director::new(stats.clone(), config);
+
+        thread::scope(|s| {
+            s.spawn(probe_loop);
+
+            for (idx, server) in servers.iter().enumerate() {
+                let backend = test_backend(&format!("test{}", idx), server.addr, idx);
+                director.add_backend(backend).unwrap();
+            }
+
+            // 300 probes are due, but only a second's worth may be sent
+            for _ in 0..100 {
+                director.get_backend(|_| true, &[], None).unwrap();
+            }
+            thread::sleep(Duration::from_millis(300));
+
+            let sent = stats.probes_sent.load(Ordering::Relaxed);
+            assert!((10..=15).contains(&sent), "sent {} probes", sent);
+            assert!(stats.probes_rate_limited.load(Ordering::Relaxed) >= 285);
+
+            drop(director);
+        });
+    }
+
     #[test]
     fn test_director_probe_table_health() {
         let mut servers = Vec::new();
@@ -568,13 +3983,13 @@ mod tests {
         }
 
         let stats = Arc::new(DirectorStats::default());
-        let (director, probe_loop) = Director::new(stats);
+        let (director, probe_loop) = Director::new(stats, DirectorConfig::default());
 
         thread::scope(|s| {
             s.spawn(probe_loop);
 
             for (idx, server) in servers.iter().enumerate() {
-                let backend = create_test_backend(&format!("test{}", idx), server.addr, idx as u32);
+                let backend = test_backend(&format!("test{}", idx), server.addr, idx);
                 director.add_backend(backend).unwrap();
             }
 
@@ -586,7 +4001,7 @@ mod tests {
             }
 
             for i in 0..1000 {
-                let (backend, _from_table) = director.get_backend().unwrap();
+                let (backend, _selection) = director.get_backend(|_| true, &[], None).unwrap();
                 assert!(
                     backend.name.starts_with("test"),
                     "Backend name should start with 'test'"
@@ -0,0 +1,323 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use varnish::ffi::VCL_BACKEND;
+
+use crate::backend::Backend;
+
+/// Number of recent probe outcomes the failure rate is computed over.
+pub const FAILURE_RATE_WINDOW: usize = 20;
+/// Outcomes needed before the failure rate is trusted.
+pub const FAILURE_RATE_MIN_SAMPLES: usize = 10;
+/// Ejections never last longer than this, however often a backend is ejected.
+pub const MAX_EJECTION_TIME: Duration = Duration::from_secs(300);
+
+/// When backends failing their probes are taken out of selection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EjectionConfig {
+    /// Consecutive probe failures that eject a backend; 0 disables
+    pub consecutive_failures: usize,
+    /// Share of failed probes among the recent ones that ejects a
+    /// backend; 0 disables
+    pub failure_rate: f64,
+    /// How long a first ejection lasts; it doubles with each ejection
+    /// following closely on the previous one
+    pub ejection_time: Duration,
+    /// Largest share of the backends ejected at once, in percent
+    pub max_ejected_percent: usize,
+}
+
+impl Default for EjectionConfig {
+    fn default() -> Self {
+        Self {
+            consecutive_failures: 0,
+            failure_rate: 0.0,
+            ejection_time: Duration::from_secs(30),
+            max_ejected_percent: 50,
+        }
+    }
+}
+
+impl EjectionConfig {
+    fn is_enabled(&self) -> bool {
+        self.consecutive_failures > 0 || self.failure_rate > 0.0
+    }
+}
+
+/// What recording a probe outcome did to its backend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ejection {
+    /// Nothing changed
+    None,
+    /// The backend is ejected for this long
+    Ejected(Duration),
+    /// The backend should have been ejected, but too many others already are
+    Prevented,
+}
+
+#[derive(Debug)]
+struct Health {
+    backend: Backend,
+    consecutive_failures: usize,
+    // Recent outcomes, true for a success
+    recent: VecDeque<bool>,
+    // Ejections so far, which the backoff grows with
+    ejections: u32,
+    ejected_until: Option<Instant>,
+    // When the last ejection ended, for the backoff to wind down
+    returned_at: Option<Instant>,
+}
+
+/// Ejects backends whose probes keep failing, for a backoff period
+/// growing exponentially with repeated ejections.
+#[derive(Debug)]
+pub struct OutlierDetector {
+    config: EjectionConfig,
+    // Keyed by the backend's VCL_BACKEND address
+    backends: Mutex<HashMap<usize, Health>>,
+}
+
+fn key(backend: &Backend) -> usize {
+    backend.vcl_backend.0 as usize
+}
+
+impl OutlierDetector {
+    pub fn new(config: EjectionConfig) -> Self {
+        Self {
+            config,
+            backends: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records a probe outcome, ejecting the backend if it crosses a threshold.
+    ///
+    /// # Arguments
+    /// * `backend` - The probed backend
+    /// * `success` - Whether the backend answered the probe
+    /// * `pool_size` - Number of backends, for the `max_ejected_percent` guard
+    /// * `now` - The current time
+    pub fn record(
+        &self,
+        backend: &Backend,
+        success: bool,
+        pool_size: usize,
+        now: Instant,
+    ) -> Ejection {
+        if !self.config.is_enabled() {
+            return Ejection::None;
+        }
+        let Ok(mut backends) = self.backends.lock() else {
+            return Ejection::None;
+        };
+        let ejected = backends
+            .values()
+            .filter(|h| h.ejected_until.is_some_and(|until| until > now))
+            .count();
+
+        let health = backends.entry(key(backend)).or_insert_with(|| Health {
+            backend: backend.clone(),
+            consecutive_failures: 0,
+            recent: VecDeque::with_capacity(FAILURE_RATE_WINDOW),
+            ejections: 0,
+            ejected_until: None,
+            returned_at: None,
+        });
+        if health.recent.len() == FAILURE_RATE_WINDOW {
+            health.recent.pop_front();
+        }
+        health.recent.push_back(success);
+        if success {
+            health.consecutive_failures = 0;
+            return Ejection::None;
+        }
+        health.consecutive_failures += 1;
+
+        if health.ejected_until.is_some_and(|until| until > now) {
+            return Ejection::None;
+        }
+        let failures = health.recent.iter().filter(|ok| !**ok).count();
+        let too_many_failures = self.config.consecutive_failures > 0
+            && health.consecutive_failures >= self.config.consecutive_failures;
+        let failure_rate_too_high = self.config.failure_rate > 0.0
+            && health.recent.len() >= FAILURE_RATE_MIN_SAMPLES
+            && failures as f64 / health.recent.len() as f64 >= self.config.failure_rate;
+        if !(too_many_failures || failure_rate_too_high) {
+            return Ejection::None;
+        }
+
+        if ejected + 1 > pool_size * self.config.max_ejected_percent / 100 {
+            return Ejection::Prevented;
+        }
+
+        // The backoff winds down by one step for each ejection time spent back in the pool
+        if let Some(returned_at) = health.returned_at {
+            let calm = now.saturating_duration_since(returned_at).as_secs_f64()
+                / self.config.ejection_time.as_secs_f64();
+            health.ejections = health.ejections.saturating_sub(calm as u32);
+        }
+        let duration = self
+            .config
+            .ejection_time
+            .saturating_mul(2u32.saturating_pow(health.ejections))
+            .min(MAX_EJECTION_TIME.max(self.config.ejection_time));
+        health.ejections += 1;
+        health.ejected_until = Some(now + duration);
+        health.consecutive_failures = 0;
+        health.recent.clear();
+        Ejection::Ejected(duration)
+    }
+
+    /// Ends the ejections that are over.
+    ///
+    /// # Returns
+    /// The number of backends that returned to the pool
+    pub fn return_expired(&self, now: Instant) -> usize {
+        let Ok(mut backends) = self.backends.lock() else {
+            return 0;
+        };
+        let mut returned = 0;
+        for health in backends.values_mut() {
+            if health.ejected_until.is_some_and(|until| until <= now) {
+                health.ejected_until = None;
+                health.returned_at = Some(now);
+                returned += 1;
+            }
+        }
+        returned
+    }
+
+    /// Returns the backends currently ejected, with the time they return.
+    pub fn ejected(&self) -> Vec<(VCL_BACKEND, Instant)> {
+        self.backends
+            .lock()
+            .map(|backends| {
+                backends
+                    .values()
+                    .filter_map(|h| h.ejected_until.map(|until| (h.backend.vcl_backend, until)))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Carries what is known of `old` over to `new`, the same server
+    /// through another VCL's backend.
+    pub fn rebind_backend(&self, old: &Backend, new: &Backend) {
+        if let Ok(mut backends) = self.backends.lock() {
+            if let Some(mut health) = backends.remove(&key(old)) {
+                health.backend = new.clone();
+                backends.insert(key(new), health);
+            }
+        }
+    }
+
+    /// Forgets everything about a backend.
+    pub fn remove_backend(&self, backend: &Backend) {
+        if let Ok(mut backends) = self.backends.lock() {
+            backends.remove(&key(backend));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+    use crate::backend::test_backend;
+
+    fn detector(consecutive_failures: usize, failure_rate: f64) -> OutlierDetector {
+        OutlierDetector::new(EjectionConfig {
+            consecutive_failures,
+            failure_rate,
+            ejection_time: Duration::from_secs(10),
+            max_ejected_percent: 50,
+        })
+    }
+
+    #[test]
+    fn test_ejection_on_consecutive_failures() {
+        let detector = detector(3, 0.0);
+        let backend = test_backend("b1", SocketAddr::from(([127, 0, 0, 1], 8080)), 1);
+        let now = Instant::now();
+
+        assert_eq!(detector.record(&backend, false, 4, now), Ejection::None);
+        assert_eq!(detector.record(&backend, false, 4, now), Ejection::None);
+        // A success resets the count
+        assert_eq!(detector.record(&backend, true, 4, now), Ejection::None);
+        assert_eq!(detector.record(&backend, false, 4, now), Ejection::None);
+        assert_eq!(detector.record(&backend, false, 4, now), Ejection::None);
+        assert_eq!(
+            detector.record(&backend, false, 4, now),
+            Ejection::Ejected(Duration::from_secs(10))
+        );
+        assert_eq!(detector.ejected().len(), 1);
+
+        assert_eq!(detector.return_expired(now + Duration::from_secs(5)), 0);
+        assert_eq!(detector.return_expired(now + Duration::from_secs(10)), 1);
+        assert!(detector.ejected().is_empty());
+    }
+
+    #[test]
+    fn test_ejection_backoff() {
+        let detector = detector(1, 0.0);
+        let backend = test_backend("b1", SocketAddr::from(([127, 0, 0, 1], 8080)), 1);
+        let mut now = Instant::now();
+
+        // Each ejection right after the previous one lasts twice as long
+        for secs in [10, 20, 40, 80, 160, 300, 300] {
+            assert_eq!(
+                detector.record(&backend, false, 2, now),
+                Ejection::Ejected(Duration::from_secs(secs))
+            );
+            now += Duration::from_secs(secs);
+            assert_eq!(detector.return_expired(now), 1);
+        }
+
+        // The backoff winds down while the backend behaves
+        now += Duration::from_secs(70);
+        assert_eq!(
+            detector.record(&backend, false, 2, now),
+            Ejection::Ejected(Duration::from_secs(10))
+        );
+    }
+
+    #[test]
+    fn test_ejection_on_failure_rate() {
+        let detector = detector(0, 0.5);
+        let backend = test_backend("b1", SocketAddr::from(([127, 0, 0, 1], 8080)), 1);
+        let now = Instant::now();
+
+        // Not enough samples yet
+        for _ in 0..4 {
+            assert_eq!(detector.record(&backend, false, 2, now), Ejection::None);
+            assert_eq!(detector.record(&backend, true, 2, now), Ejection::None);
+        }
+        assert_eq!(detector.record(&backend, true, 2, now), Ejection::None);
+        assert!(matches!(
+            detector.record(&backend, false, 2, now),
+            Ejection::Ejected(_)
+        ));
+    }
+
+    #[test]
+    fn test_ejection_max_percent() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 8080));
+        let detector = detector(1, 0.0);
+        let now = Instant::now();
+        let (b1, b2) = (test_backend("b1", addr, 1), test_backend("b2", addr, 2));
+
+        // Half of 3 backends is 1
+        assert!(matches!(
+            detector.record(&b1, false, 3, now),
+            Ejection::Ejected(_)
+        ));
+        assert_eq!(detector.record(&b2, false, 3, now), Ejection::Prevented);
+        // A single backend is never ejected
+        let single = OutlierDetector::new(EjectionConfig {
+            consecutive_failures: 1,
+            ..Default::default()
+        });
+        assert_eq!(single.record(&b1, false, 1, now), Ejection::Prevented);
+    }
+}
@@ -0,0 +1,130 @@
+use crate::backend::Backend;
+
+/// Points placed on the ring per unit of backend weight.
+const POINTS_PER_WEIGHT: f64 = 160.0;
+
+/// A consistent hash ring over a director's backends.
+///
+/// Each backend gets a number of points proportional to its weight, hashed
+/// from its name, so the same key maps to the same backend across restarts
+/// and Varnish instances, and adding or removing a backend only moves the
+/// keys it owns.
+#[derive(Debug, Default)]
+pub struct HashRing {
+    // (hash, index into the backend list), sorted by hash
+    points: Vec<(u64, usize)>,
+    backends: usize,
+}
+
+impl HashRing {
+    /// Builds the ring for `backends`; lookups return indices into that slice.
+    pub fn new(backends: &[Backend]) -> Self {
+        let mut points = Vec::new();
+        for (idx, backend) in backends.iter().enumerate() {
+            let count = (backend.weight * POINTS_PER_WEIGHT).round().max(1.0) as usize;
+            for point in 0..count {
+                let name = format!("{}#{}", backend.name, point);
+                points.push((hash(name.as_bytes()), idx));
+            }
+        }
+        points.sort_unstable();
+
+        Self {
+            points,
+            backends: backends.len(),
+        }
+    }
+
+    /// Returns the backend indices in ring order, starting at the position of
+    /// `key`, each backend once. The first one is the key's owner, the next
+    /// ones are where the key goes if its owner can't take it.
+    pub fn lookup(&self, key: &str) -> impl Iterator<Item = usize> + '_ {
+        let key_hash = hash(key.as_bytes());
+        let start = self.points.partition_point(|(h, _)| *h < key_hash);
+        let mut seen = vec![false; self.backends];
+
+        self.points[start..]
+            .iter()
+            .chain(&self.points[..start])
+            .filter_map(move |&(_, idx)| {
+                let first = !seen[idx];
+                seen[idx] = true;
+                first.then_some(idx)
+            })
+    }
+}
+
+/// FNV-1a followed by a 64-bit finalizer: stable across builds, unlike
+/// `DefaultHasher`, and well spread even for similar inputs.
+fn hash(data: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for byte in data {
+        h ^= u64::from(*byte);
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51afd7ed558ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ceb9fe1a85ec53);
+    h ^ (h >> 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+    use crate::backend::test_backend;
+
+    fn owners(ring: &HashRing, keys: usize) -> Vec<usize> {
+        (0..keys)
+            .map(|k| ring.lookup(&format!("/key/{}", k)).next().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_hash_ring_lookup_visits_each_backend_once() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 8080));
+        let backends: Vec<_> = (1..=4)
+            .map(|i| test_backend(&format!("b{}", i), addr, i))
+            .collect();
+        let ring = HashRing::new(&backends);
+
+        let mut order: Vec<usize> = ring.lookup("/some/url").collect();
+        assert_eq!(order.len(), 4);
+        order.sort();
+        assert_eq!(order, [0, 1, 2, 3]);
+
+        assert_eq!(HashRing::new(&[]).lookup("/some/url").count(), 0);
+    }
+
+    #[test]
+    fn test_hash_ring_spreads_by_weight() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 8080));
+        let backends = [
+            test_backend("small", addr, 1),
+            test_backend("big", addr, 2).with_weight(3.0),
+        ];
+        let ring = HashRing::new(&backends);
+
+        let big = owners(&ring, 10000).iter().filter(|&&i| i == 1).count();
+        assert!((6500..=8500).contains(&big), "big owns {} keys", big);
+    }
+
+    #[test]
+    fn test_hash_ring_is_consistent() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 8080));
+        let backends: Vec<_> = (1..=5)
+            .map(|i| test_backend(&format!("b{}", i), addr, i))
+            .collect();
+        let before = owners(&HashRing::new(&backends), 1000);
+
+        // Dropping the last backend only moves the keys it owned
+        let after = owners(&HashRing::new(&backends[..4]), 1000);
+        for (old, new) in before.iter().zip(&after) {
+            if *old != 4 {
+                assert_eq!(old, new);
+            }
+        }
+    }
+}
@@ -1,28 +1,68 @@
 mod backend;
+mod breaker;
+mod calibration;
+mod ejection;
+mod hashring;
 mod probe;
+mod prober;
+mod registry;
+mod scheduler;
+mod slowstart;
+mod task;
+mod vdi;
 
 #[path = "director.rs"]
 mod prequal_director;
 
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::ffi::c_void;
 use std::sync::atomic::Ordering;
-use std::sync::Arc;
-use std::thread;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::{ptr, thread};
 
 pub use backend::Backend;
-pub use prequal_director::{Director, DirectorStats};
+pub use breaker::BreakerConfig;
+pub use ejection::EjectionConfig;
+pub use prequal_director::{
+    BackendStats, Decision, Director, DirectorConfig, DirectorError, DirectorStats, FallbackMode,
+    LoadSignal, LogLevel, Selection, ZoneStats,
+};
+use prober::ProbeSpec;
+use registry::Attachment;
+pub use slowstart::SlowStartCurve;
+use task::TriedBackends;
 use varnish::ffi::VCL_BACKEND;
-use varnish::vcl::{Ctx, LogTag, VclError};
+use varnish::vcl::{Ctx, Event, LogTag, VclError};
 use varnish::Vsc;
+use vdi::{NativeDirector, VclDirector};
 
 // director is a very thin wrapper around a Director, to expose it to VCL
 #[allow(non_camel_case_types)]
 pub struct director {
     inner: Arc<Director>,
+    // Registered with Varnish so the director is resolved at fetch time
+    vdi: NativeDirector<PrequalVdi>,
+}
+
+/// The native director callbacks, resolving to a backend picked by the Director.
+struct PrequalVdi {
+    inner: Arc<Director>,
+    // This VCL's hold on a shared director, translating between its backends
+    // and the director's
+    shared: Option<Arc<Attachment>>,
     // Vsc exposes stats to varnishstat; we sync from Director's Arc<DirectorStats>
     vsc: Vsc<DirectorStats>,
+    name: String,
+    log_level: LogLevel,
+    // One segment per backend of this VCL, by backend name
+    backend_vscs: Mutex<HashMap<String, Vsc<BackendStats>>>,
+    // One segment per zone a backend was selected in, by zone name
+    zone_vscs: Mutex<HashMap<String, Vsc<ZoneStats>>>,
 }
 
-impl director {
+impl PrequalVdi {
     /// Syncs stats from Director's Arc<DirectorStats> to Vsc for varnishstat visibility.
     /// Called on each request to keep varnishstat reasonably up-to-date.
     fn sync_stats(&self) {
@@ -36,10 +76,61 @@ impl director {
             src.selected_from_table.load(Ordering::Relaxed),
             Ordering::Relaxed,
         );
+        self.vsc.selected_by_local_load.store(
+            src.selected_by_local_load.load(Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
         self.vsc.fallback_random.store(
             src.fallback_random.load(Ordering::Relaxed),
             Ordering::Relaxed,
         );
+        self.vsc.fallback_round_robin.store(
+            src.fallback_round_robin.load(Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+        self.vsc.fallback_least_local_connections.store(
+            src.fallback_least_local_connections.load(Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+        self.vsc.fallback_power_of_two.store(
+            src.fallback_power_of_two.load(Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+        self.vsc.fallback_last_known.store(
+            src.fallback_last_known.load(Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+        self.vsc
+            .hash_affinity
+            .store(src.hash_affinity.load(Ordering::Relaxed), Ordering::Relaxed);
+        self.vsc
+            .hash_bounded
+            .store(src.hash_bounded.load(Ordering::Relaxed), Ordering::Relaxed);
+        self.vsc
+            .hash_spilled
+            .store(src.hash_spilled.load(Ordering::Relaxed), Ordering::Relaxed);
+        self.vsc
+            .zone_local
+            .store(src.zone_local.load(Ordering::Relaxed), Ordering::Relaxed);
+        self.vsc.zone_spillover.store(
+            src.zone_spillover.load(Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+        self.vsc.skipped_unhealthy.store(
+            src.skipped_unhealthy.load(Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+        self.vsc
+            .skipped_tried
+            .store(src.skipped_tried.load(Ordering::Relaxed), Ordering::Relaxed);
+        self.vsc.skipped_draining.store(
+            src.skipped_draining.load(Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+        self.vsc.skipped_slow_start.store(
+            src.skipped_slow_start.load(Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
         self.vsc
             .probes_sent
             .store(src.probes_sent.load(Ordering::Relaxed), Ordering::Relaxed);
@@ -54,11 +145,81 @@ impl director {
             src.probes_missing_headers.load(Ordering::Relaxed),
             Ordering::Relaxed,
         );
+        self.vsc.probes_invalid_body.store(
+            src.probes_invalid_body.load(Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+        self.vsc.probes_timeout.store(
+            src.probes_timeout.load(Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+        self.vsc.probes_skipped.store(
+            src.probes_skipped.load(Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+        self.vsc.probes_rate_limited.store(
+            src.probes_rate_limited.load(Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+        self.vsc.probes_dropped.store(
+            src.probes_dropped.load(Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+
+        self.vsc
+            .reports
+            .store(src.reports.load(Ordering::Relaxed), Ordering::Relaxed);
+        self.vsc.reports_invalid.store(
+            src.reports_invalid.load(Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+        self.vsc
+            .observations
+            .store(src.observations.load(Ordering::Relaxed), Ordering::Relaxed);
+        self.vsc
+            .ejections
+            .store(src.ejections.load(Ordering::Relaxed), Ordering::Relaxed);
+        self.vsc
+            .unejections
+            .store(src.unejections.load(Ordering::Relaxed), Ordering::Relaxed);
+        self.vsc.ejections_prevented.store(
+            src.ejections_prevented.load(Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+        self.vsc
+            .results
+            .store(src.results.load(Ordering::Relaxed), Ordering::Relaxed);
+        self.vsc
+            .result_errors
+            .store(src.result_errors.load(Ordering::Relaxed), Ordering::Relaxed);
+        self.vsc.breaker_opened.store(
+            src.breaker_opened.load(Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+        self.vsc.breaker_closed.store(
+            src.breaker_closed.load(Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
 
         // Sync gauges (computed in probe loop)
+        self.vsc.probes_in_flight.store(
+            src.probes_in_flight.load(Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
         self.vsc
             .backends
             .store(src.backends.load(Ordering::Relaxed), Ordering::Relaxed);
+        self.vsc.backends_draining.store(
+            src.backends_draining.load(Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+        self.vsc.backends_ejected.store(
+            src.backends_ejected.load(Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+        self.vsc
+            .breakers_open
+            .store(src.breakers_open.load(Ordering::Relaxed), Ordering::Relaxed);
         self.vsc.probe_table_size.store(
             src.probe_table_size.load(Ordering::Relaxed),
             Ordering::Relaxed,
@@ -83,6 +244,261 @@ impl director {
         self.vsc
             .probe_max_rif
             .store(src.probe_max_rif.load(Ordering::Relaxed), Ordering::Relaxed);
+
+        // Sync histograms
+        let buckets = self.vsc.histograms().into_iter().flatten();
+        for (dst, src) in buckets.zip(src.histograms().into_iter().flatten()) {
+            dst.store(src.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+
+        self.sync_backend_stats();
+        self.sync_zone_stats();
+    }
+
+    /// Syncs the per-backend stats to their Vsc segments, dropping those of
+    /// backends the Director removed by itself, e.g. at the end of a drain.
+    fn sync_backend_stats(&self) {
+        let Ok(mut vscs) = self.backend_vscs.lock() else {
+            return;
+        };
+        let stats = self.inner.backend_stats();
+        vscs.retain(|name, _| stats.iter().any(|(backend, _)| backend.name == *name));
+
+        for (backend, src) in &stats {
+            let Some(vsc) = vscs.get(&backend.name) else {
+                continue;
+            };
+            vsc.selected
+                .store(src.selected.load(Ordering::Relaxed), Ordering::Relaxed);
+            vsc.probes_sent
+                .store(src.probes_sent.load(Ordering::Relaxed), Ordering::Relaxed);
+            vsc.probes_success.store(
+                src.probes_success.load(Ordering::Relaxed),
+                Ordering::Relaxed,
+            );
+            vsc.probes_fail
+                .store(src.probes_fail.load(Ordering::Relaxed), Ordering::Relaxed);
+            vsc.evictions
+                .store(src.evictions.load(Ordering::Relaxed), Ordering::Relaxed);
+            vsc.last_rif
+                .store(src.last_rif.load(Ordering::Relaxed), Ordering::Relaxed);
+            vsc.last_latency
+                .store(src.last_latency.load(Ordering::Relaxed), Ordering::Relaxed);
+            vsc.probe_age
+                .store(src.probe_age.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+    }
+
+    /// Syncs the per-zone stats to their Vsc segments,
+    /// `prequal_zone.<director>.<zone>`, created with the zone's first
+    /// selection.
+    fn sync_zone_stats(&self) {
+        let Ok(mut vscs) = self.zone_vscs.lock() else {
+            return;
+        };
+        for (zone, src) in self.inner.zone_stats() {
+            let vsc = vscs.entry(zone).or_insert_with_key(|zone| {
+                Vsc::new("prequal_zone", &format!("{}.{}", self.name, zone))
+            });
+            vsc.selected
+                .store(src.selected.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+    }
+
+    /// Creates the Vsc segment of a backend, `prequal_backend.<director>.<backend>`.
+    fn add_backend_vsc(&self, backend: &str) {
+        if let Ok(mut vscs) = self.backend_vscs.lock() {
+            vscs.entry(backend.to_string()).or_insert_with(|| {
+                Vsc::new("prequal_backend", &format!("{}.{}", self.name, backend))
+            });
+        }
+    }
+
+    /// Removes the Vsc segment of a backend.
+    fn remove_backend_vsc(&self, backend: &str) {
+        if let Ok(mut vscs) = self.backend_vscs.lock() {
+            vscs.remove(backend);
+        }
+    }
+}
+
+/// The directors a VCL created, for its temperature events to reach their
+/// probe threads.
+#[derive(Default)]
+pub struct VclDirectors {
+    directors: Vec<(Arc<Director>, Option<Arc<Attachment>>)>,
+}
+
+impl VclDirectors {
+    /// Pauses probing while the VCL is cold; a shared director keeps
+    /// probing as long as another VCL using it is warm.
+    fn set_warm(&self, warm: bool) {
+        for (director, shared) in &self.directors {
+            match shared {
+                Some(shared) => shared.set_warm(warm),
+                None if warm => director.resume(),
+                None => director.pause(),
+            }
+        }
+    }
+
+    /// Stops the probe threads of the VCL's own directors; those of shared
+    /// directors stop with their last VCL.
+    fn discard(&mut self) {
+        for (director, shared) in self.directors.drain(..) {
+            if shared.is_none() {
+                director.stop();
+            }
+        }
+    }
+}
+
+/// Converts a VCL INT argument to a count, rejecting negative values.
+fn count_arg(arg: &str, value: i64) -> Result<usize, VclError> {
+    usize::try_from(value)
+        .map_err(|_| VclError::new(format!("{} must not be negative (got {})", arg, value)))
+}
+
+impl PrequalVdi {
+    /// Returns the director's backend for one of this VCL's backends, which
+    /// differ when the director is shared.
+    fn to_director(&self, vcl_backend: VCL_BACKEND) -> VCL_BACKEND {
+        match &self.shared {
+            Some(shared) => shared.to_director(vcl_backend).unwrap_or(vcl_backend),
+            None => vcl_backend,
+        }
+    }
+
+    /// Returns this VCL's backend for one of the director's, if it has one.
+    fn to_vcl(&self, backend: &Backend) -> Option<VCL_BACKEND> {
+        match &self.shared {
+            Some(shared) => shared.to_vcl(backend).map(|b| b.vcl_backend),
+            None => Some(backend.vcl_backend),
+        }
+    }
+
+    /// Applies `update` to the probe settings: the director's, or this VCL's
+    /// own for a shared director.
+    fn update_probe_spec(
+        &self,
+        update: impl FnOnce(&mut ProbeSpec) -> Result<(), DirectorError>,
+    ) -> Result<(), DirectorError> {
+        match &self.shared {
+            Some(shared) => shared.update_probe_spec(update),
+            None => self.inner.update_probe_spec(update),
+        }
+    }
+
+    /// Logs a selection to VSL, as much as `log_level` asks for.
+    fn log_decision(&self, ctx: &mut Ctx, decision: &Decision) {
+        if self.log_level >= LogLevel::Decision {
+            ctx.log(
+                LogTag::Debug,
+                format!("prequal {}: {}", self.name, decision),
+            );
+        }
+        if self.log_level >= LogLevel::Table {
+            if let Some(table) = self.inner.debug_probe_table() {
+                ctx.log(LogTag::Debug, format!("Probe table state:{}", table));
+            }
+        }
+    }
+
+    /// Returns the backends this director handed out during the current task.
+    fn tried_backends<'a>(&self, ctx: &Ctx) -> Option<&'a mut TriedBackends> {
+        TriedBackends::for_task(ctx, self as *const Self as *const c_void)
+    }
+
+    /// Picks a backend for the current task, counting how it was chosen.
+    ///
+    /// # Arguments
+    /// * `key` - Selects by consistent hashing on this key, see `Director::get_backend`
+    fn select(&self, ctx: &mut Ctx, key: Option<&str>) -> Option<VCL_BACKEND> {
+        let stats = self.inner.stats();
+
+        // Increment request counter
+        stats.req.fetch_add(1, Ordering::Relaxed);
+
+        // Backends this request already got from us, so a retry goes elsewhere
+        let mut tried = self.tried_backends(ctx);
+        let exclude = tried.as_deref().map_or(&[][..], TriedBackends::as_slice);
+        let exclude = match self.shared {
+            Some(_) => Cow::Owned(exclude.iter().map(|b| self.to_director(*b)).collect()),
+            None => Cow::Borrowed(exclude),
+        };
+        // Backends of a shared director that this VCL doesn't have are out
+        let healthy = |backend: &Backend| match &self.shared {
+            Some(shared) => shared.to_vcl(backend).is_some_and(|b| b.is_healthy(ctx)),
+            None => backend.is_healthy(ctx),
+        };
+
+        let backend = match self.inner.select_backend(healthy, &exclude, key) {
+            Ok(decision) => {
+                self.log_decision(ctx, &decision);
+                let vcl_backend = self.to_vcl(&decision.backend);
+                if let (Some(tried), Some(vcl_backend)) = (tried.as_mut(), vcl_backend) {
+                    tried.push(vcl_backend);
+                }
+                // Track selection source
+                let counter = match decision.selection {
+                    Selection::ProbeTable => &stats.selected_from_table,
+                    Selection::LocalLoad => &stats.selected_by_local_load,
+                    Selection::Fallback(FallbackMode::Random) => &stats.fallback_random,
+                    Selection::Fallback(FallbackMode::RoundRobin) => &stats.fallback_round_robin,
+                    Selection::Fallback(FallbackMode::LeastLocalConnections) => {
+                        &stats.fallback_least_local_connections
+                    }
+                    Selection::Fallback(FallbackMode::PowerOfTwo) => &stats.fallback_power_of_two,
+                    Selection::Fallback(FallbackMode::LastKnown) => &stats.fallback_last_known,
+                    Selection::Hash => &stats.hash_affinity,
+                    Selection::HashBounded => &stats.hash_bounded,
+                    Selection::HashSpilled => &stats.hash_spilled,
+                };
+                counter.fetch_add(1, Ordering::Relaxed);
+                vcl_backend
+            }
+            Err(e) => {
+                ctx.log(
+                    LogTag::Error,
+                    format!("prequal: failed to get backend: {}", e),
+                );
+                None
+            }
+        };
+
+        // Sync to Vsc for varnishstat visibility
+        self.sync_stats();
+
+        backend
+    }
+}
+
+impl director {
+    /// Returns the backend a fetch went to, as the Director knows it:
+    /// `backend` itself, unless it is this director, which stands for the
+    /// backend it picked for this task.
+    fn fetched_backend(&self, ctx: &Ctx, backend: VCL_BACKEND) -> Option<VCL_BACKEND> {
+        let vdi = self.vdi.inner();
+        let backend = if backend.0 == self.vdi.vcl_backend().0 {
+            vdi.tried_backends(ctx).and_then(|tried| tried.last())?
+        } else {
+            backend
+        };
+        Some(vdi.to_director(backend))
+    }
+}
+
+impl VclDirector for PrequalVdi {
+    fn resolve(&self, ctx: &mut Ctx) -> Option<VCL_BACKEND> {
+        self.select(ctx, None)
+    }
+
+    fn healthy(&self, _ctx: &mut Ctx) -> bool {
+        self.inner.is_healthy()
+    }
+
+    fn list(&self, _ctx: &mut Ctx, detailed: bool, json: bool) -> String {
+        self.inner.list_backends(detailed, json)
     }
 }
 
@@ -90,110 +506,587 @@ impl director {
 mod prequal {
     use super::*;
 
+    /// Handles the VCL temperature events for the probe threads of its
+    /// directors: cold pauses them, warm resumes them with an immediate
+    /// round of probes, and discard stops and joins them.
+    #[event]
+    pub fn event(#[shared_per_vcl] vcl_directors: &mut Option<Box<VclDirectors>>, event: Event) {
+        if let Some(directors) = vcl_directors {
+            match event {
+                Event::Warm => directors.set_warm(true),
+                Event::Cold => directors.set_warm(false),
+                Event::Discard => directors.discard(),
+                Event::Load => {}
+            }
+        }
+    }
+
     impl director {
         /// Creates a new director instance.
         ///
         /// # Arguments
         /// * `name` - A name for this director instance (used in stats naming)
+        /// * `probe_interval` - How often to top up the probe table (default 5s)
+        /// * `probe_table_size` - Maximum number of probe results kept (default 16)
+        /// * `probe_ratio` - Probes sent per request, on average (default 3.0)
+        /// * `max_probe_age` - Probe results older than this are discarded (default 5s)
+        /// * `max_uses` - Probe results are discarded after this many selections (default 3)
+        /// * `hot_threshold` - Fraction of the max RIF above which a backend is hot (default 0.8)
+        /// * `probe_timeout` - Upper bound for a single probe, connection included (default 1s)
+        /// * `max_probes_in_flight` - Maximum number of concurrent probes (default 32)
+        /// * `max_probe_rate` - Maximum probes sent per second (default 200)
+        /// * `idle_probe_rate` - Probes sent per second without traffic (default 1.0)
+        /// * `fallback` - How to pick a backend when no probe result is usable:
+        ///   `random` (weighted, the default), `round_robin`,
+        ///   `least_local_connections`, `power_of_two` (on local connections)
+        ///   or `last_known` (the latest probe of each backend, however old)
+        /// * `hash_balance_factor` - With `backend(key)`, how far above its
+        ///   weighted share of local connections a backend may go before keys
+        ///   move to the next backend on the ring (default 1.25)
+        /// * `hash_max_latency` - With `backend(key)`, probed latency above which
+        ///   requests leave the key's backend for the best probe (default 0, off)
+        /// * `load_signal` - Where the requests in flight of each backend come
+        ///   from: `probe` (the default), `local` for the connections this
+        ///   Varnish has open or waiting to each backend, without probing at
+        ///   all, or `blended` for the probed count plus those local ones
+        /// * `slow_start` - How long a backend that was just added, or is back
+        ///   from being sick or ejected, takes to get its full share of
+        ///   requests, starting from a tenth of it (default 0s, off)
+        /// * `slow_start_curve` - How that share grows: `linear` (the
+        ///   default) or `exponential`, staying low for longer
+        /// * `eject_consecutive_failures` - Consecutive failed probes after
+        ///   which a backend is ejected: its probe is dropped and it isn't
+        ///   selected for `ejection_time` (default 0, off)
+        /// * `eject_failure_rate` - Share of failed probes among its last 20
+        ///   after which a backend is ejected (default 0, off)
+        /// * `ejection_time` - How long a first ejection lasts; it doubles
+        ///   for each ejection closely following the previous one, up to
+        ///   5 minutes (default 30s)
+        /// * `max_ejected_percent` - Largest share of the backends ejected
+        ///   at once, in percent (default 50)
+        /// * `breaker_error_rate` - Share of 5xx among the statuses passed to
+        ///   `record_result` that opens a backend's circuit breaker, taking
+        ///   it out of selection (default 0, off)
+        /// * `breaker_window` - How far back statuses count towards that
+        ///   rate (default 10s)
+        /// * `breaker_min_requests` - Statuses needed within the window
+        ///   before the rate is trusted (default 20)
+        /// * `breaker_open_time` - How long a circuit stays open before
+        ///   letting trial requests through (default 30s)
+        /// * `breaker_half_open_requests` - Trial requests let through; as
+        ///   many successes close the circuit, an error opens it again
+        ///   (default 5)
+        /// * `shared` - Shares the director with every VCL creating one
+        ///   with the same name, which must then have the same settings:
+        ///   its probe state and probe thread survive reloads, and labeled
+        ///   VCLs use them together. Backends are matched by name and
+        ///   address across VCLs. The probe settings and local zone set in a
+        ///   VCL apply once it is warm, replacing the previous VCL's
+        ///   (default false)
+        /// * `log_level` - What each selection logs to VSL, as `Debug`
+        ///   records: `off` (the default), `decision` for one compact record
+        ///   with the chosen backend, how it was chosen (`cold` or `hot`
+        ///   probe, `fallback`, `hash`...), its probe's RIF, latency and age
+        ///   in seconds, and the runner-up, or `table` for the decision and
+        ///   then the probe table state, as `log_probes` logs it
         ///
-        /// This spawns a background thread that periodically probes backends
-        /// to determine their health and load status.
-        pub fn new(_ctx: &mut Ctx, name: &str) -> Result<Self, VclError> {
-            let stats = Arc::new(DirectorStats::default());
+        /// This spawns a background thread, named after the director, that
+        /// periodically probes backends to determine their health and load
+        /// status, unless `load_signal` is `local` or the shared director
+        /// already has one. Probing pauses while the VCL is cold, resumes
+        /// with a fresh round of probes when it warms up, and the thread is
+        /// stopped and joined when the VCL is discarded.
+        #[allow(clippy::too_many_arguments)]
+        pub fn new(
+            ctx: &mut Ctx,
+            #[shared_per_vcl] vcl_directors: &mut Option<Box<VclDirectors>>,
+            name: &str,
+            probe_interval: Option<Duration>,
+            probe_table_size: Option<i64>,
+            probe_ratio: Option<f64>,
+            max_probe_age: Option<Duration>,
+            max_uses: Option<i64>,
+            hot_threshold: Option<f64>,
+            probe_timeout: Option<Duration>,
+            max_probes_in_flight: Option<i64>,
+            max_probe_rate: Option<f64>,
+            idle_probe_rate: Option<f64>,
+            fallback: Option<&str>,
+            hash_balance_factor: Option<f64>,
+            hash_max_latency: Option<i64>,
+            load_signal: Option<&str>,
+            slow_start: Option<Duration>,
+            slow_start_curve: Option<&str>,
+            eject_consecutive_failures: Option<i64>,
+            eject_failure_rate: Option<f64>,
+            ejection_time: Option<Duration>,
+            max_ejected_percent: Option<i64>,
+            breaker_error_rate: Option<f64>,
+            breaker_window: Option<Duration>,
+            breaker_min_requests: Option<i64>,
+            breaker_open_time: Option<Duration>,
+            breaker_half_open_requests: Option<i64>,
+            shared: Option<bool>,
+            log_level: Option<&str>,
+        ) -> Result<Self, VclError> {
+            let mut config = DirectorConfig::default();
+            if let Some(interval) = probe_interval {
+                config.probe_interval = interval;
+            }
+            if let Some(size) = probe_table_size {
+                config.probe_table.size = count_arg("probe_table_size", size)?;
+            }
+            if let Some(ratio) = probe_ratio {
+                config.probe_ratio = ratio;
+            }
+            if let Some(age) = max_probe_age {
+                config.probe_table.max_probe_age = age;
+            }
+            if let Some(uses) = max_uses {
+                config.probe_table.max_uses = count_arg("max_uses", uses)?;
+            }
+            if let Some(threshold) = hot_threshold {
+                config.probe_table.hot_threshold = threshold;
+            }
+            if let Some(timeout) = probe_timeout {
+                config.probe_timeout = timeout;
+            }
+            if let Some(count) = max_probes_in_flight {
+                config.max_probes_in_flight = count_arg("max_probes_in_flight", count)?;
+            }
+            if let Some(rate) = max_probe_rate {
+                config.max_probe_rate = rate;
+            }
+            if let Some(rate) = idle_probe_rate {
+                config.idle_probe_rate = rate;
+            }
+            let invalid = |e| VclError::new(format!("prequal.director({}): {}", name, e));
+            if let Some(mode) = fallback {
+                config.fallback = mode.parse().map_err(invalid)?;
+            }
+            if let Some(factor) = hash_balance_factor {
+                config.hash_balance_factor = factor;
+            }
+            if let Some(latency) = hash_max_latency {
+                config.hash_max_latency = count_arg("hash_max_latency", latency)?;
+            }
+            if let Some(signal) = load_signal {
+                config.load_signal = signal.parse().map_err(invalid)?;
+            }
+            if let Some(window) = slow_start {
+                config.slow_start = window;
+            }
+            if let Some(curve) = slow_start_curve {
+                config.slow_start_curve = curve.parse().map_err(invalid)?;
+            }
+            if let Some(failures) = eject_consecutive_failures {
+                config.ejection.consecutive_failures =
+                    count_arg("eject_consecutive_failures", failures)?;
+            }
+            if let Some(rate) = eject_failure_rate {
+                config.ejection.failure_rate = rate;
+            }
+            if let Some(time) = ejection_time {
+                config.ejection.ejection_time = time;
+            }
+            if let Some(percent) = max_ejected_percent {
+                config.ejection.max_ejected_percent = count_arg("max_ejected_percent", percent)?;
+            }
+            if let Some(rate) = breaker_error_rate {
+                config.breaker.error_rate = rate;
+            }
+            if let Some(window) = breaker_window {
+                config.breaker.window = window;
+            }
+            if let Some(count) = breaker_min_requests {
+                config.breaker.min_requests = count_arg("breaker_min_requests", count)?;
+            }
+            if let Some(time) = breaker_open_time {
+                config.breaker.open_time = time;
+            }
+            if let Some(count) = breaker_half_open_requests {
+                config.breaker.half_open_requests = count_arg("breaker_half_open_requests", count)?;
+            }
+            config.validate().map_err(invalid)?;
+            let log_level = match log_level {
+                Some(level) => level.parse().map_err(invalid)?,
+                None => LogLevel::default(),
+            };
+
+            let create = || {
+                let stats = Arc::new(DirectorStats::default());
+                let (inner, probe_loop) = Director::new(stats, config);
+                let thread = thread::Builder::new()
+                    .name(format!("prequal-{}", name))
+                    .spawn(probe_loop)
+                    .expect("failed to spawn probe thread");
+                inner.set_probe_thread(thread);
+                inner
+            };
+            let (inner, shared) = if shared.unwrap_or(false) {
+                let attachment = Attachment::attach(name, config, create).map_err(invalid)?;
+                (attachment.director().clone(), Some(Arc::new(attachment)))
+            } else {
+                (create(), None)
+            };
             let vsc = Vsc::<DirectorStats>::new("prequal", name);
-            let (inner, probe_loop) = Director::new(stats);
-            thread::spawn(probe_loop);
-            Ok(Self { inner, vsc })
+            let vdi = NativeDirector::new(
+                ctx,
+                "prequal",
+                name,
+                PrequalVdi {
+                    inner: inner.clone(),
+                    shared: shared.clone(),
+                    vsc,
+                    name: name.to_string(),
+                    log_level,
+                    backend_vscs: Mutex::new(HashMap::new()),
+                    zone_vscs: Mutex::new(HashMap::new()),
+                },
+            )?;
+            vcl_directors
+                .get_or_insert_with(Default::default)
+                .directors
+                .push((inner.clone(), shared));
+            Ok(Self { inner, vdi })
         }
 
         /// Sets the HTTP path used for health check probes.
         ///
         /// # Arguments
-        /// * `path` - The URL path to use for probe requests (e.g. "/probe")
-        pub fn set_probe_path(&self, path: &str) {
-            self.inner.set_probe_path(path);
+        /// * `path` - The URL path to use for probe requests (e.g. "/probe"),
+        ///   optionally with a query string (e.g. "/load?source=varnish")
+        pub fn set_probe_path(&self, path: &str) -> Result<(), VclError> {
+            self.vdi
+                .inner()
+                .update_probe_spec(|spec| spec.set_path(path))
+                .map_err(|e| VclError::new(format!("set_probe_path: {}", e)))
+        }
+
+        /// Sets the HTTP method used for probe requests (default GET).
+        ///
+        /// # Arguments
+        /// * `method` - The request method (e.g. "HEAD")
+        pub fn set_probe_method(&self, method: &str) -> Result<(), VclError> {
+            self.vdi
+                .inner()
+                .update_probe_spec(|spec| spec.set_method(method))
+                .map_err(|e| VclError::new(format!("set_probe_method: {}", e)))
+        }
+
+        /// Adds a static header to every probe request, e.g. an auth token.
+        ///
+        /// # Arguments
+        /// * `name` - The header name; a `Host` header replaces the default,
+        ///   which is the backend name. Adding a header again replaces it
+        /// * `value` - The header value
+        pub fn add_probe_header(&self, name: &str, value: &str) -> Result<(), VclError> {
+            self.vdi
+                .inner()
+                .update_probe_spec(|spec| spec.add_header(name, value))
+                .map_err(|e| VclError::new(format!("add_probe_header: {}", e)))
+        }
+
+        /// Sets the probe response headers holding the load signals, for the
+        /// `headers` probe format.
+        ///
+        /// # Arguments
+        /// * `in_flight` - Header holding the requests in flight (default "X-In-Flight")
+        /// * `latency` - Header holding the estimated latency (default "X-Estimated-Latency")
+        pub fn set_load_headers(&self, in_flight: &str, latency: &str) -> Result<(), VclError> {
+            self.vdi
+                .inner()
+                .update_probe_spec(|spec| spec.set_load_headers(in_flight, latency))
+                .map_err(|e| VclError::new(format!("set_load_headers: {}", e)))
+        }
+
+        /// Sets where probe responses carry the load signals.
+        ///
+        /// # Arguments
+        /// * `format` - `headers` (the default) reads the headers set with
+        ///   `set_load_headers`, `json` reads fields of a JSON body, and
+        ///   `auto` reads the body when its Content-Type is JSON, the headers
+        ///   otherwise
+        /// * `in_flight_field` - JSON field holding the requests in flight
+        ///   (default "in_flight"); use dots for nested objects, e.g. "load.rif"
+        /// * `latency_field` - JSON field holding the estimated latency
+        ///   (default "estimated_latency")
+        pub fn set_probe_format(
+            &self,
+            format: &str,
+            in_flight_field: Option<&str>,
+            latency_field: Option<&str>,
+        ) -> Result<(), VclError> {
+            self.vdi
+                .inner()
+                .update_probe_spec(|spec| spec.set_format(format, in_flight_field, latency_field))
+                .map_err(|e| VclError::new(format!("set_probe_format: {}", e)))
+        }
+
+        /// Sets the zone this director runs in.
+        ///
+        /// Selection then prefers cold backends added with the same `zone`,
+        /// and only goes to other zones when all local probes are hot or
+        /// missing. Without probe results, the fallback sticks to local
+        /// backends as long as one is healthy.
+        ///
+        /// Each zone a backend was selected in gets its own varnishstat
+        /// segment, `prequal_zone.<director>.<zone>`, counting those
+        /// selections.
+        ///
+        /// # Arguments
+        /// * `zone` - The local zone (e.g. "us-east-1a")
+        pub fn set_local_zone(&self, zone: &str) {
+            match &self.vdi.inner().shared {
+                Some(shared) => shared.set_local_zone(zone),
+                None => self.inner.set_local_zone(zone),
+            }
         }
 
         /// Adds a backend to the director's pool.
         ///
+        /// Each backend gets its own varnishstat segment,
+        /// `prequal_backend.<director>.<backend>`, counting the times it was
+        /// selected, its probes sent, succeeded, failed and evicted from the
+        /// table, along with the RIF, latency and age of its latest probe.
+        ///
         /// # Arguments
         /// * `vcl_backend` - The VCL backend to add
+        /// * `weight` - Relative capacity of the backend (default 1.0, at most
+        ///   100). It biases probing and random fallback, and normalizes requests
+        ///   in flight when telling hot backends from cold ones.
+        /// * `zone` - Availability zone of the backend, see `set_local_zone`
         ///
         /// # Returns
         /// * `Ok(())` if the backend was added successfully
         /// * `Err(VclError)` if the backend was invalid or could not be added
-        pub fn add_backend(&self, vcl_backend: VCL_BACKEND) -> Result<(), VclError> {
+        pub fn add_backend(
+            &self,
+            vcl_backend: VCL_BACKEND,
+            weight: Option<f64>,
+            zone: Option<&str>,
+        ) -> Result<(), VclError> {
             match Backend::new(vcl_backend) {
-                Ok(backend) => self
-                    .inner
-                    .add_backend(backend)
-                    .map_err(|e| VclError::new(format!("Failed to add backend: {:?}", e))),
+                Ok(backend) => {
+                    let vdi = self.vdi.inner();
+                    let name = backend.name.clone();
+                    let backend = backend
+                        .with_weight(weight.unwrap_or(1.0))
+                        .with_zone(zone.map(String::from));
+                    match &vdi.shared {
+                        Some(shared) => shared.add_backend(backend),
+                        None => self.inner.add_backend(backend),
+                    }
+                    .map_err(|e| VclError::new(format!("Failed to add backend: {}", e)))?;
+                    vdi.add_backend_vsc(&name);
+                    Ok(())
+                }
                 Err(e) => Err(VclError::new(format!("Invalid backend: {:?}", e))),
             }
         }
 
-        /// Removes a backend from the pool.
+        /// Removes a backend from the pool; from a shared director, it is
+        /// removed for every VCL.
         ///
         /// # Arguments
         /// * `backend` - The VCL backend to remove
         pub fn remove_backend(&self, backend: VCL_BACKEND) {
-            self.inner.remove_backend(backend)
+            let vdi = self.vdi.inner();
+            if let Ok(removed) = Backend::new(backend) {
+                vdi.remove_backend_vsc(&removed.name);
+            }
+            match &vdi.shared {
+                Some(shared) => shared.remove_backend(backend),
+                None => self.inner.remove_backend(backend),
+            }
         }
 
-        /// Selects the best backend for the current request.
-        ///
-        /// The selection is based on probe results (in_flight requests and latency).
-        /// Falls back to random selection if no probe results are available.
+        /// Stops selecting a backend for new requests, e.g. before it is
+        /// deployed, without removing it: it is still probed, and shows up
+        /// in `log_probes` and the `backends_draining` counter.
         ///
-        /// # Safety
-        /// This function is marked unsafe because it returns a raw VCL_BACKEND pointer.
-        pub unsafe fn backend(&self, ctx: &mut Ctx) -> Result<VCL_BACKEND, VclError> {
-            self.log_probes(ctx); // just for now, for debugging
-
-            let stats = self.inner.stats();
-
-            // Increment request counter
-            stats.req.fetch_add(1, Ordering::Relaxed);
+        /// # Arguments
+        /// * `backend` - The VCL backend to drain
+        /// * `duration` - How long the drain lasts; draining a backend again
+        ///   restarts it
+        /// * `remove` - Whether to remove the backend once drained, rather
+        ///   than selecting it again (default false)
+        pub fn drain_backend(
+            &self,
+            backend: VCL_BACKEND,
+            duration: Duration,
+            remove: Option<bool>,
+        ) -> Result<(), VclError> {
+            let backend = self.vdi.inner().to_director(backend);
+            self.inner
+                .drain_backend(backend, duration, remove.unwrap_or(false))
+                .map_err(|e| VclError::new(format!("drain_backend: {}", e)))
+        }
 
-            let (backend, from_table) = self
-                .inner
-                .get_backend()
-                .map_err(|e| VclError::new(format!("Failed to get backend: {:?}", e)))?;
+        /// Returns the director as a backend for the current request.
+        ///
+        /// This is a native Varnish director: the actual backend is selected
+        /// when the fetch happens, based on probe results (in_flight requests
+        /// and latency), falling back to random selection if no probe results
+        /// are available. It can be assigned to `req.backend_hint` or
+        /// `bereq.backend`, or added to other directors.
+        ///
+        /// Backends already used by the current task are avoided, so a fetch
+        /// retried with `return(retry)` goes to a different backend when one is
+        /// available.
+        ///
+        /// # Arguments
+        /// * `key` - Keeps requests with the same key on the same backend, by
+        ///   consistent hashing with bounded loads, as long as that backend's
+        ///   probe shows it neither hot nor above `hash_max_latency`. The
+        ///   backend is then picked right away rather than at fetch time, so
+        ///   to have retries avoid it, call this from `vcl_backend_fetch`.
+        pub fn backend(&self, ctx: &mut Ctx, key: Option<&str>) -> VCL_BACKEND {
+            match key {
+                Some(key) => self
+                    .vdi
+                    .inner()
+                    .select(ctx, Some(key))
+                    .unwrap_or(VCL_BACKEND(ptr::null())),
+                None => self.vdi.vcl_backend(),
+            }
+        }
 
-            // Track selection source
-            if from_table {
-                stats.selected_from_table.fetch_add(1, Ordering::Relaxed);
-            } else {
-                stats.fallback_random.fetch_add(1, Ordering::Relaxed);
+        /// Feeds load signals from a real backend response into the probe
+        /// table, as a fresh probe result. Call it from `vcl_backend_response`
+        /// for backends that attach their load to every response.
+        ///
+        /// # Arguments
+        /// * `backend` - The backend that sent the response: `beresp.backend`,
+        ///   or `bereq.backend` when it holds this director, which stands for
+        ///   the backend the director picked for this fetch
+        /// * `in_flight` - The backend's requests in flight (e.g.
+        ///   `beresp.http.X-In-Flight`)
+        /// * `latency` - The backend's estimated latency (e.g.
+        ///   `beresp.http.X-Estimated-Latency`); when empty, the latency
+        ///   observed with `observe` stands in
+        ///
+        /// Reports with missing or unparsable values, or for a backend not in
+        /// the pool, are ignored and counted in `reports_invalid`.
+        pub fn report(&self, ctx: &mut Ctx, backend: VCL_BACKEND, in_flight: &str, latency: &str) {
+            let vdi = self.vdi.inner();
+            let backend = self.fetched_backend(ctx, backend);
+            let latency = match latency.trim() {
+                "" => Ok(None),
+                latency => latency.parse().map(Some),
+            };
+            let reported = match (backend, in_flight.trim().parse(), latency) {
+                (Some(backend), Ok(in_flight), Ok(latency)) => {
+                    self.inner.report(backend, in_flight, latency).is_ok()
+                }
+                _ => false,
+            };
+            if !reported {
+                self.inner
+                    .stats()
+                    .reports_invalid
+                    .fetch_add(1, Ordering::Relaxed);
             }
+            vdi.sync_stats();
+        }
 
-            // Sync to Vsc for varnishstat visibility
-            self.sync_stats();
+        /// Records how long a fetch from a backend took, e.g. its time to
+        /// first byte. Call it from `vcl_backend_response`.
+        ///
+        /// The director compares these observations with the latency each
+        /// backend estimates, and corrects future estimates by the bias it
+        /// finds. Backends that send no latency in their probe responses or
+        /// reports are judged on their observed latency instead.
+        ///
+        /// # Arguments
+        /// * `backend` - The backend that served the fetch, as for `report`
+        /// * `duration` - How long the fetch took (e.g.
+        ///   `std.duration(beresp.http.X-TTFB, 0s)`)
+        ///
+        /// Observations for a backend not in the pool are ignored.
+        pub fn observe(&self, ctx: &mut Ctx, backend: VCL_BACKEND, duration: Duration) {
+            if let Some(backend) = self.fetched_backend(ctx, backend) {
+                if let Err(e) = self.inner.observe(backend, duration) {
+                    ctx.log(LogTag::Debug, format!("prequal: observe: {}", e));
+                }
+            }
+            self.vdi.inner().sync_stats();
+        }
 
-            Ok(backend.vcl_backend)
+        /// Records the status of a real response, for the backend's circuit
+        /// breaker (see `breaker_error_rate`). Call it from both
+        /// `vcl_backend_response` and `vcl_backend_error`, where failed
+        /// fetches have a 503 status.
+        ///
+        /// # Arguments
+        /// * `backend` - The backend that served the fetch, as for `report`
+        /// * `status` - The response status (e.g. `beresp.status`); 5xx
+        ///   counts as an error
+        ///
+        /// Statuses for a backend not in the pool are ignored.
+        pub fn record_result(&self, ctx: &mut Ctx, backend: VCL_BACKEND, status: i64) {
+            let status = u16::try_from(status).unwrap_or(u16::MAX);
+            if let Some(backend) = self.fetched_backend(ctx, backend) {
+                if let Err(e) = self.inner.record_result(backend, status) {
+                    ctx.log(LogTag::Debug, format!("prequal: record_result: {}", e));
+                }
+            }
+            self.vdi.inner().sync_stats();
         }
 
         /// Checks if the director has any valid probe results.
         ///
         /// # Returns
-        /// `true` if there are valid probe results, `false` otherwise
+        /// `true` if there are valid probe results, `false` otherwise. With
+        /// `load_signal = local`, `true` as soon as it has a backend.
         pub fn healthy(&self) -> bool {
             self.inner.is_healthy()
         }
 
-        /// Triggers a probe fetch for every backend.
+        /// Triggers an immediate round of probes to fill the probe table,
+        /// within `max_probe_rate`.
         pub fn seed_probes(&self) {
             self.inner.trigger_probe();
         }
 
-        /// Logs the current state of the probe table for debugging.
+        /// Logs the current state of the probe table for debugging, followed by
+        /// the backends being drained, ejected or in their slow start, with
+        /// their progress, and the state of the circuit breakers.
         ///
         /// # Arguments
         /// * `ctx` - The VCL context for logging
-        pub fn log_probes(&self, ctx: &mut Ctx) {
-            if let Some(table) = self.inner.debug_probe_table() {
-                ctx.log(LogTag::Debug, format!("Probe table state:{}", table));
+        /// * `format` - `text` (the default), or `json` to log what
+        ///   `dump_json` returns
+        pub fn log_probes(&self, ctx: &mut Ctx, format: Option<&str>) -> Result<(), VclError> {
+            match format.unwrap_or("text") {
+                "text" => {
+                    if let Some(table) = self.inner.debug_probe_table() {
+                        ctx.log(LogTag::Debug, format!("Probe table state:{}", table));
+                    }
+                }
+                "json" => ctx.log(LogTag::Debug, self.inner.dump_json()),
+                other => {
+                    return Err(VclError::new(format!(
+                        "log_probes: invalid format {}, expected text or json",
+                        other
+                    )))
+                }
             }
+            Ok(())
+        }
+
+        /// Returns the state of the director as JSON, e.g. to serve it from a
+        /// `vcl_synth` endpoint.
+        ///
+        /// The object holds the director's `config`; its `backends`, with
+        /// their name, address, weight, zone and `state`: `draining`,
+        /// `ejected`, `breaker_open`, `breaker_half_open`, `slow_start` or
+        /// `active`; the probe `table` entries, with their backend, `rif`,
+        /// `latency`, `used_count` and `age` in seconds; the `zones`, with
+        /// the times a backend of each was `selected`; and the `stats`, as
+        /// in varnishstat, with each histogram's bucket bounds and counts.
+        pub fn dump_json(&self) -> String {
+            self.inner.dump_json()
         }
     }
 }
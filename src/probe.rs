@@ -1,12 +1,50 @@
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Mutex;
 use std::time::{Duration, SystemTime};
 
 use crate::backend::Backend;
+use crate::calibration::LatencyCalibration;
+
+pub const DEFAULT_MAX_PROBE_AGE: Duration = Duration::from_secs(5);
+pub const DEFAULT_PROBE_TABLE_SIZE: usize = 16;
+pub const DEFAULT_MAX_USES_BEFORE_EXPIRE: usize = 3;
+pub const DEFAULT_HOT_THRESHOLD: f64 = 0.8;
+
+/// Tuning knobs for a `ProbeTable`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProbeTableConfig {
+    /// Maximum number of probe results kept in the table
+    pub size: usize,
+    /// Probe results older than this are discarded
+    pub max_probe_age: Duration,
+    /// Probe results are discarded after being selected this many times
+    pub max_uses: usize,
+    /// Fraction of the max RIF above which a probe is considered hot
+    pub hot_threshold: f64,
+    /// Adds this Varnish's own requests to each backend's probed RIF, so it
+    /// doesn't pile onto a backend between two probes
+    pub local_load: bool,
+}
+
+impl Default for ProbeTableConfig {
+    fn default() -> Self {
+        Self {
+            size: DEFAULT_PROBE_TABLE_SIZE,
+            max_probe_age: DEFAULT_MAX_PROBE_AGE,
+            max_uses: DEFAULT_MAX_USES_BEFORE_EXPIRE,
+            hot_threshold: DEFAULT_HOT_THRESHOLD,
+            local_load: false,
+        }
+    }
+}
 
-const MAX_PROBE_AGE: Duration = Duration::from_secs(5);
-pub const PROBE_TABLE_SIZE: usize = 16;
-const MAX_USES_BEFORE_EXPIRE: usize = 3;
+impl ProbeTableConfig {
+    /// Returns the normalized RIF at or below which a probe is considered cold.
+    pub fn rif_threshold(&self, max_rif: f64) -> f64 {
+        max_rif * self.hot_threshold
+    }
+}
 
 #[derive(Debug)]
 pub struct ProbeResult {
@@ -32,8 +70,14 @@ impl ProbeResult {
         self.used_count.fetch_add(1, Ordering::SeqCst) + 1
     }
 
-    pub fn is_over_used(&self) -> bool {
-        self.used_count.load(Ordering::SeqCst) >= MAX_USES_BEFORE_EXPIRE
+    pub fn is_over_used(&self, max_uses: usize) -> bool {
+        self.used_count.load(Ordering::SeqCst) >= max_uses
+    }
+
+    /// Returns the RIF relative to the backend's weight, so that a backend
+    /// with twice the capacity is as loaded with twice the requests.
+    pub fn normalized_rif(&self) -> f64 {
+        self.rif as f64 / self.backend.weight
     }
 }
 
@@ -49,33 +93,75 @@ impl Clone for ProbeResult {
     }
 }
 
+/// A backend picked from the probe table, with the probe it was picked on.
+#[derive(Debug, Clone)]
+pub struct Pick {
+    pub backend: Backend,
+    /// How old the probe was
+    pub age: Duration,
+    pub rif: usize,
+    pub est_latency: usize,
+    /// Whether the probe was hot, no cold one being usable
+    pub hot: bool,
+    /// The backend that would have been picked next
+    pub runner_up: Option<Backend>,
+}
+
 #[derive(Debug)]
 pub struct ProbeTable {
     results: Mutex<Vec<ProbeResult>>,
-    max_rif: AtomicUsize,
+    // Latest probe of each backend, kept after it expires from `results`
+    last_known: Mutex<Vec<ProbeResult>>,
+    // Max normalized rif, as f64 bits
+    max_rif: AtomicU64,
+    // Corrects estimated latencies against observed ones
+    calibration: LatencyCalibration,
+    // Probes evicted per backend since `take_evicted` last ran, keyed by
+    // the backend's VCL_BACKEND address
+    evicted: Mutex<HashMap<usize, (Backend, u64)>>,
+    config: ProbeTableConfig,
 }
 
-pub fn remove_stale_and_over_used(results: &mut Vec<ProbeResult>) {
+/// Removes the probes that are too old or used too often.
+///
+/// # Returns
+/// The removed probes
+pub fn remove_stale_and_over_used(
+    results: &mut Vec<ProbeResult>,
+    config: &ProbeTableConfig,
+) -> Vec<ProbeResult> {
     let now = SystemTime::now();
-    results
-        .retain(|p| !p.is_over_used() && now.duration_since(p.timestamp).unwrap() <= MAX_PROBE_AGE);
+    let (kept, removed) = results.drain(..).partition(|p| {
+        !p.is_over_used(config.max_uses)
+            && now.duration_since(p.timestamp).unwrap_or_default() <= config.max_probe_age
+    });
+    *results = kept;
+    removed
 }
 
 /// Removes the worst probe from the pool.
 /// Uses inverse HCL logic: prefer removing hot probes (high RIF) first,
 /// and among those, remove the one with highest latency.
-pub fn remove_worst_probe(results: &mut Vec<ProbeResult>, max_rif: usize) {
+/// RIF is normalized by backend weight.
+///
+/// # Returns
+/// The removed probe, if there was any
+pub fn remove_worst_probe(
+    results: &mut Vec<ProbeResult>,
+    max_rif: f64,
+    hot_threshold: f64,
+) -> Option<ProbeResult> {
     if results.is_empty() {
-        return;
+        return None;
     }
 
-    let threshold = (max_rif as f64 * 0.8) as usize;
+    let threshold = max_rif * hot_threshold;
 
     // Partition into cold and hot
     let (cold_indices, hot_indices): (Vec<_>, Vec<_>) = results
         .iter()
         .enumerate()
-        .partition(|(_, probe)| probe.rif <= threshold);
+        .partition(|(_, probe)| probe.normalized_rif() <= threshold);
 
     // Prefer removing from hot probes (highest latency first)
     // Fall back to cold probes if no hot ones exist
@@ -87,75 +173,253 @@ pub fn remove_worst_probe(results: &mut Vec<ProbeResult>, max_rif: usize) {
                 .iter()
                 .max_by_key(|(_, probe)| probe.est_latency)
         })
-        .map(|(idx, _)| *idx);
+        .map(|(idx, _)| *idx)?;
 
-    if let Some(idx) = worst_idx {
-        results.remove(idx);
-    }
+    Some(results.remove(worst_idx))
 }
 
 impl ProbeTable {
-    pub fn new() -> Self {
+    pub fn new(config: ProbeTableConfig) -> Self {
         Self {
-            results: Mutex::new(Vec::with_capacity(PROBE_TABLE_SIZE * 2)),
-            max_rif: AtomicUsize::new(0),
+            results: Mutex::new(Vec::with_capacity(config.size * 2)),
+            last_known: Mutex::new(Vec::new()),
+            max_rif: AtomicU64::new(0f64.to_bits()),
+            calibration: LatencyCalibration::default(),
+            evicted: Mutex::new(HashMap::new()),
+            config,
+        }
+    }
+
+    fn record_evicted(&self, removed: impl IntoIterator<Item = ProbeResult>) {
+        if let Ok(mut evicted) = self.evicted.lock() {
+            for probe in removed {
+                let key = probe.backend.vcl_backend.0 as usize;
+                evicted.entry(key).or_insert((probe.backend, 0)).1 += 1;
+            }
         }
     }
 
+    /// Returns the backends whose probes left the table since the last
+    /// call, for being stale, over-used or the worst of a full table, with
+    /// how many of their probes did.
+    pub fn take_evicted(&self) -> Vec<(Backend, u64)> {
+        self.evicted
+            .lock()
+            .map(|mut evicted| evicted.drain().map(|(_, e)| e).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn config(&self) -> &ProbeTableConfig {
+        &self.config
+    }
+
     pub fn add_result(&self, result: ProbeResult) {
+        // One entry per server, whichever VCL's backend it comes through
+        if let Ok(mut last_known) = self.last_known.lock() {
+            last_known.retain(|p| !p.backend.is_same_server(&result.backend));
+            last_known.push(result.clone());
+        }
+
         if let Ok(mut results) = self.results.lock() {
-            remove_stale_and_over_used(&mut results);
+            self.record_evicted(remove_stale_and_over_used(&mut results, &self.config));
 
             // remove probe result's backend if it was already in the table
-            results.retain(|p| p.backend != result.backend);
+            results.retain(|p| !p.backend.is_same_server(&result.backend));
 
             results.push(result);
 
             // Calculate max_rif before removing worst probes
-            let max_rif = results.iter().map(|p| p.rif).max().unwrap_or(0);
-
-            while results.len() > PROBE_TABLE_SIZE {
-                remove_worst_probe(&mut results, max_rif);
+            let max_rif = results
+                .iter()
+                .map(|p| p.normalized_rif())
+                .fold(0.0, f64::max);
+
+            while results.len() > self.config.size {
+                self.record_evicted(remove_worst_probe(
+                    &mut results,
+                    max_rif,
+                    self.config.hot_threshold,
+                ));
             }
 
-            self.max_rif.store(max_rif, Ordering::SeqCst);
+            self.max_rif.store(max_rif.to_bits(), Ordering::SeqCst);
         }
     }
 
-    pub fn find_best(&self) -> Option<Backend> {
-        let probes: Vec<ProbeResult> = {
-            let mut results = self.results.lock().ok()?;
-            if results.is_empty() {
-                return None;
-            }
-            remove_stale_and_over_used(&mut results);
-            results.iter().cloned().collect()
-        };
+    /// Picks the best backend among the probes whose backend is `usable`.
+    pub fn find_best(&self, usable: impl Fn(&Backend) -> bool) -> Option<Backend> {
+        self.pick_best(usable).map(|pick| pick.backend)
+    }
+
+    /// Like `find_best`, but only considers cold probes.
+    pub fn find_cold(&self, usable: impl Fn(&Backend) -> bool) -> Option<Backend> {
+        self.pick_cold(usable).map(|pick| pick.backend)
+    }
+
+    /// Like `find_best`, also telling what the probe used said, and the runner-up.
+    pub fn pick_best(&self, usable: impl Fn(&Backend) -> bool) -> Option<Pick> {
+        self.pick(usable, true)
+    }
+
+    /// Like `find_cold`, also telling what the probe used said, and the runner-up.
+    pub fn pick_cold(&self, usable: impl Fn(&Backend) -> bool) -> Option<Pick> {
+        self.pick(usable, false)
+    }
+
+    fn pick(&self, usable: impl Fn(&Backend) -> bool, allow_hot: bool) -> Option<Pick> {
+        let mut results = self.results.lock().ok()?;
+        if results.is_empty() {
+            return None;
+        }
+        self.record_evicted(remove_stale_and_over_used(&mut results, &self.config));
 
         // Normalize rif values against the max rif
-        let max_rif = self.max_rif.load(Ordering::SeqCst);
-        let threshold = (max_rif as f64 * 0.8) as usize;
+        let threshold = self.config.rif_threshold(self.max_rif(&results));
 
         // Partition probes into cold and hot, based on rif threshold
-        let (cold_probes, hot_probes): (Vec<_>, Vec<_>) = probes
+        let (mut cold_probes, mut hot_probes): (Vec<_>, Vec<_>) = results
             .iter()
-            .enumerate()
-            .partition(|(_, probe)| probe.rif <= threshold);
+            .filter(|probe| usable(&probe.backend))
+            .partition(|probe| self.rif(probe) <= threshold);
+
+        // Prefer cold probes with the lowest latency
+        // Fall back to hot probes with the lowest rif if no cold probes available
+        cold_probes.sort_by_key(|probe| self.latency(probe));
+        let hot = cold_probes.is_empty();
+        let mut ranked = cold_probes;
+        if allow_hot {
+            hot_probes.sort_by(|a, b| self.rif(a).total_cmp(&self.rif(b)));
+            ranked.append(&mut hot_probes);
+        }
+        let best = *ranked.first()?;
+
+        // Count the use on the table entry itself so max_uses is enforced
+        best.increment_used();
+        Some(Pick {
+            backend: best.backend.clone(),
+            age: SystemTime::now()
+                .duration_since(best.timestamp)
+                .unwrap_or_default(),
+            rif: best.rif,
+            est_latency: best.est_latency,
+            hot,
+            runner_up: ranked.get(1).map(|probe| probe.backend.clone()),
+        })
+    }
 
-        // Prefer cold probe with lowest latency
-        // Fall back to hot probe with lowest rif if no cold probes available
-        let best = cold_probes
+    /// Picks the usable backend with the lowest normalized RIF according to
+    /// its latest probe, however old. Used when the table itself is empty.
+    pub fn find_last_known(&self, usable: impl Fn(&Backend) -> bool) -> Option<Backend> {
+        let last_known = self.last_known.lock().ok()?;
+        last_known
             .iter()
-            .min_by_key(|(_, probe)| probe.est_latency)
-            .or_else(|| hot_probes.iter().min_by_key(|(_, probe)| probe.rif))
-            .map(|(_, probe)| probe)?;
+            .filter(|probe| usable(&probe.backend))
+            .min_by(|a, b| {
+                self.rif(a)
+                    .total_cmp(&self.rif(b))
+                    .then(self.latency(a).cmp(&self.latency(b)))
+            })
+            .map(|probe| probe.backend.clone())
+    }
 
-        // Increment the atomic counter directly - no lock needed since it's atomic
-        best.increment_used();
-        Some(best.backend.clone())
+    /// Tells whether the current probe of `backend` shows it hot, or slower
+    /// than `max_latency` if that is not 0. Backends without a probe are not.
+    pub fn is_overloaded(&self, backend: &Backend, max_latency: usize) -> bool {
+        let Ok(results) = self.results.lock() else {
+            return false;
+        };
+        let threshold = self.config.rif_threshold(self.max_rif(&results));
+        let now = SystemTime::now();
+
+        results
+            .iter()
+            .filter(|p| {
+                now.duration_since(p.timestamp)
+                    .is_ok_and(|age| age <= self.config.max_probe_age)
+            })
+            .find(|p| p.backend == *backend)
+            .is_some_and(|p| {
+                self.rif(p) > threshold || (max_latency > 0 && self.latency(p) > max_latency)
+            })
+    }
+
+    /// Returns the probe's RIF relative to its backend's weight, including
+    /// this Varnish's current requests to it with `local_load`.
+    pub fn rif(&self, probe: &ProbeResult) -> f64 {
+        if self.config.local_load {
+            (probe.rif + probe.backend.local_load()) as f64 / probe.backend.weight
+        } else {
+            probe.normalized_rif()
+        }
+    }
+
+    /// Returns the max normalized RIF, which hot and cold are relative to.
+    /// Local loads change between probes, so they are accounted for now.
+    fn max_rif(&self, results: &[ProbeResult]) -> f64 {
+        if self.config.local_load {
+            results.iter().map(|p| self.rif(p)).fold(0.0, f64::max)
+        } else {
+            f64::from_bits(self.max_rif.load(Ordering::SeqCst))
+        }
+    }
+
+    /// Returns the probe's estimated latency, corrected by what was observed
+    /// for its backend.
+    pub fn latency(&self, probe: &ProbeResult) -> usize {
+        self.calibration.correct(&probe.backend, probe.est_latency)
+    }
+
+    /// Records the latency of a real fetch from `backend`, to calibrate its
+    /// future estimates against its latest one.
+    pub fn observe(&self, backend: &Backend, observed: Duration) {
+        let estimated = self.last_known.lock().ok().and_then(|last_known| {
+            last_known
+                .iter()
+                .find(|p| p.backend == *backend)
+                .map(|p| p.est_latency)
+        });
+        self.calibration.observe(backend, observed, estimated);
+    }
+
+    /// Returns the average latency observed for `backend`, in milliseconds.
+    pub fn observed_latency(&self, backend: &Backend) -> Option<usize> {
+        self.calibration.observed_latency(backend)
+    }
+
+    /// Drops the current probe of `backend`, keeping what is known of it
+    /// otherwise.
+    pub fn purge_backend(&self, backend: &Backend) {
+        if let Ok(mut results) = self.results.lock() {
+            results.retain(|p| p.backend != *backend);
+        }
+    }
+
+    /// Carries the probes of `old`, and what is known of it, over to
+    /// `new`, the same server through another VCL's backend.
+    pub fn rebind_backend(&self, old: &Backend, new: &Backend) {
+        self.calibration.rebind_backend(old, new);
+        if let Ok(mut evicted) = self.evicted.lock() {
+            if let Some((_, count)) = evicted.remove(&(old.vcl_backend.0 as usize)) {
+                evicted.insert(new.vcl_backend.0 as usize, (new.clone(), count));
+            }
+        }
+        for probes in [&self.results, &self.last_known] {
+            if let Ok(mut probes) = probes.lock() {
+                for probe in probes.iter_mut().filter(|p| p.backend == *old) {
+                    probe.backend = new.clone();
+                }
+            }
+        }
     }
 
     pub fn remove_backend(&self, backend: Backend) {
+        self.calibration.remove_backend(&backend);
+        if let Ok(mut evicted) = self.evicted.lock() {
+            evicted.remove(&(backend.vcl_backend.0 as usize));
+        }
+        if let Ok(mut last_known) = self.last_known.lock() {
+            last_known.retain(|p| p.backend != backend);
+        }
         if let Ok(mut results) = self.results.lock() {
             results.retain(|p| p.backend != backend);
         }
@@ -167,10 +431,12 @@ impl ProbeTable {
         let mut output = String::new();
         for (idx, probe) in results.iter().enumerate() {
             output.push_str(&format!(
-                "probe[{}]: backend={} ({}) in_flight={}, latency={}, used={}, age={}\n",
+                "probe[{}]: backend={} ({}) weight={}, zone={}, in_flight={}, latency={}, used={}, age={}\n",
                 idx,
                 probe.backend.name,
                 probe.backend.address,
+                probe.backend.weight,
+                probe.backend.zone.as_deref().unwrap_or("-"),
                 probe.rif,
                 probe.est_latency,
                 probe.used_count.load(Ordering::SeqCst),
@@ -184,6 +450,14 @@ impl ProbeTable {
         Some(output)
     }
 
+    /// Returns a copy of the current probe results.
+    pub fn entries(&self) -> Vec<ProbeResult> {
+        self.results
+            .lock()
+            .map(|results| results.clone())
+            .unwrap_or_default()
+    }
+
     pub fn has_probes(&self) -> bool {
         !self.results.lock().unwrap().is_empty()
     }
@@ -191,10 +465,22 @@ impl ProbeTable {
     pub fn remove_stale(&self) {
         if let Ok(mut results) = self.results.lock() {
             let now = SystemTime::now();
-            results.retain(|p| now.duration_since(p.timestamp).unwrap() <= MAX_PROBE_AGE);
+            let (kept, stale) = results.drain(..).partition(|p| {
+                now.duration_since(p.timestamp).unwrap_or_default() <= self.config.max_probe_age
+            });
+            *results = kept;
+            self.record_evicted(stale);
         }
     }
 
+    /// Returns a copy of the latest probe of each backend, however old.
+    pub fn last_known(&self) -> Vec<ProbeResult> {
+        self.last_known
+            .lock()
+            .map(|last_known| last_known.clone())
+            .unwrap_or_default()
+    }
+
     pub fn len(&self) -> usize {
         self.results
             .lock()
@@ -208,7 +494,7 @@ impl ProbeTable {
 
         // If pool is less than half full, signal that we need more probes
         let pool_size = self.len();
-        pool_size >= PROBE_TABLE_SIZE / 2
+        pool_size >= self.config.size / 2
     }
 
     /// Returns vectors of (rif, latency) values from all probes for computing metrics
@@ -227,9 +513,8 @@ impl ProbeTable {
 mod tests {
     use std::net::SocketAddr;
 
-    use varnish::ffi::{director, VCL_BACKEND};
-
     use super::*;
+    use crate::backend::test_backend;
 
     fn create_test_probe(
         idx: usize,
@@ -242,23 +527,19 @@ mod tests {
             timestamp,
             rif,
             est_latency,
-            Backend {
-                name: name.to_string(),
-                address: SocketAddr::from(([127, 0, 0, 1], 8080)),
-                vcl_backend: VCL_BACKEND(idx as *const director),
-            },
+            test_backend(name, SocketAddr::from(([127, 0, 0, 1], 8080)), idx),
         )
     }
 
     #[test]
     fn test_probe_table() {
-        let table = ProbeTable::new();
+        let table = ProbeTable::new(ProbeTableConfig::default());
         assert_eq!(table.len(), 0);
     }
 
     #[test]
     fn test_probe_table_add_result() {
-        let table = ProbeTable::new();
+        let table = ProbeTable::new(ProbeTableConfig::default());
         let result = create_test_probe(0, "test", 10, 100, SystemTime::now());
         table.add_result(result);
         assert_eq!(table.len(), 1);
@@ -266,15 +547,137 @@ mod tests {
 
     #[test]
     fn test_probe_table_find_best() {
-        let table = ProbeTable::new();
+        let table = ProbeTable::new(ProbeTableConfig::default());
         let result = create_test_probe(0, "test", 10, 100, SystemTime::now());
         table.add_result(result.clone());
-        assert_eq!(table.find_best(), Some(result.backend));
+        assert_eq!(table.find_best(|_| true), Some(result.backend));
+    }
+
+    #[test]
+    fn test_probe_table_find_best_skips_unusable() {
+        let table = ProbeTable::new(ProbeTableConfig::default());
+        table.add_result(create_test_probe(0, "best", 1, 50, SystemTime::now()));
+        table.add_result(create_test_probe(1, "next", 2, 100, SystemTime::now()));
+
+        let best = table.find_best(|b| b.name != "best").unwrap();
+        assert_eq!(best.name, "next");
+        assert!(table.find_best(|_| false).is_none());
+    }
+
+    #[test]
+    fn test_probe_table_find_best_normalizes_rif_by_weight() {
+        // max normalized rif = 40, threshold = 32
+        let table = ProbeTable::new(ProbeTableConfig::default());
+        let mut big = create_test_probe(0, "big", 40, 50, SystemTime::now());
+        big.backend.weight = 4.0; // normalized rif = 10, cold
+        table.add_result(big);
+        table.add_result(create_test_probe(1, "small", 40, 100, SystemTime::now())); // hot
+        table.add_result(create_test_probe(2, "other", 20, 200, SystemTime::now())); // cold
+
+        assert_eq!(table.find_best(|_| true).unwrap().name, "big");
+    }
+
+    #[test]
+    fn test_probe_table_find_last_known() {
+        let config = ProbeTableConfig {
+            max_probe_age: Duration::from_secs(1),
+            ..Default::default()
+        };
+        let table = ProbeTable::new(config);
+        let old = SystemTime::now() - Duration::from_secs(10);
+        table.add_result(create_test_probe(1, "busy", 20, 10, old));
+        table.add_result(create_test_probe(2, "idle", 2, 50, old));
+
+        // Both probes expired, but are still known
+        assert_eq!(table.find_best(|_| true), None);
+        assert_eq!(table.find_last_known(|_| true).unwrap().name, "idle");
+        assert_eq!(
+            table.find_last_known(|b| b.name != "idle").unwrap().name,
+            "busy"
+        );
+
+        table.remove_backend(create_test_probe(2, "idle", 0, 0, old).backend);
+        assert_eq!(table.find_last_known(|_| true).unwrap().name, "busy");
+    }
+
+    #[test]
+    fn test_probe_table_is_overloaded() {
+        let table = ProbeTable::new(ProbeTableConfig::default());
+        let now = SystemTime::now();
+        let hot = create_test_probe(1, "hot", 20, 10, now);
+        let cold = create_test_probe(2, "cold", 2, 100, now);
+        let unprobed = create_test_probe(3, "unprobed", 0, 0, now);
+        table.add_result(hot.clone());
+        table.add_result(cold.clone());
+
+        assert!(table.is_overloaded(&hot.backend, 0));
+        assert!(!table.is_overloaded(&cold.backend, 0));
+        assert!(table.is_overloaded(&cold.backend, 50));
+        assert!(!table.is_overloaded(&unprobed.backend, 50));
+    }
+
+    #[test]
+    fn test_probe_table_find_cold() {
+        let table = ProbeTable::new(ProbeTableConfig::default());
+        let now = SystemTime::now();
+        table.add_result(create_test_probe(1, "hot", 20, 10, now));
+        table.add_result(create_test_probe(2, "cold", 2, 100, now));
+
+        assert_eq!(table.find_cold(|_| true).unwrap().name, "cold");
+        assert_eq!(table.find_cold(|b| b.name == "hot"), None);
+        assert_eq!(table.find_best(|b| b.name == "hot").unwrap().name, "hot");
+    }
+
+    #[test]
+    fn test_probe_table_pick_runner_up() {
+        let table = ProbeTable::new(ProbeTableConfig::default());
+        let now = SystemTime::now();
+        table.add_result(create_test_probe(1, "hot", 20, 10, now));
+        table.add_result(create_test_probe(2, "fast", 2, 50, now));
+        table.add_result(create_test_probe(3, "slow", 3, 100, now));
+
+        let pick = table.pick_best(|_| true).unwrap();
+        assert_eq!(pick.backend.name, "fast");
+        assert!(!pick.hot);
+        assert_eq!((pick.rif, pick.est_latency), (2, 50));
+        assert_eq!(pick.runner_up.unwrap().name, "slow");
+
+        // Hot probes come after all the cold ones
+        let pick = table.pick_best(|b| b.name != "fast").unwrap();
+        assert_eq!(pick.runner_up.unwrap().name, "hot");
+        let pick = table.pick_best(|b| b.name == "hot").unwrap();
+        assert!(pick.hot);
+        assert!(pick.runner_up.is_none());
+        assert!(table
+            .pick_cold(|b| b.name != "fast")
+            .unwrap()
+            .runner_up
+            .is_none());
+    }
+
+    #[test]
+    fn test_probe_table_find_best_corrects_latency() {
+        let table = ProbeTable::new(ProbeTableConfig::default());
+        let now = SystemTime::now();
+        let optimist = create_test_probe(1, "optimist", 1, 10, now);
+        let honest = create_test_probe(2, "honest", 1, 20, now);
+        table.add_result(optimist.clone());
+        table.add_result(honest.clone());
+        table.add_result(create_test_probe(3, "hot", 10, 1, now));
+        assert_eq!(table.find_best(|_| true).unwrap().name, "optimist");
+
+        // The optimist is really 5 times slower than it says
+        for _ in 0..20 {
+            table.observe(&optimist.backend, Duration::from_millis(50));
+            table.observe(&honest.backend, Duration::from_millis(20));
+        }
+        assert_eq!(table.latency(&optimist), 50);
+        assert_eq!(table.find_best(|_| true).unwrap().name, "honest");
     }
 
     #[test]
     fn test_probe_table_remove_backend() {
-        let table = ProbeTable::new();
+        let table = ProbeTable::new(ProbeTableConfig::default());
         let result = create_test_probe(0, "test", 10, 100, SystemTime::now());
         table.add_result(result.clone());
         table.remove_backend(result.backend);
@@ -283,28 +686,41 @@ mod tests {
 
     #[test]
     fn test_probe_table_remove_stale() {
-        let table = ProbeTable::new();
+        let table = ProbeTable::new(ProbeTableConfig::default());
         let result = create_test_probe(
             0,
             "test",
             10,
             100,
-            SystemTime::now() - MAX_PROBE_AGE - Duration::from_secs(1),
+            SystemTime::now() - DEFAULT_MAX_PROBE_AGE - Duration::from_secs(1),
         );
         table.add_result(result.clone());
         table.remove_stale();
         assert_eq!(table.len(), 0);
+        assert_eq!(table.take_evicted(), [(result.backend, 1)]);
+        assert!(table.take_evicted().is_empty());
+    }
+
+    #[test]
+    fn test_probe_table_survives_clock_going_back() {
+        let table = ProbeTable::new(ProbeTableConfig::default());
+        // Taken before the wall clock stepped back
+        let future = SystemTime::now() + Duration::from_secs(60);
+        table.add_result(create_test_probe(0, "test", 10, 100, future));
+        table.remove_stale();
+        assert_eq!(table.len(), 1);
+        assert!(table.pick_best(|_| true).is_some());
     }
 
     #[test]
     fn test_probe_table_has_enough_probes() {
-        let table = ProbeTable::new();
+        let table = ProbeTable::new(ProbeTableConfig::default());
         table.add_result(create_test_probe(0, "test", 10, 100, SystemTime::now()));
         assert!(
             !table.has_enough_probes(),
             "Table should not yet have enough probes"
         );
-        for idx in 0..PROBE_TABLE_SIZE / 2 {
+        for idx in 0..DEFAULT_PROBE_TABLE_SIZE / 2 {
             table.add_result(create_test_probe(
                 idx + 1,
                 &format!("test-{}", idx),
@@ -319,6 +735,43 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_probe_table_custom_config() {
+        let table = ProbeTable::new(ProbeTableConfig {
+            size: 4,
+            max_uses: 1,
+            ..ProbeTableConfig::default()
+        });
+        for idx in 0..8 {
+            table.add_result(create_test_probe(
+                idx,
+                &format!("test-{}", idx),
+                idx,
+                100,
+                SystemTime::now(),
+            ));
+        }
+        assert_eq!(table.len(), 4, "Table should be capped at configured size");
+
+        // With max_uses = 1, each probe can only be selected once
+        let first = table.find_best(|_| true).unwrap();
+        let second = table.find_best(|_| true).unwrap();
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn test_probe_table_custom_hot_threshold() {
+        // threshold = 50 * 0.5 = 25, so the low-latency probe at rif 30 is hot
+        let table = ProbeTable::new(ProbeTableConfig {
+            hot_threshold: 0.5,
+            ..ProbeTableConfig::default()
+        });
+        table.add_result(create_test_probe(0, "cold", 10, 300, SystemTime::now()));
+        table.add_result(create_test_probe(1, "hot", 30, 50, SystemTime::now()));
+        table.add_result(create_test_probe(2, "max", 50, 500, SystemTime::now()));
+        assert_eq!(table.find_best(|_| true).unwrap().name, "cold");
+    }
+
     #[test]
     fn test_remove_worst_probe_prefers_hot_high_latency() {
         let mut probes = vec![
@@ -328,8 +781,8 @@ mod tests {
             create_test_probe(3, "hot-high-lat", 95, 300, SystemTime::now()), // hot, high latency (worst)
         ];
 
-        let max_rif = 100;
-        remove_worst_probe(&mut probes, max_rif);
+        let max_rif = 100.0;
+        remove_worst_probe(&mut probes, max_rif, DEFAULT_HOT_THRESHOLD);
 
         // Should have removed "hot-high-lat" (idx 3)
         assert_eq!(probes.len(), 3);
@@ -347,8 +800,8 @@ mod tests {
             create_test_probe(2, "cold-mid-lat", 10, 100, SystemTime::now()), // cold, mid latency
         ];
 
-        let max_rif = 100; // threshold = 80, all probes are cold
-        remove_worst_probe(&mut probes, max_rif);
+        let max_rif = 100.0; // threshold = 80, all probes are cold
+        remove_worst_probe(&mut probes, max_rif, DEFAULT_HOT_THRESHOLD);
 
         // Should have removed "cold-high-lat" (highest latency among cold)
         assert_eq!(probes.len(), 2);
@@ -365,8 +818,8 @@ mod tests {
             create_test_probe(1, "hot-low-lat", 90, 50, SystemTime::now()), // hot, low latency
         ];
 
-        let max_rif = 100; // threshold = 80
-        remove_worst_probe(&mut probes, max_rif);
+        let max_rif = 100.0; // threshold = 80
+        remove_worst_probe(&mut probes, max_rif, DEFAULT_HOT_THRESHOLD);
 
         // Should remove the hot probe even though cold has higher latency
         assert_eq!(probes.len(), 1);
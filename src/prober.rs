@@ -0,0 +1,480 @@
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Duration;
+
+use hyper::body::HttpBody;
+use hyper::client::conn;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE, HOST};
+use hyper::{Body, Method, Request, StatusCode, Uri};
+use serde_json::Value;
+use tokio::net::TcpStream;
+
+use crate::prequal_director::DirectorError;
+
+/// Larger probe response bodies are rejected rather than buffered.
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// Where a probe response carries the load signals.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ResponseFormat {
+    /// `X-In-Flight` and `X-Estimated-Latency` headers
+    #[default]
+    Headers,
+    /// Fields of a JSON body
+    Json,
+    /// JSON if the `Content-Type` says so, headers otherwise
+    Auto,
+}
+
+impl FromStr for ResponseFormat {
+    type Err = DirectorError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "headers" => Ok(ResponseFormat::Headers),
+            "json" => Ok(ResponseFormat::Json),
+            "auto" => Ok(ResponseFormat::Auto),
+            _ => Err(DirectorError::InvalidConfig(format!(
+                "unknown probe format \"{}\"",
+                s
+            ))),
+        }
+    }
+}
+
+/// What to ask a backend's probe endpoint, and how to read its answer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProbeSpec {
+    /// The probe path, with an optional query string (e.g. "/probe")
+    pub path: String,
+    /// The probe request method
+    pub method: Method,
+    /// Static headers added to every probe request, a `Host` one replacing the default
+    pub headers: Vec<(HeaderName, HeaderValue)>,
+    /// Where the response carries the load signals
+    pub format: ResponseFormat,
+    /// Response header holding the requests in flight
+    pub in_flight_header: HeaderName,
+    /// Response header holding the estimated latency
+    pub latency_header: HeaderName,
+    /// JSON field holding the requests in flight, with dots for nested objects
+    pub json_in_flight: String,
+    /// JSON field holding the estimated latency, with dots for nested objects
+    pub json_latency: String,
+}
+
+impl Default for ProbeSpec {
+    fn default() -> Self {
+        Self {
+            path: "/probe".to_string(),
+            method: Method::GET,
+            headers: Vec::new(),
+            format: ResponseFormat::default(),
+            in_flight_header: HeaderName::from_static("x-in-flight"),
+            latency_header: HeaderName::from_static("x-estimated-latency"),
+            json_in_flight: "in_flight".to_string(),
+            json_latency: "estimated_latency".to_string(),
+        }
+    }
+}
+
+/// Load signals reported by a backend's probe endpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoadReport {
+    pub in_flight: usize,
+    /// `None` when the backend doesn't estimate its latency
+    pub est_latency: Option<usize>,
+}
+
+impl ProbeSpec {
+    /// Sets the probe path, which may carry a query string.
+    pub fn set_path(&mut self, path: &str) -> Result<(), DirectorError> {
+        let uri: Uri = path.parse().map_err(|_| {
+            DirectorError::InvalidConfig(format!("invalid probe path \"{}\"", path))
+        })?;
+        if uri.scheme().is_some() || !path.starts_with('/') {
+            return Err(DirectorError::InvalidConfig(format!(
+                "probe path \"{}\" must start with /",
+                path
+            )));
+        }
+        self.path = path.to_string();
+        Ok(())
+    }
+
+    /// Sets the probe request method (e.g. "HEAD").
+    pub fn set_method(&mut self, method: &str) -> Result<(), DirectorError> {
+        self.method = Method::from_bytes(method.as_bytes()).map_err(|_| {
+            DirectorError::InvalidConfig(format!("invalid probe method \"{}\"", method))
+        })?;
+        Ok(())
+    }
+
+    /// Adds a header sent with every probe request, replacing any header
+    /// of the same name added before.
+    pub fn add_header(&mut self, name: &str, value: &str) -> Result<(), DirectorError> {
+        let name = parse_header_name(name)?;
+        let value = HeaderValue::from_str(value).map_err(|_| {
+            DirectorError::InvalidConfig(format!("invalid value for probe header {}", name))
+        })?;
+        self.headers.retain(|(added, _)| *added != name);
+        self.headers.push((name, value));
+        Ok(())
+    }
+
+    /// Sets where responses carry the load signals and, for JSON bodies,
+    /// the fields holding them when not the default ones.
+    pub fn set_format(
+        &mut self,
+        format: &str,
+        in_flight_field: Option<&str>,
+        latency_field: Option<&str>,
+    ) -> Result<(), DirectorError> {
+        self.format = format.parse()?;
+        if let Some(field) = in_flight_field {
+            self.json_in_flight = field.to_string();
+        }
+        if let Some(field) = latency_field {
+            self.json_latency = field.to_string();
+        }
+        Ok(())
+    }
+
+    /// Sets the response headers holding the load signals.
+    pub fn set_load_headers(
+        &mut self,
+        in_flight: &str,
+        latency: &str,
+    ) -> Result<(), DirectorError> {
+        let in_flight = parse_header_name(in_flight)?;
+        self.latency_header = parse_header_name(latency)?;
+        self.in_flight_header = in_flight;
+        Ok(())
+    }
+
+    /// Builds the probe request for a backend.
+    ///
+    /// # Arguments
+    /// * `host` - The value of the `Host` header, unless a static header overrides it
+    fn request(&self, host: &str) -> Result<Request<Body>, ProbeError> {
+        let mut request = Request::builder()
+            .method(self.method.clone())
+            .uri(self.path.as_str())
+            .body(Body::empty())
+            .map_err(ProbeError::Request)?;
+
+        let headers = request.headers_mut();
+        if !self.headers.iter().any(|(name, _)| name == HOST) {
+            let host = HeaderValue::from_str(host).map_err(|e| ProbeError::Request(e.into()))?;
+            headers.insert(HOST, host);
+        }
+        for (name, value) in &self.headers {
+            headers.append(name.clone(), value.clone());
+        }
+        Ok(request)
+    }
+}
+
+fn parse_header_name(name: &str) -> Result<HeaderName, DirectorError> {
+    HeaderName::from_bytes(name.as_bytes())
+        .map_err(|_| DirectorError::InvalidConfig(format!("invalid header name \"{}\"", name)))
+}
+
+#[derive(Debug)]
+pub enum ProbeError {
+    Request(hyper::http::Error),
+    Connect(std::io::Error),
+    Http(hyper::Error),
+    Timeout,
+    Status(StatusCode),
+    MissingHeaders,
+    InvalidBody(String),
+    MissingFields,
+}
+
+impl std::fmt::Display for ProbeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProbeError::Request(e) => write!(f, "Invalid probe request: {}", e),
+            ProbeError::Connect(e) => write!(f, "Connection failed: {}", e),
+            ProbeError::Http(e) => write!(f, "HTTP error: {}", e),
+            ProbeError::Timeout => write!(f, "Probe timed out"),
+            ProbeError::Status(status) => write!(f, "Unexpected status: {}", status),
+            ProbeError::MissingHeaders => write!(f, "Missing or invalid load headers"),
+            ProbeError::InvalidBody(e) => write!(f, "Invalid JSON body: {}", e),
+            ProbeError::MissingFields => write!(f, "Missing or invalid load fields in JSON body"),
+        }
+    }
+}
+
+impl std::error::Error for ProbeError {}
+
+impl From<hyper::Error> for ProbeError {
+    fn from(e: hyper::Error) -> Self {
+        ProbeError::Http(e)
+    }
+}
+
+/// Sends a single probe request and parses the load signals from the response.
+///
+/// # Arguments
+/// * `address` - The backend address to connect to
+/// * `host` - The value of the `Host` header
+/// * `spec` - The probe path and the response format
+/// * `timeout` - Upper bound for the whole exchange, connection included
+pub async fn probe(
+    address: SocketAddr,
+    host: &str,
+    spec: &ProbeSpec,
+    timeout: Duration,
+) -> Result<LoadReport, ProbeError> {
+    tokio::time::timeout(timeout, send_probe(address, host, spec))
+        .await
+        .map_err(|_| ProbeError::Timeout)?
+}
+
+async fn send_probe(
+    address: SocketAddr,
+    host: &str,
+    spec: &ProbeSpec,
+) -> Result<LoadReport, ProbeError> {
+    let stream = TcpStream::connect(address)
+        .await
+        .map_err(ProbeError::Connect)?;
+    let (mut sender, connection) = conn::handshake(stream).await?;
+    // Drives the connection; it ends once the response is read and `sender` is dropped
+    tokio::spawn(connection);
+
+    let request = spec.request(host)?;
+    let response = sender.send_request(request).await?;
+
+    if response.status() != StatusCode::OK {
+        return Err(ProbeError::Status(response.status()));
+    }
+
+    let json = match spec.format {
+        ResponseFormat::Headers => false,
+        ResponseFormat::Json => true,
+        ResponseFormat::Auto => is_json(response.headers()),
+    };
+    if json {
+        let body = read_body(response.into_body()).await?;
+        parse_json(&body, spec)
+    } else {
+        parse_headers(response.headers(), spec)
+    }
+}
+
+fn parse_headers(headers: &HeaderMap, spec: &ProbeSpec) -> Result<LoadReport, ProbeError> {
+    let header = |name: &HeaderName| {
+        headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|s| s.parse::<usize>().ok())
+    };
+
+    match header(&spec.in_flight_header) {
+        Some(in_flight) => Ok(LoadReport {
+            in_flight,
+            est_latency: header(&spec.latency_header),
+        }),
+        None => Err(ProbeError::MissingHeaders),
+    }
+}
+
+/// Tells whether the response is JSON: `application/json` or any `+json` type.
+fn is_json(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .map(|mime| mime.trim().to_ascii_lowercase())
+        .is_some_and(|mime| mime == "application/json" || mime.ends_with("+json"))
+}
+
+async fn read_body(mut body: Body) -> Result<Vec<u8>, ProbeError> {
+    let mut buf = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if buf.len() + chunk.len() > MAX_BODY_SIZE {
+            return Err(ProbeError::InvalidBody(format!(
+                "larger than {} bytes",
+                MAX_BODY_SIZE
+            )));
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(buf)
+}
+
+fn parse_json(body: &[u8], spec: &ProbeSpec) -> Result<LoadReport, ProbeError> {
+    let value: Value =
+        serde_json::from_slice(body).map_err(|e| ProbeError::InvalidBody(e.to_string()))?;
+
+    // Numbers only, fractional ones are rounded
+    let field = |path: &str| {
+        path.split('.')
+            .try_fold(&value, |v, key| v.get(key))
+            .and_then(|v| {
+                v.as_u64()
+                    .or_else(|| v.as_f64().filter(|f| *f >= 0.0).map(|f| f.round() as u64))
+            })
+            .map(|n| n as usize)
+    };
+
+    match field(&spec.json_in_flight) {
+        Some(in_flight) => Ok(LoadReport {
+            in_flight,
+            est_latency: field(&spec.json_latency),
+        }),
+        None => Err(ProbeError::MissingFields),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::header::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_json() {
+        let spec = ProbeSpec::default();
+        assert_eq!(
+            parse_json(br#"{"in_flight": 3, "estimated_latency": 12.6}"#, &spec).unwrap(),
+            LoadReport {
+                in_flight: 3,
+                est_latency: Some(13)
+            }
+        );
+        // The latency is optional, the requests in flight are not
+        assert_eq!(
+            parse_json(br#"{"in_flight": 3}"#, &spec)
+                .unwrap()
+                .est_latency,
+            None
+        );
+        assert!(matches!(
+            parse_json(br#"{"estimated_latency": 3}"#, &spec),
+            Err(ProbeError::MissingFields)
+        ));
+        assert!(matches!(
+            parse_json(br#"{"in_flight": "3", "estimated_latency": 1}"#, &spec),
+            Err(ProbeError::MissingFields)
+        ));
+        assert!(matches!(
+            parse_json(b"OK", &spec),
+            Err(ProbeError::InvalidBody(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_json_nested_fields() {
+        let spec = ProbeSpec {
+            json_in_flight: "load.rif".to_string(),
+            json_latency: "load.latency_ms".to_string(),
+            ..Default::default()
+        };
+        let body = br#"{"status": "ok", "load": {"rif": 7, "latency_ms": 40}}"#;
+        assert_eq!(
+            parse_json(body, &spec).unwrap(),
+            LoadReport {
+                in_flight: 7,
+                est_latency: Some(40)
+            }
+        );
+    }
+
+    #[test]
+    fn test_is_json() {
+        let mut headers = HeaderMap::new();
+        assert!(!is_json(&headers));
+        for (content_type, json) in [
+            ("application/json", true),
+            ("Application/JSON; charset=utf-8", true),
+            ("application/health+json", true),
+            ("text/plain", false),
+        ] {
+            headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+            assert_eq!(is_json(&headers), json, "{}", content_type);
+        }
+    }
+
+    #[test]
+    fn test_parse_renamed_headers() {
+        let mut spec = ProbeSpec::default();
+        spec.set_load_headers("X-Active", "X-Latency-Ms").unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-in-flight", HeaderValue::from_static("3"));
+        headers.insert("x-estimated-latency", HeaderValue::from_static("10"));
+        assert!(matches!(
+            parse_headers(&headers, &spec),
+            Err(ProbeError::MissingHeaders)
+        ));
+
+        headers.insert("x-active", HeaderValue::from_static("5"));
+        assert_eq!(parse_headers(&headers, &spec).unwrap().est_latency, None);
+        headers.insert("x-latency-ms", HeaderValue::from_static("50"));
+        assert_eq!(
+            parse_headers(&headers, &spec).unwrap(),
+            LoadReport {
+                in_flight: 5,
+                est_latency: Some(50)
+            }
+        );
+    }
+
+    #[test]
+    fn test_probe_request() {
+        let request = ProbeSpec::default().request("backend1").unwrap();
+        assert_eq!(request.method(), Method::GET);
+        assert_eq!(request.uri(), "/probe");
+        assert_eq!(request.headers()[HOST], "backend1");
+
+        let mut spec = ProbeSpec::default();
+        spec.set_method("HEAD").unwrap();
+        spec.set_path("/load?source=varnish").unwrap();
+        spec.add_header("Authorization", "Bearer secret").unwrap();
+        spec.add_header("X-Probe-Source", "varnish").unwrap();
+        spec.add_header("Host", "probe.internal").unwrap();
+        let request = spec.request("backend1").unwrap();
+        assert_eq!(request.method(), Method::HEAD);
+        assert_eq!(request.uri().query(), Some("source=varnish"));
+        assert_eq!(request.headers()["authorization"], "Bearer secret");
+        assert_eq!(request.headers()["x-probe-source"], "varnish");
+        assert_eq!(request.headers().get_all(HOST).iter().count(), 1);
+        assert_eq!(request.headers()[HOST], "probe.internal");
+    }
+
+    #[test]
+    fn test_probe_header_added_again_replaces() {
+        let mut spec = ProbeSpec::default();
+        spec.add_header("X-Token", "one").unwrap();
+        spec.add_header("x-token", "two").unwrap();
+        let request = spec.request("backend1").unwrap();
+        let tokens: Vec<_> = request.headers().get_all("x-token").iter().collect();
+        assert_eq!(tokens, ["two"]);
+    }
+
+    #[test]
+    fn test_probe_spec_rejects_invalid_settings() {
+        let mut spec = ProbeSpec::default();
+        assert!(spec.set_path("probe").is_err());
+        assert!(spec.set_path("http://example.com/probe").is_err());
+        assert!(spec.set_method("GE T").is_err());
+        assert!(spec.add_header("X Bad", "value").is_err());
+        assert!(spec.add_header("X-Good", "bad\nvalue").is_err());
+        assert!(spec.set_load_headers("", "X-Latency").is_err());
+        assert_eq!(spec, ProbeSpec::default());
+    }
+
+    #[test]
+    fn test_response_format_parse() {
+        assert!(matches!("auto".parse(), Ok(ResponseFormat::Auto)));
+        assert!(matches!(
+            "xml".parse::<ResponseFormat>(),
+            Err(DirectorError::InvalidConfig(_))
+        ));
+    }
+}
@@ -0,0 +1,383 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+
+use varnish::ffi::VCL_BACKEND;
+
+use crate::backend::Backend;
+use crate::prequal_director::{Director, DirectorConfig, DirectorError};
+use crate::prober::ProbeSpec;
+
+/// A shared director, with the backends each VCL attached to it added.
+struct Entry {
+    director: Arc<Director>,
+    config: DirectorConfig,
+    // Keyed by attachment id
+    attachments: HashMap<u64, Arc<RwLock<Vec<Backend>>>>,
+    // Attachments whose VCL is cold
+    cold: HashSet<u64>,
+}
+
+impl Entry {
+    /// Probes as long as one of the VCLs using the director is warm.
+    fn update_temperature(&self) {
+        if self.cold.len() < self.attachments.len() {
+            self.director.resume();
+        } else {
+            self.director.pause();
+        }
+    }
+}
+
+/// The shared directors of this Varnish process, by name.
+fn registry() -> &'static Mutex<HashMap<String, Entry>> {
+    static REGISTRY: OnceLock<Mutex<HashMap<String, Entry>>> = OnceLock::new();
+    REGISTRY.get_or_init(Default::default)
+}
+
+static NEXT_ATTACHMENT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// A VCL's hold on a shared director.
+///
+/// Every VCL creating a shared director with the same name gets the same
+/// `Director`, so its probe state and probe thread survive reloads and
+/// serve labeled VCLs alike. Each VCL has its own backends for the same
+/// servers, matched by name and address: the director works with those
+/// of the VCL that added them last, and the attachment translates between
+/// those and its own VCL's.
+///
+/// Each VCL also stages its own probe settings and local zone, starting
+/// from the defaults: they replace the director's as soon as it is the only
+/// VCL using the director, and then whenever it warms up. A reload thus
+/// brings its settings in once it is warm, without piling them on top of
+/// the previous VCL's, and a VCL failing to load leaves them alone.
+///
+/// Dropping the attachment, when its VCL is discarded, hands the backends
+/// the director got from it over to another VCL that has them, or removes
+/// them; the director stops probing with its last attachment.
+pub struct Attachment {
+    name: String,
+    id: u64,
+    director: Arc<Director>,
+    // The backends this VCL added
+    backends: Arc<RwLock<Vec<Backend>>>,
+    // This VCL's probe settings and local zone
+    settings: Mutex<(ProbeSpec, Option<String>)>,
+}
+
+impl Attachment {
+    /// Attaches to the shared director called `name`, creating it if needed.
+    ///
+    /// # Arguments
+    /// * `name` - The name of the shared director
+    /// * `config` - Its configuration, which must be the same for every VCL
+    /// * `create` - Creates the director, when no VCL attached to it yet
+    ///
+    /// # Returns
+    /// * `Err(DirectorError::InvalidConfig)` if the director exists with
+    ///   another configuration
+    pub fn attach(
+        name: &str,
+        config: DirectorConfig,
+        create: impl FnOnce() -> Arc<Director>,
+    ) -> Result<Self, DirectorError> {
+        let mut registry = registry()
+            .lock()
+            .map_err(|e| DirectorError::BackendLockError(e.to_string()))?;
+        let entry = registry.entry(name.to_string()).or_insert_with(|| Entry {
+            director: create(),
+            config,
+            attachments: HashMap::new(),
+            cold: HashSet::new(),
+        });
+        if entry.config != config {
+            return Err(DirectorError::InvalidConfig(format!(
+                "shared director {} already exists with another configuration",
+                name
+            )));
+        }
+
+        let id = NEXT_ATTACHMENT_ID.fetch_add(1, Ordering::Relaxed);
+        let backends = Arc::new(RwLock::new(Vec::new()));
+        entry.attachments.insert(id, backends.clone());
+        entry.update_temperature();
+        Ok(Self {
+            name: name.to_string(),
+            id,
+            director: entry.director.clone(),
+            backends,
+            settings: Mutex::new((ProbeSpec::default(), None)),
+        })
+    }
+
+    /// Returns the shared director.
+    pub fn director(&self) -> &Arc<Director> {
+        &self.director
+    }
+
+    /// Records the temperature of this VCL: the director pauses probing
+    /// while all the VCLs using it are cold, and takes this VCL's probe
+    /// settings when it warms up.
+    pub fn set_warm(&self, warm: bool) {
+        let Ok(mut registry) = registry().lock() else {
+            return;
+        };
+        if let Some(entry) = registry.get_mut(&self.name) {
+            if warm {
+                entry.cold.remove(&self.id);
+                self.apply_settings();
+            } else {
+                entry.cold.insert(self.id);
+            }
+            entry.update_temperature();
+        }
+    }
+
+    /// Applies `update` to this VCL's probe settings.
+    pub fn update_probe_spec(
+        &self,
+        update: impl FnOnce(&mut ProbeSpec) -> Result<(), DirectorError>,
+    ) -> Result<(), DirectorError> {
+        {
+            let mut settings = self
+                .settings
+                .lock()
+                .map_err(|e| DirectorError::BackendLockError(e.to_string()))?;
+            update(&mut settings.0)?;
+        }
+        self.apply_settings_if_alone();
+        Ok(())
+    }
+
+    /// Sets this VCL's local zone.
+    pub fn set_local_zone(&self, zone: &str) {
+        if let Ok(mut settings) = self.settings.lock() {
+            settings.1 = Some(zone.to_string());
+        }
+        self.apply_settings_if_alone();
+    }
+
+    /// Applies this VCL's settings right away when no other VCL uses the
+    /// director, e.g. in the `vcl_init` of the VCL creating it.
+    fn apply_settings_if_alone(&self) {
+        let alone = registry().lock().is_ok_and(|registry| {
+            registry
+                .get(&self.name)
+                .is_some_and(|entry| entry.attachments.len() == 1)
+        });
+        if alone {
+            self.apply_settings();
+        }
+    }
+
+    fn apply_settings(&self) {
+        if let Ok(settings) = self.settings.lock() {
+            let (spec, local_zone) = settings.clone();
+            self.director.replace_probe_settings(spec, local_zone);
+        }
+    }
+
+    /// Adds a backend of this VCL, which the director then works with,
+    /// taking over the probes and state of the same server.
+    pub fn add_backend(&self, backend: Backend) -> Result<(), DirectorError> {
+        self.director.add_backend(backend.clone())?;
+        let mut backends = self
+            .backends
+            .write()
+            .map_err(|e| DirectorError::BackendLockError(e.to_string()))?;
+        backends.retain(|b| !b.is_same_server(&backend));
+        backends.push(backend);
+        Ok(())
+    }
+
+    /// Removes a backend of this VCL from the director, for every VCL.
+    pub fn remove_backend(&self, vcl_backend: VCL_BACKEND) {
+        if let Some(shared) = self.to_director(vcl_backend) {
+            self.director.remove_backend(shared);
+        }
+        if let Ok(mut backends) = self.backends.write() {
+            backends.retain(|b| *b != vcl_backend);
+        }
+    }
+
+    /// Returns the director's backend for one of this VCL's backends.
+    pub fn to_director(&self, vcl_backend: VCL_BACKEND) -> Option<VCL_BACKEND> {
+        let backends = self.backends.read().ok()?;
+        let backend = backends.iter().find(|b| **b == vcl_backend)?;
+        self.director
+            .find_server(backend)
+            .map(|shared| shared.vcl_backend)
+    }
+
+    /// Returns this VCL's backend for one of the director's, if it has one.
+    pub fn to_vcl(&self, backend: &Backend) -> Option<Backend> {
+        self.backends
+            .read()
+            .ok()?
+            .iter()
+            .find(|b| b.is_same_server(backend))
+            .cloned()
+    }
+}
+
+impl Drop for Attachment {
+    fn drop(&mut self) {
+        let Ok(mut registry) = registry().lock() else {
+            return;
+        };
+        let Some(entry) = registry.get_mut(&self.name) else {
+            return;
+        };
+        entry.attachments.remove(&self.id);
+        entry.cold.remove(&self.id);
+        if entry.attachments.is_empty() {
+            registry.remove(&self.name);
+            self.director.stop();
+            return;
+        }
+        entry.update_temperature();
+
+        // The director must not keep backends of a discarded VCL
+        let backends = self
+            .backends
+            .read()
+            .map(|backends| backends.clone())
+            .unwrap_or_default();
+        for backend in backends {
+            if self.director.find_server(&backend).as_ref() != Some(&backend) {
+                continue;
+            }
+            let other = entry.attachments.values().find_map(|others| {
+                others
+                    .read()
+                    .ok()?
+                    .iter()
+                    .find(|b| b.is_same_server(&backend))
+                    .cloned()
+            });
+            match other {
+                Some(other) => {
+                    let _ = self.director.add_backend(other);
+                }
+                None => self.director.remove_backend(backend.vcl_backend),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+    use crate::backend::test_backend;
+    use crate::prequal_director::DirectorStats;
+
+    fn create_director() -> Arc<Director> {
+        Director::new(
+            Arc::new(DirectorStats::default()),
+            DirectorConfig::default(),
+        )
+        .0
+    }
+
+    #[test]
+    fn test_attach_shares_director() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 8080));
+        let config = DirectorConfig::default();
+        let first = Attachment::attach("registry_shares", config, create_director).unwrap();
+        let second = Attachment::attach("registry_shares", config, || unreachable!()).unwrap();
+        assert!(Arc::ptr_eq(first.director(), second.director()));
+
+        let other = DirectorConfig {
+            probe_ratio: 1.0,
+            ..config
+        };
+        assert!(Attachment::attach("registry_shares", other, create_director).is_err());
+
+        // Probing pauses while every VCL is cold
+        first.set_warm(false);
+        assert!(!first.director().is_paused());
+        second.set_warm(false);
+        assert!(first.director().is_paused());
+        first.set_warm(true);
+        assert!(!first.director().is_paused());
+
+        // The director goes away with its last attachment
+        drop(first);
+        drop(second);
+        let third = Attachment::attach("registry_shares", other, create_director).unwrap();
+        assert!(third
+            .director()
+            .find_server(&test_backend("b1", addr, 1))
+            .is_none());
+    }
+
+    #[test]
+    fn test_attach_translates_backends() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 8080));
+        let config = DirectorConfig::default();
+        let old = Attachment::attach("registry_translates", config, create_director).unwrap();
+        old.add_backend(test_backend("b1", addr, 1)).unwrap();
+        old.add_backend(test_backend("b2", addr, 2)).unwrap();
+
+        // A reload brings the same servers through new backends
+        let new = Attachment::attach("registry_translates", config, || unreachable!()).unwrap();
+        new.add_backend(test_backend("b1", addr, 11)).unwrap();
+        let b1 = test_backend("b1", addr, 11);
+        assert_eq!(
+            old.to_director(test_backend("b1", addr, 1).vcl_backend),
+            Some(b1.vcl_backend)
+        );
+        assert_eq!(old.to_vcl(&b1).map(|b| b.vcl_backend.0 as usize), Some(1));
+        assert!(new.to_vcl(&test_backend("b2", addr, 2)).is_none());
+
+        // Discarding the old VCL drops the backends only it had
+        drop(old);
+        let director = new.director();
+        assert_eq!(
+            director.find_server(&b1).map(|b| b.vcl_backend.0 as usize),
+            Some(11)
+        );
+        assert!(director.find_server(&test_backend("b2", addr, 2)).is_none());
+    }
+
+    #[test]
+    fn test_attach_stages_probe_settings() {
+        let config = DirectorConfig::default();
+        let first = Attachment::attach("registry_settings", config, create_director).unwrap();
+        let header = |spec: &mut ProbeSpec| spec.add_header("X-Token", "one");
+        first.update_probe_spec(header).unwrap();
+        first.set_local_zone("zone-a");
+        let dump = |attachment: &Attachment| -> serde_json::Value {
+            serde_json::from_str(&attachment.director().dump_json()).unwrap()
+        };
+        // Alone, the VCL creating the director sets it up right away
+        assert_eq!(dump(&first)["config"]["local_zone"], "zone-a");
+
+        // A reload's settings wait for it to warm up
+        let second = Attachment::attach("registry_settings", config, || unreachable!()).unwrap();
+        second.set_local_zone("zone-b");
+        assert_eq!(dump(&first)["config"]["local_zone"], "zone-a");
+        second.set_warm(true);
+        assert_eq!(dump(&first)["config"]["local_zone"], "zone-b");
+        // Starting over from the defaults, not from the previous VCL's
+        assert!(second.settings.lock().unwrap().0.headers.is_empty());
+    }
+
+    #[test]
+    fn test_detach_hands_backends_over() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 8080));
+        let config = DirectorConfig::default();
+        let first = Attachment::attach("registry_hands_over", config, create_director).unwrap();
+        first.add_backend(test_backend("b1", addr, 1)).unwrap();
+        let second = Attachment::attach("registry_hands_over", config, || unreachable!()).unwrap();
+        second.add_backend(test_backend("b1", addr, 2)).unwrap();
+        // The first VCL adds it again, the director moves back to its backend
+        first.add_backend(test_backend("b1", addr, 1)).unwrap();
+
+        drop(first);
+        let b1 = second.director().find_server(&test_backend("b1", addr, 0));
+        assert_eq!(b1.map(|b| b.vcl_backend.0 as usize), Some(2));
+    }
+}
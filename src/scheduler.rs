@@ -0,0 +1,159 @@
+use std::time::{Duration, Instant};
+
+/// Decides how many probes the probe loop sends on each wakeup.
+///
+/// Probes are sent in proportion to the request rate (`r_probe` in the
+/// Prequal paper): each request earns `probe_ratio` probes, with fractional
+/// credit carried over between wakeups. The total is capped by a token
+/// bucket refilled at `max_probe_rate` probes per second, and probes over
+/// the cap are dropped rather than queued. Without traffic, the loop falls
+/// back to `idle_probe_rate`. Probes refilling the table, e.g. to seed it,
+/// draw from the same bucket.
+#[derive(Debug)]
+pub struct ProbeScheduler {
+    probe_ratio: f64,
+    max_probe_rate: f64,
+    idle_probe_rate: f64,
+    credit: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Outcome of a scheduling decision.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProbeBudget {
+    /// Probes to send now
+    pub send: usize,
+    /// Probes that were due but dropped by the rate cap
+    pub dropped: usize,
+}
+
+impl ProbeScheduler {
+    pub fn new(probe_ratio: f64, max_probe_rate: f64, idle_probe_rate: f64, now: Instant) -> Self {
+        Self {
+            probe_ratio,
+            max_probe_rate,
+            idle_probe_rate,
+            credit: 0.0,
+            tokens: Self::bucket_size(max_probe_rate),
+            last_refill: now,
+        }
+    }
+
+    // Allows bursts of up to one second worth of probes
+    fn bucket_size(max_probe_rate: f64) -> f64 {
+        max_probe_rate.max(1.0)
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.max_probe_rate)
+            .min(Self::bucket_size(self.max_probe_rate));
+        self.last_refill = now;
+    }
+
+    fn spend(&mut self, now: Instant) -> ProbeBudget {
+        self.refill(now);
+        let due = self.credit.floor();
+        let send = due.min(self.tokens.floor());
+        self.tokens -= send;
+        // Keep the fractional part only, so a capped burst doesn't turn into a backlog
+        self.credit = self.credit.fract();
+        ProbeBudget {
+            send: send as usize,
+            dropped: (due - send) as usize,
+        }
+    }
+
+    /// Returns the probes earned by `requests` requests since the last call.
+    pub fn on_requests(&mut self, requests: u64, now: Instant) -> ProbeBudget {
+        self.credit += requests as f64 * self.probe_ratio;
+        self.spend(now)
+    }
+
+    /// Returns the probes to send after `elapsed` without any traffic.
+    pub fn on_idle(&mut self, elapsed: Duration, now: Instant) -> ProbeBudget {
+        self.credit += elapsed.as_secs_f64() * self.idle_probe_rate;
+        self.spend(now)
+    }
+
+    /// Returns how many of `wanted` probes refilling the probe table can
+    /// be sent now, without earning any credit.
+    pub fn on_refill(&mut self, wanted: usize, now: Instant) -> ProbeBudget {
+        self.refill(now);
+        let send = (wanted as f64).min(self.tokens.floor());
+        self.tokens -= send;
+        ProbeBudget {
+            send: send as usize,
+            dropped: wanted - send as usize,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scheduler_proportional_to_requests() {
+        let now = Instant::now();
+        let mut scheduler = ProbeScheduler::new(0.5, 1000.0, 0.0, now);
+
+        assert_eq!(scheduler.on_requests(1, now).send, 0);
+        // Fractional credit carries over
+        assert_eq!(scheduler.on_requests(1, now).send, 1);
+        assert_eq!(scheduler.on_requests(10, now).send, 5);
+    }
+
+    #[test]
+    fn test_scheduler_rate_cap_drops_excess() {
+        let now = Instant::now();
+        let mut scheduler = ProbeScheduler::new(3.0, 10.0, 0.0, now);
+
+        let budget = scheduler.on_requests(100, now);
+        assert_eq!(
+            budget,
+            ProbeBudget {
+                send: 10,
+                dropped: 290
+            }
+        );
+
+        // Dropped probes are not carried over
+        assert_eq!(scheduler.on_requests(0, now).send, 0);
+
+        // Tokens refill over time
+        let later = now + Duration::from_millis(500);
+        assert_eq!(scheduler.on_requests(100, later).send, 5);
+    }
+
+    #[test]
+    fn test_scheduler_refill_within_rate_cap() {
+        let now = Instant::now();
+        let mut scheduler = ProbeScheduler::new(1.0, 4.0, 0.0, now);
+
+        // Half of a 16 probe table is over the cap
+        assert_eq!(
+            scheduler.on_refill(8, now),
+            ProbeBudget {
+                send: 4,
+                dropped: 4
+            }
+        );
+        assert_eq!(scheduler.on_refill(8, now).send, 0);
+        // Refills and requests share the bucket
+        let later = now + Duration::from_millis(500);
+        assert_eq!(scheduler.on_refill(1, later).send, 1);
+        assert_eq!(scheduler.on_requests(10, later).send, 1);
+    }
+
+    #[test]
+    fn test_scheduler_idle_rate() {
+        let now = Instant::now();
+        let mut scheduler = ProbeScheduler::new(3.0, 100.0, 0.5, now);
+
+        assert_eq!(scheduler.on_idle(Duration::from_secs(1), now).send, 0);
+        assert_eq!(scheduler.on_idle(Duration::from_secs(1), now).send, 1);
+        assert_eq!(scheduler.on_idle(Duration::from_secs(10), now).send, 5);
+    }
+}
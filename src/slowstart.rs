@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::backend::Backend;
+use crate::prequal_director::DirectorError;
+
+/// Share of its traffic a backend gets when its slow start begins.
+pub const SLOW_START_MIN_FRACTION: f64 = 0.1;
+
+/// How a backend's share of traffic grows during its slow start.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SlowStartCurve {
+    /// By the same amount every second
+    #[default]
+    Linear,
+    /// By the same factor every second, staying low for longer
+    Exponential,
+}
+
+impl FromStr for SlowStartCurve {
+    type Err = DirectorError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "linear" => Ok(SlowStartCurve::Linear),
+            "exponential" => Ok(SlowStartCurve::Exponential),
+            _ => Err(DirectorError::InvalidConfig(format!(
+                "unknown slow start curve \"{}\"",
+                s
+            ))),
+        }
+    }
+}
+
+impl std::fmt::Display for SlowStartCurve {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            SlowStartCurve::Linear => "linear",
+            SlowStartCurve::Exponential => "exponential",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl SlowStartCurve {
+    /// Returns the share of traffic at `progress` (0 to 1) through the window.
+    pub fn fraction(&self, progress: f64) -> f64 {
+        let progress = progress.clamp(0.0, 1.0);
+        match self {
+            SlowStartCurve::Linear => {
+                SLOW_START_MIN_FRACTION + (1.0 - SLOW_START_MIN_FRACTION) * progress
+            }
+            SlowStartCurve::Exponential => SLOW_START_MIN_FRACTION.powf(1.0 - progress),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum State {
+    /// Ramping up since then
+    Ramping(Instant),
+    /// Seen down, ramps up again once back
+    Down,
+}
+
+/// Tracks the backends in their slow start: just added, or back after
+/// being down, so their caches and JIT warm up before they get full traffic.
+#[derive(Debug)]
+pub struct SlowStart {
+    window: Duration,
+    curve: SlowStartCurve,
+    // Keyed by the backend's VCL_BACKEND address; backends at full
+    // traffic have no entry
+    backends: Mutex<HashMap<usize, State>>,
+}
+
+fn key(backend: &Backend) -> usize {
+    backend.vcl_backend.0 as usize
+}
+
+impl SlowStart {
+    /// Creates a tracker ramping backends up over `window`; a zero window
+    /// disables slow start.
+    pub fn new(window: Duration, curve: SlowStartCurve) -> Self {
+        Self {
+            window,
+            curve,
+            backends: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        !self.window.is_zero()
+    }
+
+    /// Starts the ramp of a backend, e.g. when it is added.
+    pub fn start(&self, backend: &Backend, now: Instant) {
+        if !self.is_enabled() {
+            return;
+        }
+        if let Ok(mut backends) = self.backends.lock() {
+            backends.insert(key(backend), State::Ramping(now));
+        }
+    }
+
+    /// Records whether a backend is up, starting its ramp when it comes
+    /// back after being down.
+    pub fn update(&self, backend: &Backend, up: bool, now: Instant) {
+        if !self.is_enabled() {
+            return;
+        }
+        let Ok(mut backends) = self.backends.lock() else {
+            return;
+        };
+        match (backends.get(&key(backend)), up) {
+            (Some(State::Down), true) => {
+                backends.insert(key(backend), State::Ramping(now));
+            }
+            (_, false) => {
+                backends.insert(key(backend), State::Down);
+            }
+            _ => {}
+        }
+    }
+
+    /// Returns the share of its traffic a backend should get: 1 unless it
+    /// is in its slow start.
+    pub fn fraction(&self, backend: &Backend, now: Instant) -> f64 {
+        self.progress(backend, now)
+            .map_or(1.0, |progress| self.curve.fraction(progress))
+    }
+
+    /// Returns how far through its slow start a backend is, from 0 to 1,
+    /// or `None` if it isn't ramping up.
+    pub fn progress(&self, backend: &Backend, now: Instant) -> Option<f64> {
+        if !self.is_enabled() {
+            return None;
+        }
+        let mut backends = self.backends.lock().ok()?;
+        let State::Ramping(since) = *backends.get(&key(backend))? else {
+            return None;
+        };
+        let progress =
+            now.saturating_duration_since(since).as_secs_f64() / self.window.as_secs_f64();
+        if progress >= 1.0 {
+            backends.remove(&key(backend));
+            return None;
+        }
+        Some(progress)
+    }
+
+    /// Returns the curve the backends ramp up along.
+    pub fn curve(&self) -> SlowStartCurve {
+        self.curve
+    }
+
+    /// Carries what is known of `old` over to `new`, the same server
+    /// through another VCL's backend.
+    pub fn rebind_backend(&self, old: &Backend, new: &Backend) {
+        if let Ok(mut backends) = self.backends.lock() {
+            if let Some(state) = backends.remove(&key(old)) {
+                backends.insert(key(new), state);
+            }
+        }
+    }
+
+    /// Forgets everything about a backend.
+    pub fn remove_backend(&self, backend: &Backend) {
+        if let Ok(mut backends) = self.backends.lock() {
+            backends.remove(&key(backend));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+    use crate::backend::test_backend;
+
+    #[test]
+    fn test_slow_start_curves() {
+        for curve in [SlowStartCurve::Linear, SlowStartCurve::Exponential] {
+            assert_eq!(curve.to_string().parse::<SlowStartCurve>().unwrap(), curve);
+            assert!((curve.fraction(0.0) - SLOW_START_MIN_FRACTION).abs() < 1e-9);
+            assert!((curve.fraction(1.0) - 1.0).abs() < 1e-9);
+        }
+        assert!((SlowStartCurve::Linear.fraction(0.5) - 0.55).abs() < 1e-9);
+        // The exponential curve stays lower until the very end
+        assert!(SlowStartCurve::Exponential.fraction(0.5) < 0.32);
+        assert!(matches!(
+            "step".parse::<SlowStartCurve>(),
+            Err(DirectorError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn test_slow_start_ramp() {
+        let slow_start = SlowStart::new(Duration::from_secs(10), SlowStartCurve::Linear);
+        let backend = test_backend("b1", SocketAddr::from(([127, 0, 0, 1], 8080)), 1);
+        let now = Instant::now();
+        assert_eq!(slow_start.fraction(&backend, now), 1.0);
+
+        slow_start.start(&backend, now);
+        assert_eq!(slow_start.progress(&backend, now), Some(0.0));
+        let half = slow_start.progress(&backend, now + Duration::from_secs(5));
+        assert_eq!(half, Some(0.5));
+        assert!((slow_start.fraction(&backend, now + Duration::from_secs(5)) - 0.55).abs() < 1e-9);
+
+        // Done ramping, until it goes down and comes back
+        assert_eq!(
+            slow_start.fraction(&backend, now + Duration::from_secs(10)),
+            1.0
+        );
+        let later = now + Duration::from_secs(20);
+        slow_start.update(&backend, true, later);
+        assert_eq!(slow_start.progress(&backend, later), None);
+        slow_start.update(&backend, false, later);
+        assert_eq!(slow_start.progress(&backend, later), None);
+        slow_start.update(&backend, true, later);
+        assert_eq!(slow_start.progress(&backend, later), Some(0.0));
+
+        slow_start.remove_backend(&backend);
+        assert_eq!(slow_start.progress(&backend, later), None);
+    }
+
+    #[test]
+    fn test_slow_start_disabled() {
+        let slow_start = SlowStart::new(Duration::ZERO, SlowStartCurve::Linear);
+        let backend = test_backend("b1", SocketAddr::from(([127, 0, 0, 1], 8080)), 1);
+        let now = Instant::now();
+        slow_start.start(&backend, now);
+        slow_start.update(&backend, false, now);
+        slow_start.update(&backend, true, now);
+        assert_eq!(slow_start.fraction(&backend, now), 1.0);
+    }
+}
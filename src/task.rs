@@ -0,0 +1,57 @@
+use std::ffi::c_void;
+
+use varnish::ffi::{
+    vmod_priv_methods, vrt_ctx, VRT_priv_task, VCL_BACKEND, VMOD_PRIV_METHODS_MAGIC,
+};
+use varnish::vcl::Ctx;
+
+/// Backends already handed out by a director during the current task
+/// (a client request or a backend fetch), kept in its PRIV_TASK so that
+/// a `return(retry)` doesn't land on the backend that just failed.
+#[derive(Debug, Default)]
+pub struct TriedBackends(Vec<VCL_BACKEND>);
+
+struct PrivMethods(vmod_priv_methods);
+
+// Only holds a pointer to a static string
+unsafe impl Sync for PrivMethods {}
+
+static TRIED_BACKENDS_METHODS: PrivMethods = PrivMethods(vmod_priv_methods {
+    magic: VMOD_PRIV_METHODS_MAGIC,
+    type_: c"prequal tried backends".as_ptr(),
+    fini: Some(fini_tried_backends),
+});
+
+unsafe extern "C" fn fini_tried_backends(_ctx: *const vrt_ctx, tried: *mut c_void) {
+    drop(Box::from_raw(tried as *mut TriedBackends));
+}
+
+impl TriedBackends {
+    /// Returns the backends tried during the current task by the director
+    /// identified by `key`, or `None` if there is no task to attach to.
+    pub fn for_task<'a>(ctx: &Ctx, key: *const c_void) -> Option<&'a mut TriedBackends> {
+        unsafe {
+            let task_priv = VRT_priv_task(&*ctx.raw, key).as_mut()?;
+            if task_priv.priv_.is_null() {
+                task_priv.priv_ = Box::into_raw(Box::<TriedBackends>::default()) as *mut c_void;
+                task_priv.methods = &TRIED_BACKENDS_METHODS.0;
+            }
+            (task_priv.priv_ as *mut TriedBackends).as_mut()
+        }
+    }
+
+    pub fn as_slice(&self) -> &[VCL_BACKEND] {
+        &self.0
+    }
+
+    /// Returns the backend handed out most recently.
+    pub fn last(&self) -> Option<VCL_BACKEND> {
+        self.0.last().copied()
+    }
+
+    pub fn push(&mut self, backend: VCL_BACKEND) {
+        // Keep the latest one last
+        self.0.retain(|b| *b != backend);
+        self.0.push(backend);
+    }
+}
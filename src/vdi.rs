@@ -0,0 +1,143 @@
+use std::ffi::{c_int, c_void, CString};
+use std::{mem, ptr};
+
+use varnish::ffi::{
+    vdi_methods, vrt_ctx, vsb, VRT_AddDirector, VRT_DelDirector, VSB_bcat, VCL_BACKEND, VCL_BOOL,
+    VCL_TIME, VDI_METHODS_MAGIC,
+};
+use varnish::vcl::{Ctx, VclError};
+
+/// Callbacks for a native Varnish director.
+///
+/// Implementors are invoked by Varnish itself (at fetch time, for health
+/// checks and for `varnishadm backend.list`), not from VCL.
+pub trait VclDirector {
+    /// Picks the backend to use for the current fetch, or `None` to fail it.
+    fn resolve(&self, ctx: &mut Ctx) -> Option<VCL_BACKEND>;
+
+    /// Reports whether the director can currently serve requests.
+    fn healthy(&self, ctx: &mut Ctx) -> bool;
+
+    /// Renders the director state for `backend.list`.
+    ///
+    /// # Arguments
+    /// * `detailed` - `-p` was passed, list every entry
+    /// * `json` - `-j` was passed, output must be JSON
+    fn list(&self, ctx: &mut Ctx, detailed: bool, json: bool) -> String;
+}
+
+/// A director registered with Varnish through `VRT_AddDirector`.
+///
+/// The `VCL_BACKEND` it exposes can be assigned to `req.backend_hint` or
+/// `bereq.backend`, or added to other directors. Varnish calls back into
+/// `T` to resolve it to a real backend when the fetch happens.
+/// The director is deregistered when this value is dropped.
+pub struct NativeDirector<T: VclDirector> {
+    vcl_backend: VCL_BACKEND,
+    // Both must outlive the registered director, which points into them
+    _methods: Box<vdi_methods>,
+    inner: Box<T>,
+}
+
+impl<T: VclDirector> NativeDirector<T> {
+    /// Registers `inner` as a native director named `name`.
+    pub fn new(ctx: &mut Ctx, type_name: &str, name: &str, inner: T) -> Result<Self, VclError> {
+        let type_name = CString::new(type_name)
+            .map_err(|_| VclError::new("Invalid director type name".to_string()))?;
+        let c_name = CString::new(name)
+            .map_err(|_| VclError::new(format!("Invalid director name: {}", name)))?;
+
+        let methods = Box::new(vdi_methods {
+            magic: VDI_METHODS_MAGIC,
+            // Varnish keeps this pointer, so it is leaked on purpose
+            type_: type_name.into_raw(),
+            healthy: Some(healthy::<T>),
+            resolve: Some(resolve::<T>),
+            list: Some(list::<T>),
+            ..unsafe { mem::zeroed() }
+        });
+        let inner = Box::new(inner);
+
+        let vcl_backend = unsafe {
+            VRT_AddDirector(
+                &*ctx.raw,
+                &*methods,
+                &*inner as *const T as *mut c_void,
+                c"%s".as_ptr(),
+                c_name.as_ptr(),
+            )
+        };
+        if vcl_backend.0.is_null() {
+            return Err(VclError::new(format!(
+                "Failed to register director {}",
+                name
+            )));
+        }
+
+        Ok(Self {
+            vcl_backend,
+            _methods: methods,
+            inner,
+        })
+    }
+
+    /// Returns the `VCL_BACKEND` handle for this director.
+    pub fn vcl_backend(&self) -> VCL_BACKEND {
+        self.vcl_backend
+    }
+
+    /// Returns the callbacks implementation.
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T: VclDirector> Drop for NativeDirector<T> {
+    fn drop(&mut self) {
+        unsafe { VRT_DelDirector(&mut self.vcl_backend) };
+    }
+}
+
+/// Recovers the `T` registered as the director's private pointer.
+///
+/// # Safety
+/// `be` must be a director created by `NativeDirector::<T>::new`.
+unsafe fn director_priv<'a, T>(be: VCL_BACKEND) -> Option<&'a T> {
+    let director = be.0.as_ref()?;
+    (director.priv_ as *const T).as_ref()
+}
+
+unsafe extern "C" fn resolve<T: VclDirector>(ctx: *const vrt_ctx, be: VCL_BACKEND) -> VCL_BACKEND {
+    let mut ctx = Ctx::from_ptr(ctx);
+    director_priv::<T>(be)
+        .and_then(|inner| inner.resolve(&mut ctx))
+        .unwrap_or(VCL_BACKEND(ptr::null()))
+}
+
+unsafe extern "C" fn healthy<T: VclDirector>(
+    ctx: *const vrt_ctx,
+    be: VCL_BACKEND,
+    _changed: *mut VCL_TIME,
+) -> VCL_BOOL {
+    let mut ctx = Ctx::from_ptr(ctx);
+    let healthy = director_priv::<T>(be).is_some_and(|inner| inner.healthy(&mut ctx));
+    VCL_BOOL::from(healthy)
+}
+
+unsafe extern "C" fn list<T: VclDirector>(
+    ctx: *const vrt_ctx,
+    be: VCL_BACKEND,
+    vsb: *mut vsb,
+    pflag: c_int,
+    jflag: c_int,
+) {
+    let mut ctx = Ctx::from_ptr(ctx);
+    if let Some(inner) = director_priv::<T>(be) {
+        let output = inner.list(&mut ctx, pflag != 0, jflag != 0);
+        VSB_bcat(vsb, output.as_ptr() as *const c_void, output.len() as _);
+    }
+}
+
+// Varnish calls these from any worker thread
+unsafe impl<T: VclDirector + Sync> Send for NativeDirector<T> {}
+unsafe impl<T: VclDirector + Sync> Sync for NativeDirector<T> {}